@@ -1,5 +1,5 @@
-use serialport::SerialPortType;
-use serialport::available_ports;
+use crate::device_util;
+use crate::file_util;
 use std::env;
 use std::path::Path;
 use std::path::PathBuf;
@@ -35,6 +35,14 @@ pub struct Config {
 
     pub current_dir: PathBuf,
 
+    pub work_dir: String,
+
+    pub sendxml_str: String,
+
+    pub output_dir_str: String,
+
+    pub log_prefix: String,
+
     pub is_connect: bool,
 
     pub log_level: LogLevel,
@@ -47,7 +55,7 @@ pub enum LogLevel {
 }
 
 impl Config {
-    pub fn setup_env(debug: bool) -> Self {
+    pub fn setup_env(device_id: &str, debug: bool) -> Self {
         let mut config = Self {
             fh_loader_path: String::new(),
             sahara_server_path: String::new(),
@@ -59,13 +67,25 @@ impl Config {
             fh_port_conn_str_linux: String::new(),
             sahara_port_conn_str_linux: String::new(),
             current_dir: PathBuf::new(),
+            work_dir: String::new(),
+            sendxml_str: String::new(),
+            output_dir_str: String::new(),
+            log_prefix: String::new(),
             is_connect: false,
             log_level: LogLevel::Info,
         };
-        let (port_path, _port_info) = update_port();
-        if port_path == "Not found" {
-            return config;
+        // Each device gets its own cmd.xml and fh_loader output folder
+        let work_dir = device_work_dir(device_id);
+        if let Err(e) = file_util::create_dir_if_not_exists(&work_dir) {
+            println!("create {} dir failed:{}", work_dir, e);
         }
+        config.sendxml_str = format!("--sendxml={}/cmd.xml", work_dir);
+        config.output_dir_str = format!("--mainoutputdir={}", work_dir);
+        config.work_dir = work_dir;
+        let port_path = match device_util::find_edl_device(device_id) {
+            Some(device) if !device.port.is_empty() => device.port,
+            _ => return config,
+        };
         let port_str = r"\\.\".to_owned() + &port_path;
         let port_conn_str = r"--port=\\.\".to_owned() + &port_path;
         let port_conn_str_linux = r"--port=".to_owned() + &port_path;
@@ -112,26 +132,15 @@ impl Config {
     }
 }
 
-fn update_port() -> (String, String) {
-    let ports = available_ports().expect("Not found");
-    let mut port = String::new();
-    let mut product = String::new();
-    for p in ports {
-        match p.port_type {
-            SerialPortType::UsbPort(info) => {
-                port = p.port_name;
-                if let Some(pinfo) = info.product {
-                    println!("product : {}", pinfo);
-                    product = pinfo;
-                }
-            }
-            SerialPortType::PciPort | SerialPortType::BluetoothPort | SerialPortType::Unknown => {}
-        }
-    }
-    if port.is_empty() {
-        ("Not found".to_string(), "N/A".to_string())
+fn device_work_dir(device_id: &str) -> String {
+    let name: String = device_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if name.is_empty() {
+        "res".to_string()
     } else {
-        (port, product)
+        format!("res/{}", name)
     }
 }
 
@@ -146,16 +155,16 @@ pub async fn exec_cmd_with_msg(
         for (_index, s) in cmd.iter().enumerate() {
             cmd_str = format!("{} {}", &cmd_str, s);
         }
-        let _ = app.emit("log_event", &format!("{}", &cmd_str));
+        let _ = app.emit("log_event", &format!("{}{}", config.log_prefix, &cmd_str));
     }
     let result = exec_cmd_with_progress(&app, &cmd, None).await;
     match result {
         Ok(result) => {
-            let _ = app.emit("log_event", &format!("{}...OK", msg));
+            let _ = app.emit("log_event", &format!("{}{}...OK", config.log_prefix, msg));
             Ok(result)
         },
         Err(error) => {
-            let _ = app.emit("log_event", &format!("{}...Error", msg));
+            let _ = app.emit("log_event", &format!("{}{}...Error", config.log_prefix, msg));
             Err(error)
         },
     }
@@ -0,0 +1,271 @@
+use crate::qdl::usb::{is_edl_usb_id, usb_bus_location};
+use rusb::{Context, Device, Hotplug, HotplugBuilder, UsbContext};
+use serde::Serialize;
+use serialport::{SerialPortType, available_ports};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// An EDL device attached to this host
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EdlDevice {
+    /// Stable identifier the UI passes back to every command
+    pub id: String,
+    /// Serial port bound to the device, empty if only reachable over libusb
+    pub port: String,
+    pub serial_number: String,
+    /// "bus-port.port" path of the USB port the device is plugged in
+    pub bus_location: String,
+    pub product: String,
+    pub vid: u16,
+    pub pid: u16,
+}
+
+impl EdlDevice {
+    /// Whether `device_id` refers to this device, by ID, port name or USB serial
+    pub fn matches(&self, device_id: &str) -> bool {
+        !device_id.is_empty()
+            && (self.id == device_id || self.port == device_id || self.serial_number == device_id)
+    }
+}
+
+/// Attach/detach notification from `spawn_hotplug_watcher`
+pub enum HotplugEvent {
+    Arrived(EdlDevice),
+    Removed(EdlDevice),
+}
+
+/// Resolve the USB port a tty hangs off, e.g.
+/// /sys/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2.3/1-2.3:1.0/ttyUSB0 -> 1-2.3
+#[cfg(target_os = "linux")]
+fn port_bus_location(port: &str) -> String {
+    let name = std::path::Path::new(port)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or(port);
+    let link = format!("/sys/class/tty/{}/device", name);
+    let path = match std::fs::canonicalize(link) {
+        Ok(path) => path,
+        Err(_e) => return String::new(),
+    };
+
+    path.components()
+        .filter_map(|c| c.as_os_str().to_str())
+        .filter_map(|c| c.split_once(':'))
+        .map(|(location, _interface)| location)
+        .rfind(|location| {
+            location.contains('-')
+                && location
+                    .chars()
+                    .all(|ch| ch.is_ascii_digit() || ch == '-' || ch == '.')
+        })
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn port_bus_location(_port: &str) -> String {
+    String::new()
+}
+
+fn device_id(device: &EdlDevice) -> String {
+    if !device.bus_location.is_empty() {
+        device.bus_location.clone()
+    } else if !device.serial_number.is_empty() {
+        device.serial_number.clone()
+    } else {
+        device.port.clone()
+    }
+}
+
+/// List every Qualcomm device in EDL (or ramdump) mode, whether it's bound
+/// to a serial driver or only reachable over libusb
+pub fn list_edl_devices() -> Vec<EdlDevice> {
+    scan_edl_devices(&[])
+}
+
+/// Like `list_edl_devices`, but USB devices already in `known` are taken as
+/// they are instead of being opened again, they may be busy with a session
+fn scan_edl_devices(known: &[EdlDevice]) -> Vec<EdlDevice> {
+    let mut devices: Vec<EdlDevice> = Vec::new();
+
+    if let Ok(ports) = available_ports() {
+        for p in ports {
+            if let SerialPortType::UsbPort(info) = p.port_type {
+                if !is_edl_usb_id(info.vid, info.pid) {
+                    continue;
+                }
+                devices.push(EdlDevice {
+                    id: String::new(),
+                    bus_location: port_bus_location(&p.port_name),
+                    port: p.port_name,
+                    serial_number: info.serial_number.unwrap_or_default(),
+                    product: info.product.unwrap_or_default(),
+                    vid: info.vid,
+                    pid: info.pid,
+                });
+            }
+        }
+    }
+
+    if let Ok(usb_devices) = rusb::devices() {
+        for device in usb_devices.iter() {
+            let desc = match device.device_descriptor() {
+                Ok(desc) => desc,
+                Err(_) => continue,
+            };
+            if !is_edl_usb_id(desc.vendor_id(), desc.product_id()) {
+                continue;
+            }
+            let bus_location = usb_bus_location(&device);
+            let cached = known.iter().find(|d| {
+                d.bus_location == bus_location
+                    && d.vid == desc.vendor_id()
+                    && d.pid == desc.product_id()
+            });
+            // Devices we can't open (e.g. owned by the Windows serial
+            // driver) are only usable through their serial port
+            let handle = match cached {
+                Some(_) => None,
+                None => device.open().ok(),
+            };
+            let (serial_number, product) = match (cached, &handle) {
+                (Some(d), _) => (d.serial_number.clone(), d.product.clone()),
+                (None, Some(handle)) => (
+                    handle
+                        .read_serial_number_string_ascii(&desc)
+                        .unwrap_or_default(),
+                    handle.read_product_string_ascii(&desc).unwrap_or_default(),
+                ),
+                (None, None) => (String::new(), String::new()),
+            };
+            let usb_only = handle.is_some() || cached.is_some_and(|d| d.port.is_empty());
+
+            let known = devices.iter_mut().find(|d| {
+                d.bus_location == bus_location
+                    || (!serial_number.is_empty() && d.serial_number == serial_number)
+            });
+            match known {
+                Some(d) => {
+                    d.bus_location = bus_location;
+                    if d.serial_number.is_empty() {
+                        d.serial_number = serial_number;
+                    }
+                    if d.product.is_empty() {
+                        d.product = product;
+                    }
+                }
+                None if usb_only => devices.push(EdlDevice {
+                    id: String::new(),
+                    port: String::new(),
+                    serial_number,
+                    bus_location,
+                    product,
+                    vid: desc.vendor_id(),
+                    pid: desc.product_id(),
+                }),
+                None => {}
+            }
+        }
+    }
+
+    for device in devices.iter_mut() {
+        device.id = device_id(device);
+    }
+    devices
+}
+
+/// Look a device up by its ID, port name or USB serial number
+pub fn find_edl_device(device_id: &str) -> Option<EdlDevice> {
+    list_edl_devices()
+        .into_iter()
+        .find(|d| d.matches(device_id))
+}
+
+/// How long to let the drivers bind after a hotplug event before rescanning
+const HOTPLUG_SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// Wakes the watcher up when an EDL device is plugged in or out
+struct UsbChangeNotifier(mpsc::Sender<()>);
+
+impl UsbChangeNotifier {
+    fn notify(&self, device: &Device<Context>) {
+        // Rescan anyway if the descriptor is gone already
+        let is_edl = match device.device_descriptor() {
+            Ok(desc) => is_edl_usb_id(desc.vendor_id(), desc.product_id()),
+            Err(_e) => true,
+        };
+        if is_edl {
+            let _ = self.0.send(());
+        }
+    }
+}
+
+impl Hotplug<Context> for UsbChangeNotifier {
+    fn device_arrived(&mut self, device: Device<Context>) {
+        self.notify(&device);
+    }
+
+    fn device_left(&mut self, device: Device<Context>) {
+        self.notify(&device);
+    }
+}
+
+/// Register a libusb hotplug callback, None if the platform has none
+fn watch_usb_changes() -> Option<mpsc::Receiver<()>> {
+    if !rusb::has_hotplug() {
+        return None;
+    }
+    let context = Context::new().ok()?;
+    let (tx, rx) = mpsc::channel();
+    let registration = HotplugBuilder::new()
+        .enumerate(false)
+        .register(&context, Box::new(UsbChangeNotifier(tx)))
+        .ok()?;
+    thread::spawn(move || {
+        // Dropping the registration closes the channel, see spawn_hotplug_watcher
+        let _registration = registration;
+        while context.handle_events(None).is_ok() {}
+    });
+    Some(rx)
+}
+
+/// Watch for EDL devices coming and going. With libusb hotplug the device
+/// list is only rescanned when an EDL device is plugged in or out, otherwise
+/// (e.g. on Windows) it's polled every `interval`. Either way, devices seen
+/// before aren't opened again.
+pub fn spawn_hotplug_watcher<F>(interval: Duration, mut on_event: F) -> thread::JoinHandle<()>
+where
+    F: FnMut(HotplugEvent) + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut changes = watch_usb_changes();
+        let mut known: Vec<EdlDevice> = Vec::new();
+        loop {
+            let current = scan_edl_devices(&known);
+            for device in known.iter() {
+                if !current.iter().any(|d| d.id == device.id) {
+                    on_event(HotplugEvent::Removed(device.clone()));
+                }
+            }
+            for device in current.iter() {
+                if !known.iter().any(|d| d.id == device.id) {
+                    on_event(HotplugEvent::Arrived(device.clone()));
+                }
+            }
+            known = current;
+
+            match &changes {
+                Some(rx) => match rx.recv() {
+                    Ok(()) => {
+                        thread::sleep(HOTPLUG_SETTLE_DELAY);
+                        while rx.try_recv().is_ok() {}
+                    }
+                    // The event thread is gone, fall back to polling
+                    Err(_e) => changes = None,
+                },
+                None => thread::sleep(interval),
+            }
+        }
+    })
+}
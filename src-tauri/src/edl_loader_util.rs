@@ -1,5 +1,4 @@
 use ring::digest::{self, Digest, SHA384};
-use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fs::File;
 use std::io::{Read, Result};
@@ -80,6 +79,77 @@ pub fn parser_key_hash(file_path: &str) -> Result<HashSet<String>> {
     Ok(matches)
 }
 
+pub struct Chipset {
+    /// Part number, as found in the loader, e.g. "SM8550"
+    pub name: &'static str,
+    pub marketing_name: &'static str,
+}
+
+/// Chipsets known to the loader identification
+pub const CHIPSETS: &[Chipset] = &[
+    Chipset {
+        name: "SM4250",
+        marketing_name: "Snapdragon 460",
+    },
+    Chipset {
+        name: "SM4350",
+        marketing_name: "Snapdragon 480",
+    },
+    Chipset {
+        name: "SM6375",
+        marketing_name: "Snapdragon 695",
+    },
+    Chipset {
+        name: "SM7475",
+        marketing_name: "Snapdragon 7+ Gen 2",
+    },
+    Chipset {
+        name: "SM7675",
+        marketing_name: "Snapdragon 7+ Gen 3",
+    },
+    Chipset {
+        name: "SM8350",
+        marketing_name: "Snapdragon 888",
+    },
+    Chipset {
+        name: "SM8450",
+        marketing_name: "Snapdragon 8 Gen 1",
+    },
+    Chipset {
+        name: "SM8475",
+        marketing_name: "Snapdragon 8+ Gen 1",
+    },
+    Chipset {
+        name: "SM8550",
+        marketing_name: "Snapdragon 8 Gen 2",
+    },
+    Chipset {
+        name: "SM8650",
+        marketing_name: "Snapdragon 8 Gen 3",
+    },
+    Chipset {
+        name: "SM8750",
+        marketing_name: "Snapdragon 8 Elite",
+    },
+];
+
+pub fn chipset_by_name(name: &str) -> Option<&'static Chipset> {
+    CHIPSETS.iter().find(|c| c.name == name)
+}
+
+/// JTAG IDs (without the version nibble) reported in the Sahara HWID.
+/// Chipsets missing here are reported as unknown rather than guessed.
+const MSM_IDS: &[(u32, &str)] = &[
+    (0x1350e1, "SM8350"),
+    (0x1620e1, "SM8450"),
+    (0x22a0e1, "SM8550"),
+];
+
+pub fn chipset_by_msm_id(msm_id: u32) -> Option<&'static Chipset> {
+    let (_, name) = MSM_IDS.iter().find(|(id, _)| *id == msm_id)?;
+    chipset_by_name(name)
+}
+
 pub fn identify_loader<P: AsRef<Path>>(file_path: P) -> String {
     let mut buffer = Vec::new();
     match File::open(file_path) {
@@ -124,20 +194,8 @@ pub fn identify_loader<P: AsRef<Path>>(file_path: P) -> String {
         }
     }
 
-    let mut map = HashMap::new();
-    map.insert("SM4250", "Snapdragon 460");
-    map.insert("SM4350", "Snapdragon 480");
-    map.insert("SM6375", "Snapdragon 695");
-    map.insert("SM7475", "Snapdragon 7+ Gen 2");
-    map.insert("SM7675", "Snapdragon 7+ Gen 3");
-    map.insert("SM8350", "Snapdragon 888");
-    map.insert("SM8450", "Snapdragon 8 Gen 1");
-    map.insert("SM8475", "Snapdragon 8+ Gen 1");
-    map.insert("SM8550", "Snapdragon 8 Gen 2");
-    map.insert("SM8650", "Snapdragon 8 Gen 3");
-    map.insert("SM8750", "Snapdragon 8 Elite");
-    if let Some(model) = map.get(result.as_str()) {
-        result = format!("{} ({})", result, model);
+    if let Some(chipset) = chipset_by_name(&result) {
+        result = format!("{} ({})", result, chipset.marketing_name);
     } else {
         result = format!("{} (Unknown)", result);
     }
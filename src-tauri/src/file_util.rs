@@ -1,6 +1,5 @@
 use crate::xml_file_util;
 use glob::glob;
-use regex::Regex;
 use std::fmt;
 use std::fs;
 use std::fs::metadata;
@@ -171,48 +170,6 @@ pub fn parse_file_path(parent_dir: &str, path: &str) -> (String, String) {
     (file_name, directory)
 }
 
-pub fn analysis_info(input: &str) -> String {
-    let mut output = String::new();
-    let re = Regex::new(r"0x[0-9a-fA-F]+").expect("Reg compile failed");
-    input.lines().for_each(|line| {
-        if line.contains("Device Total Physical Partitions") {
-            if let Some(hex_match) = re.find(line) {
-                let hex_str = hex_match.as_str();
-                match u64::from_str_radix(&hex_str[2..], 16) {
-                    Ok(decimal) => {
-                        output =
-                            format!("{}\n Device Total Physical Partitions:{}", output, decimal);
-                    }
-                    Err(e) => eprintln!("Convert Failed: {}", e),
-                }
-            }
-            println!("{}", line);
-        } else if line.contains("Device Serial Number") {
-            if let Some(hex_match) = re.find(line) {
-                let hex_str = hex_match.as_str();
-                output = format!("{}\n Device Serial Number:{}", output, hex_str);
-            }
-        } else if line.contains("UFS Inquiry Command Output") {
-            if let Some((_, content)) = line.split_once("Output:") {
-                let model = content.replace("'", " ");
-                output = format!("{}\n Storage:{}", output, model.trim());
-            }
-        } else if line.contains("Boot Partition Enabled") {
-            if let Some(hex_match) = re.find(line) {
-                let hex_str = hex_match.as_str();
-                match u64::from_str_radix(&hex_str[2..], 16) {
-                    Ok(decimal) => {
-                        let slot = if decimal == 1 { "A" } else { "B" };
-                        output = format!("{}\n Active slot:{}", output, slot);
-                    }
-                    Err(e) => eprintln!("Convert Failed: {}", e),
-                }
-            }
-        }
-    });
-    return output;
-}
-
 /// Check all files matching pattern in the specified folder and return their paths
 ///
 /// # Parameters
@@ -0,0 +1,921 @@
+use crate::gpt_parser::{self, GptError, GptHeader};
+use crate::qdl::SaharaClient;
+use crate::qdl::firehose::{
+    PeekProgress, firehose_benchmark_sweep, firehose_checksum_storage, firehose_dump_memory,
+    firehose_erase, firehose_get_storage_info, firehose_nop, firehose_patch,
+    firehose_program_storage, firehose_read_storage, firehose_reset, firehose_set_bootable,
+    firehose_ufs, firehose_zeroout,
+};
+use crate::qdl::sparse::{SparseReader, sparse_runs};
+use crate::qdl::types::{
+    BenchmarkResult, FirehoseResetMode, QdlChan, QdlDevice, QdlReadWrite, StorageInfo,
+};
+use crate::xml_file_util::{self, DataRoot, EraseTag, PatchTag, Program, ReadTag, UfsProvision};
+use anyhow::{Context, bail};
+use quick_xml::de::from_str;
+use ring::digest::{Context as DigestContext, SHA256};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom};
+use std::path::Path;
+use tauri::{AppHandle, Emitter};
+
+/// Where the Firehose operations of a device end up. The XML handed in is
+/// the same `<data>` document fh_loader would get as cmd.xml.
+pub trait FlashBackend {
+    /// Run the `<erase>` tags of `xml`
+    fn erase(&mut self, part: &str, xml: &str) -> Result<String, String>;
+
+    /// Run the `<read>` tags of `xml` (`<program>` ones are read back
+    /// instead of written), saving the files into `folder`
+    fn read(&mut self, part: &str, xml: &str, folder: &str) -> Result<String, String>;
+
+    /// Run the `<program>` tags of `xml`, the images are looked up in `dir`
+    fn program(&mut self, part: &str, xml: &str, dir: &str) -> Result<String, String>;
+
+    /// Apply the `<patch>` tags of `folder/file` that target the device
+    fn patch(&mut self, folder: &str, file: &str) -> Result<String, String>;
+
+    fn power(&mut self, mode: &FirehoseResetMode) -> Result<String, String>;
+
+    /// Make the LUN of `slot` ("A" or "B") the boot one
+    fn set_active_slot(&mut self, slot: &str) -> Result<String, String>;
+
+    fn nop(&mut self) -> Result<String, String>;
+
+    /// `<getstorageinfo>` of the physical partition `lun`
+    fn storage_info(&mut self, lun: u8) -> Result<StorageInfo, String>;
+
+    /// SHA256 of `num_sectors` sectors of `lun` from `start_sector`, as
+    /// computed by the device through `<getsha256digest>`
+    fn digest(
+        &mut self,
+        lun: u8,
+        start_sector: &str,
+        num_sectors: u64,
+        sector_size: u64,
+    ) -> Result<[u8; 32], String>;
+}
+
+/// Outcome of checking one `<program>` against the device
+#[derive(Clone, Debug, Serialize)]
+pub struct VerifyResult {
+    pub label: String,
+    pub filename: String,
+    pub physical_partition_number: u8,
+    pub start_sector: String,
+    pub num_sectors: u64,
+    pub host_sha256: String,
+    pub device_sha256: String,
+    pub passed: bool,
+    /// Why the digests couldn't be compared, if so
+    pub error: String,
+}
+
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Boot LUN of each slot, as set through `<setbootablestoragedrive>`
+fn slot_boot_lun(slot: &str) -> u8 {
+    match slot {
+        "A" => 1,
+        _ => 2,
+    }
+}
+
+fn send_ufs(
+    channel: &mut QdlDevice<dyn QdlReadWrite>,
+    attrs: &[(&str, String)],
+    what: &str,
+) -> anyhow::Result<()> {
+    let attrs: Vec<(&str, &str)> = attrs.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    firehose_ufs(channel, &attrs, what)
+}
+
+/// The `<ufs>` tags of `provision`, closed by a `commit="0"` one and, only
+/// if `commit` is set, a `commit="1"` one
+pub fn send_ufs_provision(
+    channel: &mut QdlDevice<dyn QdlReadWrite>,
+    provision: &UfsProvision,
+    commit: bool,
+) -> anyhow::Result<()> {
+    send_ufs(channel, &provision.common.attributes(), "the device")?;
+    for lun in &provision.luns {
+        send_ufs(channel, &lun.attributes(), &format!("LUN {}", lun.lun))?;
+    }
+    send_ufs(channel, &provision.commit_attributes(false), "the layout")?;
+    if commit {
+        send_ufs(channel, &provision.commit_attributes(true), "the commit")?;
+    }
+    Ok(())
+}
+
+fn parse_data(xml: &str) -> anyhow::Result<DataRoot> {
+    from_str::<DataRoot>(xml).context("XML parsing failed")
+}
+
+/// Speaks Firehose over the channel the loader was sent on, no fh_loader
+/// or QSaharaServer involved
+pub struct NativeBackend {
+    client: SaharaClient,
+    app: AppHandle,
+    log_prefix: String,
+}
+
+impl NativeBackend {
+    /// `client` must have sent the loader already, see `SaharaClient::send_images`
+    pub fn new(app: &AppHandle, client: SaharaClient) -> Self {
+        Self {
+            client,
+            app: app.clone(),
+            log_prefix: String::new(),
+        }
+    }
+
+    /// Prefix of the log lines, e.g. the device a flash job runs on
+    pub fn set_log_prefix(&mut self, prefix: &str) {
+        self.log_prefix = prefix.to_string();
+    }
+
+    /// Dump `len` bytes of memory from `addr` into `path` with \<peek\>s.
+    /// With `resume`, whatever `path` already holds is taken as the start of
+    /// the dump and only the rest is fetched.
+    pub fn dump_memory(
+        &mut self,
+        addr: u64,
+        len: u64,
+        path: &Path,
+        resume: bool,
+        progress: PeekProgress,
+    ) -> Result<String, String> {
+        let msg = format!("Dump memory 0x{:x}+0x{:x} to {}", addr, len, path.display());
+        self.run(&msg, |channel| {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Couldn't open {}", path.display()))?;
+            let mut offset = file.metadata()?.len();
+            if !resume || offset > len {
+                file.set_len(0)?;
+                offset = 0;
+            }
+            firehose_dump_memory(
+                channel,
+                addr,
+                len,
+                offset,
+                &mut BufWriter::new(file),
+                progress,
+            )
+        })
+    }
+
+    /// Time the loader's reads and writes with each of `payload_sizes`, see
+    /// `firehose_benchmark_sweep`
+    pub fn benchmark(
+        &mut self,
+        trials: u32,
+        payload_sizes: &[usize],
+    ) -> Result<Vec<BenchmarkResult>, String> {
+        let mut results = Vec::new();
+        self.run(&format!("Benchmark, {} trials", trials), |channel| {
+            results = firehose_benchmark_sweep(channel, trials, payload_sizes)?;
+            Ok(())
+        })?;
+        Ok(results)
+    }
+
+    /// Send the `<ufs>` tags of `provision`. The loader always checks the
+    /// whole layout first, with a `commit="0"` closing tag, and only writes
+    /// it to the device if `commit` is set, which can't be undone.
+    pub fn provision_ufs(
+        &mut self,
+        provision: &UfsProvision,
+        commit: bool,
+    ) -> Result<String, String> {
+        let msg = match commit {
+            true => "Provision UFS",
+            false => "Check UFS provisioning",
+        };
+        self.run(msg, |channel| {
+            send_ufs_provision(channel, provision, commit)
+        })
+    }
+
+    /// Run `op` on the channel and log the outcome the way
+    /// `command_util::exec_cmd_with_msg` does. A loader asking for a restart
+    /// is loaded again and `op` retried, see `SaharaClient::with_recovery`.
+    fn run(
+        &mut self,
+        msg: &str,
+        op: impl FnMut(&mut QdlDevice<dyn QdlReadWrite>) -> anyhow::Result<()>,
+    ) -> Result<String, String> {
+        match self.client.with_recovery(op) {
+            Ok(_) => {
+                let _ = self
+                    .app
+                    .emit("log_event", format!("{}{}...OK", self.log_prefix, msg));
+                Ok(String::new())
+            }
+            Err(e) => {
+                let _ = self
+                    .app
+                    .emit("log_event", format!("{}{}...Error", self.log_prefix, msg));
+                let _ = self
+                    .app
+                    .emit("log_event", format!("{}{:#}", self.log_prefix, e));
+                Err(format!("{:#}", e))
+            }
+        }
+    }
+}
+
+/// Sectors a `<program>` of an `image_len` bytes image covers: like
+/// fh_loader, the image size rounded up to whole sectors, but never past
+/// the partition
+fn program_sectors(program: &Program, image_len: u64) -> u64 {
+    let num_sectors = image_len.div_ceil(program.sector_size_in_bytes);
+    if program.num_partition_sectors > 0 {
+        num_sectors.min(program.num_partition_sectors)
+    } else {
+        num_sectors
+    }
+}
+
+fn open_image(program: &Program, dir: &str) -> anyhow::Result<File> {
+    let path = Path::new(dir).join(&program.filename);
+    File::open(&path).with_context(|| format!("Couldn't open {}", path.display()))
+}
+
+/// Flash a sparse image as it gets expanded, without an intermediate raw
+/// copy: one `<program>` per run of data, one `<zeroout>` per run of zeros
+fn program_sparse<T: QdlChan>(
+    channel: &mut T,
+    program: &Program,
+    file: File,
+) -> anyhow::Result<()> {
+    let start_sector = program
+        .start_sector
+        .trim()
+        .parse::<u64>()
+        .with_context(|| {
+            format!(
+                "Can't flash sparse {}: start sector {} isn't a plain number",
+                program.label, program.start_sector
+            )
+        })?;
+    let sector_size = program.sector_size_in_bytes;
+    let mut reader = SparseReader::new(file)?;
+    let block_size = reader.image().block_size as u64;
+    if !block_size.is_multiple_of(sector_size) {
+        bail!(
+            "{} has {} bytes blocks, which aren't whole {} bytes sectors",
+            program.filename,
+            block_size,
+            sector_size
+        );
+    }
+    let sectors_per_block = block_size / sector_size;
+    let total_sectors = reader.image().total_blocks * sectors_per_block;
+    if program.num_partition_sectors > 0 && total_sectors > program.num_partition_sectors {
+        bail!(
+            "{} expands to {} sectors, {} only has {}",
+            program.filename,
+            total_sectors,
+            program.label,
+            program.num_partition_sectors
+        );
+    }
+
+    channel.mut_fh_config().storage_sector_size = sector_size as usize;
+    for run in sparse_runs(reader.image()) {
+        let start = (start_sector + run.start_block * sectors_per_block).to_string();
+        let num_sectors = (run.num_blocks * sectors_per_block) as usize;
+        if run.zero {
+            firehose_zeroout(
+                channel,
+                num_sectors,
+                0,
+                program.physical_partition_number,
+                &start,
+            )?;
+        } else {
+            reader.seek_block(run.start_block);
+            firehose_program_storage(
+                channel,
+                &mut reader,
+                &program.label,
+                num_sectors,
+                0,
+                program.physical_partition_number,
+                &start,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn program_image<T: QdlChan>(channel: &mut T, program: &Program, dir: &str) -> anyhow::Result<()> {
+    let mut file = open_image(program, dir)?;
+    if program.sparse {
+        return program_sparse(channel, program, file);
+    }
+    let sector_size = program.sector_size_in_bytes;
+    let offset = program.file_sector_offset * sector_size;
+    let file_len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(offset))?;
+
+    let num_sectors = program_sectors(program, file_len.saturating_sub(offset));
+    if num_sectors == 0 {
+        return Ok(());
+    }
+
+    channel.mut_fh_config().storage_sector_size = sector_size as usize;
+    firehose_program_storage(
+        channel,
+        &mut file,
+        &program.label,
+        num_sectors as usize,
+        0,
+        program.physical_partition_number,
+        &program.start_sector,
+    )
+}
+
+/// The sectors `program_image` writes and their SHA256: the image from
+/// file_sector_offset (expanded, if sparse) zero padded to whole sectors
+fn image_sha256(program: &Program, dir: &str) -> anyhow::Result<(u64, [u8; 32])> {
+    let mut file = open_image(program, dir)?;
+    let (mut data, image_len): (Box<dyn Read>, u64) = if program.sparse {
+        let reader = SparseReader::new(file)?;
+        let len = reader.image().expanded_len();
+        (Box::new(reader), len)
+    } else {
+        let offset = program.file_sector_offset * program.sector_size_in_bytes;
+        let len = file.metadata()?.len().saturating_sub(offset);
+        file.seek(SeekFrom::Start(offset))?;
+        (Box::new(file), len)
+    };
+
+    let num_sectors = program_sectors(program, image_len);
+    let mut left = num_sectors * program.sector_size_in_bytes;
+    let mut ctx = DigestContext::new(&SHA256);
+    let mut buf = vec![0u8; 1024 * 1024];
+    while left > 0 {
+        let len = buf.len().min(left as usize);
+        let read = data.read(&mut buf[..len])?;
+        if read == 0 {
+            // Padding up to the sector boundary
+            buf[..len].fill(0);
+            ctx.update(&buf[..len]);
+            left -= len as u64;
+            continue;
+        }
+        ctx.update(&buf[..read]);
+        left -= read as u64;
+    }
+
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(ctx.finish().as_ref());
+    Ok((num_sectors, digest))
+}
+
+/// Compare what each `<program>` of `xml` would write, the images being
+/// looked up in `dir`, with what the device holds
+pub fn verify_programs(
+    backend: &mut dyn FlashBackend,
+    xml: &str,
+    dir: &str,
+) -> Result<Vec<VerifyResult>, String> {
+    let data = parse_data(xml).map_err(|e| format!("{:#}", e))?;
+    let mut results = Vec::new();
+    for program in data.programs {
+        if program.filename.trim().is_empty() {
+            continue;
+        }
+        let mut result = VerifyResult {
+            label: program.label.clone(),
+            filename: program.filename.clone(),
+            physical_partition_number: program.physical_partition_number,
+            start_sector: program.start_sector.clone(),
+            num_sectors: 0,
+            host_sha256: String::new(),
+            device_sha256: String::new(),
+            passed: false,
+            error: String::new(),
+        };
+        match image_sha256(&program, dir) {
+            Ok((num_sectors, host)) => {
+                result.num_sectors = num_sectors;
+                result.host_sha256 = to_hex(&host);
+                match backend.digest(
+                    program.physical_partition_number,
+                    &program.start_sector,
+                    num_sectors,
+                    program.sector_size_in_bytes,
+                ) {
+                    Ok(device) => {
+                        result.device_sha256 = to_hex(&device);
+                        result.passed = device == host;
+                    }
+                    Err(e) => result.error = e,
+                }
+            }
+            Err(e) => result.error = format!("{:#}", e),
+        }
+        results.push(result);
+    }
+    Ok(results)
+}
+
+/// A GPT copy being switched to another slot: `data` holds the sectors
+/// from `start_sector`, `header` and `entries` are offsets into it
+struct GptCopy {
+    label: &'static str,
+    filename: String,
+    start_sector: u64,
+    data: Vec<u8>,
+    header: usize,
+    entries: usize,
+}
+
+impl GptCopy {
+    fn switch(&mut self, slot: &str) -> Result<usize, GptError> {
+        let (head, tail) = self.data.split_at_mut(self.header.max(self.entries));
+        let (header, entries) = match self.header < self.entries {
+            true => (&mut head[self.header..], tail),
+            false => (tail, &mut head[self.entries..]),
+        };
+        gpt_parser::switch_ab_slot(header, entries, slot)
+    }
+}
+
+fn data_xml<T: Serialize>(tag: &T) -> String {
+    format!(
+        "<?xml version=\"1.0\" ?>\n<data>\n{}\n</data>\n",
+        xml_file_util::to_xml(tag)
+    )
+}
+
+fn read_gpt_copy(
+    backend: &mut dyn FlashBackend,
+    lun: u8,
+    sector_size: u64,
+    start_sector: u64,
+    num_sectors: u64,
+    label: &'static str,
+    folder: &str,
+) -> Result<GptCopy, String> {
+    // What the device held is kept aside, the switched copy gets its own file
+    let orig_filename = format!("{}{}.orig.bin", label, lun);
+    let read = xml_file_util::create_read_tag_dynamic(
+        &orig_filename,
+        lun,
+        start_sector,
+        num_sectors,
+        sector_size,
+        label,
+    );
+    backend.read(
+        &format!("{} of LUN {}", label, lun),
+        &data_xml(&read),
+        folder,
+    )?;
+    let path = Path::new(folder).join(&orig_filename);
+    let data = fs::read(&path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+    if data.len() as u64 != num_sectors * sector_size {
+        return Err(format!("Short read of the {} of LUN {}", label, lun));
+    }
+    Ok(GptCopy {
+        label,
+        filename: format!("{}{}.bin", label, lun),
+        start_sector,
+        data,
+        header: 0,
+        entries: 0,
+    })
+}
+
+/// Read the primary and backup GPTs of `lun`, None if it has no GPT
+fn read_gpt_copies(
+    backend: &mut dyn FlashBackend,
+    lun: u8,
+    sector_size: u64,
+    folder: &str,
+) -> Result<Option<[GptCopy; 2]>, String> {
+    let ss = sector_size as usize;
+    let gpt_err = |e: GptError| format!("Bad GPT on LUN {}: {}", lun, e);
+
+    // Protective MBR, GPT header and the 16 KiB of partition entries, like read_gpt
+    let mut primary = read_gpt_copy(
+        backend,
+        lun,
+        sector_size,
+        0,
+        2 + 16384 / sector_size,
+        "PrimaryGPT",
+        folder,
+    )?;
+    let header = match GptHeader::parse(&primary.data[ss..]) {
+        Ok(header) => header,
+        Err(GptError::InvalidSignature) => return Ok(None),
+        Err(e) => return Err(gpt_err(e)),
+    };
+    primary.header = ss;
+    primary.entries = header.entries_lba as usize * ss;
+    if header.current_lba != 1
+        || header.entries_lba < 2
+        || primary.entries + header.entries_len() > primary.data.len()
+    {
+        return Err(gpt_err(GptError::EntryOutOfBounds));
+    }
+
+    // The backup entries sit right before the backup header, in the last sector
+    let entries_sectors = header.entries_sectors(sector_size);
+    let Some(start_sector) = header.alternate_lba.checked_sub(entries_sectors) else {
+        return Err(gpt_err(GptError::EntryOutOfBounds));
+    };
+    let mut backup = read_gpt_copy(
+        backend,
+        lun,
+        sector_size,
+        start_sector,
+        entries_sectors + 1,
+        "BackupGPT",
+        folder,
+    )?;
+    backup.header = entries_sectors as usize * ss;
+    let backup_header = GptHeader::parse(&backup.data[backup.header..]).map_err(gpt_err)?;
+    if backup_header.current_lba != header.alternate_lba
+        || backup_header.entries_lba != start_sector
+        || backup_header.entries_len() != header.entries_len()
+    {
+        return Err(format!(
+            "The backup GPT of LUN {} doesn't match the primary one",
+            lun
+        ));
+    }
+
+    Ok(Some([primary, backup]))
+}
+
+/// Make `slot` ("A" or "B") the active one as the bootloader sees it: the
+/// A/B attribute bits of the slotted entries are switched in the primary and
+/// backup GPTs of each LUN of `layout`, the GPTs going through `folder`:
+/// PrimaryGPT{lun}.orig.bin and BackupGPT{lun}.orig.bin keep what was read,
+/// the switched copies are programmed from PrimaryGPT{lun}.bin and
+/// BackupGPT{lun}.bin. Nothing is written unless every GPT could be read
+/// and switched. Returns how many entries of `slot` were made active.
+pub fn switch_slot_gpt(
+    backend: &mut dyn FlashBackend,
+    slot: &str,
+    layout: &[(u8, u64)],
+    folder: &str,
+) -> Result<usize, String> {
+    fs::create_dir_all(folder).map_err(|e| e.to_string())?;
+    let mut switched = 0;
+    let mut writes = Vec::new();
+    for &(lun, sector_size) in layout {
+        let Some(mut copies) = read_gpt_copies(backend, lun, sector_size, folder)? else {
+            continue;
+        };
+        let mut counts = [0; 2];
+        for (copy, count) in copies.iter_mut().zip(counts.iter_mut()) {
+            *count = copy
+                .switch(slot)
+                .map_err(|e| format!("Bad {} on LUN {}: {}", copy.label, lun, e))?;
+        }
+        if counts[0] != counts[1] {
+            return Err(format!(
+                "The backup GPT of LUN {} doesn't match the primary one",
+                lun
+            ));
+        }
+        if counts[0] > 0 {
+            switched += counts[0];
+            writes.push((lun, sector_size, copies));
+        }
+    }
+    if switched == 0 {
+        return Err(format!("No partition of slot {} found", slot));
+    }
+
+    // The backup first, a bootloader finding the primary broken falls back to it
+    for (lun, sector_size, copies) in writes {
+        for copy in copies.iter().rev() {
+            let path = Path::new(folder).join(&copy.filename);
+            fs::write(&path, &copy.data)
+                .map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+            let mut program = xml_file_util::create_program_dynamic(
+                lun,
+                copy.start_sector,
+                copy.data.len() as u64 / sector_size,
+                sector_size,
+                copy.label,
+            );
+            program.filename = copy.filename.clone();
+            backend.program(
+                &format!("{} of LUN {}", copy.label, lun),
+                &data_xml(&program),
+                folder,
+            )?;
+        }
+    }
+    Ok(switched)
+}
+
+fn read_to_file<T: QdlChan>(channel: &mut T, read: &ReadTag, folder: &str) -> anyhow::Result<()> {
+    let start_sector = u32::try_from(read.start_sector)
+        .with_context(|| format!("Start sector {} is out of range", read.start_sector))?;
+    let path = Path::new(folder).join(&read.filename);
+    let mut file =
+        File::create(&path).with_context(|| format!("Couldn't create {}", path.display()))?;
+
+    channel.mut_fh_config().storage_sector_size = read.sector_size_in_bytes as usize;
+    firehose_read_storage(
+        channel,
+        &mut file,
+        read.num_partition_sectors as usize,
+        0,
+        read.physical_partition_number,
+        start_sector,
+    )
+}
+
+/// The `<read>` counterpart of a `<program>`, fh_loader's --convertprogram2read
+fn program_to_read(program: Program) -> anyhow::Result<ReadTag> {
+    let start_sector = program
+        .start_sector
+        .trim()
+        .parse::<u64>()
+        .with_context(|| {
+            format!(
+                "Can't read {}: start sector {} isn't a plain number",
+                program.label, program.start_sector
+            )
+        })?;
+    Ok(ReadTag {
+        filename: program.filename,
+        physical_partition_number: program.physical_partition_number,
+        label: program.label,
+        start_sector,
+        num_partition_sectors: program.num_partition_sectors,
+        sector_size_in_bytes: program.sector_size_in_bytes,
+        sparse: program.sparse,
+    })
+}
+
+fn erase_range<T: QdlChan>(channel: &mut T, erase: &EraseTag) -> anyhow::Result<()> {
+    channel.mut_fh_config().storage_sector_size = erase.sector_size_in_bytes as usize;
+    firehose_erase(
+        channel,
+        erase.num_partition_sectors as usize,
+        0,
+        erase.physical_partition_number,
+        &erase.start_sector.to_string(),
+    )
+}
+
+fn apply_patch<T: QdlChan>(channel: &mut T, patch: &PatchTag) -> anyhow::Result<()> {
+    channel.mut_fh_config().storage_sector_size = patch.sector_size_in_bytes as usize;
+    firehose_patch(
+        channel,
+        patch.byte_offset,
+        0,
+        patch.physical_partition_number,
+        patch.size_in_bytes,
+        &patch.start_sector,
+        &patch.value,
+    )
+}
+
+impl FlashBackend for NativeBackend {
+    fn erase(&mut self, part: &str, xml: &str) -> Result<String, String> {
+        self.run(&format!("Erase partition {}", part), |channel| {
+            for erase in parse_data(xml)?.erase_tags {
+                erase_range(channel, &erase)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn read(&mut self, part: &str, xml: &str, folder: &str) -> Result<String, String> {
+        self.run(&format!("Read partition {}...", part), |channel| {
+            let data = parse_data(xml)?;
+            fs::create_dir_all(folder)?;
+            for read in data.read_tags {
+                read_to_file(channel, &read, folder)?;
+            }
+            for program in data.programs {
+                if program.filename.trim().is_empty() {
+                    continue;
+                }
+                read_to_file(channel, &program_to_read(program)?, folder)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn program(&mut self, part: &str, xml: &str, dir: &str) -> Result<String, String> {
+        self.run(&format!("Write partition {}", part), |channel| {
+            for program in parse_data(xml)?.programs {
+                // fh_loader skips the ones without an image as well
+                if program.filename.trim().is_empty() {
+                    continue;
+                }
+                program_image(channel, &program, dir)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn patch(&mut self, folder: &str, file: &str) -> Result<String, String> {
+        self.run(&format!("Flash patch file: {}...", file), |channel| {
+            let path = Path::new(folder).join(file);
+            let xml = fs::read_to_string(&path)
+                .with_context(|| format!("Couldn't read {}", path.display()))?;
+            // The others patch the host-side GPT copies, fh_loader skips them too
+            for patch in parse_data(&xml)?.patches {
+                if patch.filename == "DISK" {
+                    apply_patch(channel, &patch)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn power(&mut self, mode: &FirehoseResetMode) -> Result<String, String> {
+        let msg = match mode {
+            FirehoseResetMode::ResetToEdl => "Reboot to EDL",
+            FirehoseResetMode::Reset => "Reboot to System",
+            FirehoseResetMode::Off => "Power off",
+        };
+        self.run(msg, |channel| firehose_reset(channel, mode, 0))
+    }
+
+    fn set_active_slot(&mut self, slot: &str) -> Result<String, String> {
+        self.run("Set active slot", |channel| {
+            firehose_set_bootable(channel, slot_boot_lun(slot))
+        })
+    }
+
+    fn nop(&mut self) -> Result<String, String> {
+        self.run("Send Ping Command", firehose_nop)
+    }
+
+    fn storage_info(&mut self, lun: u8) -> Result<StorageInfo, String> {
+        let mut info = StorageInfo::default();
+        self.run(&format!("Read storage info of LUN {}", lun), |channel| {
+            info = firehose_get_storage_info(channel, lun)?;
+            Ok(())
+        })?;
+        Ok(info)
+    }
+
+    fn digest(
+        &mut self,
+        lun: u8,
+        start_sector: &str,
+        num_sectors: u64,
+        sector_size: u64,
+    ) -> Result<[u8; 32], String> {
+        let mut digest = [0u8; 32];
+        let msg = format!("SHA256 of LUN {} from sector {}", lun, start_sector);
+        self.run(&msg, |channel| {
+            channel.mut_fh_config().storage_sector_size = sector_size as usize;
+            digest = firehose_checksum_storage(channel, num_sectors as usize, lun, start_sector)?;
+            Ok(())
+        })?;
+        Ok(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR: u64 = 512;
+
+    /// A single LUN held in memory, only reads and writes go through
+    struct MemBackend {
+        disk: Vec<u8>,
+    }
+
+    impl FlashBackend for MemBackend {
+        fn erase(&mut self, _part: &str, _xml: &str) -> Result<String, String> {
+            unimplemented!()
+        }
+        fn read(&mut self, _part: &str, xml: &str, folder: &str) -> Result<String, String> {
+            for read in parse_data(xml).map_err(|e| e.to_string())?.read_tags {
+                let start = (read.start_sector * SECTOR) as usize;
+                let end = start + (read.num_partition_sectors * SECTOR) as usize;
+                fs::write(
+                    Path::new(folder).join(&read.filename),
+                    &self.disk[start..end],
+                )
+                .map_err(|e| e.to_string())?;
+            }
+            Ok("OK".to_owned())
+        }
+        fn program(&mut self, _part: &str, xml: &str, dir: &str) -> Result<String, String> {
+            for program in parse_data(xml).map_err(|e| e.to_string())?.programs {
+                let data =
+                    fs::read(Path::new(dir).join(&program.filename)).map_err(|e| e.to_string())?;
+                let start = program.start_sector.parse::<usize>().unwrap() * SECTOR as usize;
+                self.disk[start..start + data.len()].copy_from_slice(&data);
+            }
+            Ok("OK".to_owned())
+        }
+        fn patch(&mut self, _folder: &str, _file: &str) -> Result<String, String> {
+            unimplemented!()
+        }
+        fn power(&mut self, _mode: &FirehoseResetMode) -> Result<String, String> {
+            unimplemented!()
+        }
+        fn set_active_slot(&mut self, _slot: &str) -> Result<String, String> {
+            unimplemented!()
+        }
+        fn nop(&mut self) -> Result<String, String> {
+            unimplemented!()
+        }
+        fn storage_info(&mut self, _lun: u8) -> Result<StorageInfo, String> {
+            unimplemented!()
+        }
+        fn digest(
+            &mut self,
+            _lun: u8,
+            _start_sector: &str,
+            _num_sectors: u64,
+            _sector_size: u64,
+        ) -> Result<[u8; 32], String> {
+            unimplemented!()
+        }
+    }
+
+    /// A 128-sector disk with boot_a active and boot_b not, 128 entries of
+    /// 128 bytes in both GPTs
+    fn ab_disk() -> Vec<u8> {
+        let ss = SECTOR as usize;
+        let mut disk = vec![0u8; 128 * ss];
+        let mut entries = vec![0u8; 128 * 128];
+        for (idx, (name, flags)) in [("boot_a", 0x7fu8), ("boot_b", 0)].iter().enumerate() {
+            let entry = &mut entries[idx * 128..(idx + 1) * 128];
+            entry[..16].fill(0xa5);
+            entry[48 + 6] = *flags;
+            for (i, c) in name.encode_utf16().enumerate() {
+                entry[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+        for (header_lba, alternate_lba, entries_lba) in [(1u64, 127u64, 2u64), (127, 1, 95)] {
+            let header = &mut disk[header_lba as usize * ss..][..ss];
+            header[..8].copy_from_slice(b"EFI PART");
+            header[12..16].copy_from_slice(&92u32.to_le_bytes());
+            header[24..32].copy_from_slice(&header_lba.to_le_bytes());
+            header[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+            header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+            header[80..84].copy_from_slice(&128u32.to_le_bytes());
+            header[84..88].copy_from_slice(&128u32.to_le_bytes());
+            disk[entries_lba as usize * ss..][..entries.len()].copy_from_slice(&entries);
+        }
+        disk
+    }
+
+    #[test]
+    fn switch_slot_keeps_originals() {
+        let dir = std::env::temp_dir().join("qdl-switch-slot-test");
+        let _ = fs::remove_dir_all(&dir);
+        let folder = dir.to_str().unwrap();
+        let orig = ab_disk();
+        let mut backend = MemBackend { disk: orig.clone() };
+
+        let switched = switch_slot_gpt(&mut backend, "B", &[(0, SECTOR)], folder).unwrap();
+        assert_eq!(switched, 1);
+
+        // What was read is left as it came off the device
+        let ss = SECTOR as usize;
+        let primary = fs::read(dir.join("PrimaryGPT0.orig.bin")).unwrap();
+        let backup = fs::read(dir.join("BackupGPT0.orig.bin")).unwrap();
+        assert_eq!(primary, orig[..34 * ss]);
+        assert_eq!(backup, orig[95 * ss..]);
+
+        // The switched copies are the ones that went to the device
+        assert_eq!(
+            fs::read(dir.join("PrimaryGPT0.bin")).unwrap(),
+            backend.disk[..34 * ss]
+        );
+        assert_eq!(
+            fs::read(dir.join("BackupGPT0.bin")).unwrap(),
+            backend.disk[95 * ss..]
+        );
+        assert_ne!(backend.disk[..34 * ss], primary[..]);
+        assert_ne!(backend.disk[95 * ss..], backup[..]);
+        // boot_b is the active one in both GPTs
+        assert_eq!(backend.disk[2 * ss + 128 + 48 + 6] & 0x04, 0x04);
+        assert_eq!(backend.disk[95 * ss + 128 + 48 + 6] & 0x04, 0x04);
+    }
+}
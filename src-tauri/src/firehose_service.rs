@@ -1,11 +1,15 @@
 use crate::command_util;
 use crate::command_util::Config;
 use crate::file_util;
+use crate::firehose_backend::FlashBackend;
+use crate::qdl::parsers::{firehose_parse_sha256_digest, firehose_parse_storage_info};
+use crate::qdl::types::{FirehoseResetMode, StorageInfo};
 use std::fs;
 use tauri::{AppHandle, Emitter};
+use tokio::runtime::Runtime;
 
-pub async fn erase_part(app: &AppHandle, part: &str, xml_content: &str, config: &Config) {
-    let file_name = "res/cmd.xml";
+pub async fn erase_part(app: &AppHandle, part: &str, xml_content: &str, config: &Config) -> Result<String, String> {
+    let file_name = format!("{}/cmd.xml", config.work_dir);
     println!("file:{}", &file_name);
     if let Err(e) = fs::write(&file_name, xml_content) {
         let _ = app.emit(
@@ -13,7 +17,7 @@ pub async fn erase_part(app: &AppHandle, part: &str, xml_content: &str, config:
             format!("Write file {} failed: {}", file_name, e),
         );
         eprintln!("Write file {} failed: {}", file_name, e);
-        return;
+        return Err(format!("Write file {} failed: {}", file_name, e));
     } else {
         println!("success:{}", file_name);
     }
@@ -27,18 +31,18 @@ pub async fn erase_part(app: &AppHandle, part: &str, xml_content: &str, config:
             &config.fh_port_conn_str,
             "--memoryname=ufs",
             "--showpercentagecomplete",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--skip_configure",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
-        let _ = command_util::exec_cmd_with_msg(
+        command_util::exec_cmd_with_msg(
             &format!("Erase partition {}", part),
             &app,
             &config,
             &cmd,
         )
-        .await;
+        .await?;
     }
     #[cfg(target_os = "linux")]
     {
@@ -47,19 +51,20 @@ pub async fn erase_part(app: &AppHandle, part: &str, xml_content: &str, config:
             &config.fh_port_conn_str_linux,
             "--memoryname=ufs",
             "--showpercentagecomplete",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--zlpawarehost=1",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
-        let _ = command_util::exec_cmd_with_msg(
+        command_util::exec_cmd_with_msg(
             &format!("Erase partition {}", part),
             &app,
             &config,
             &cmd,
         )
-        .await;
+        .await?;
     }
+    Ok(String::new())
 }
 
 pub async fn exec_xml_cmd (
@@ -67,7 +72,7 @@ pub async fn exec_xml_cmd (
     xml_content: &str,
     config: &Config,
 ) -> Result<String, String> {
-    let file_name = "res/cmd.xml";
+    let file_name = format!("{}/cmd.xml", config.work_dir);
     println!("file:{}", &file_name);
     if let Err(e) = fs::write(&file_name, xml_content) {
         let _ = app.emit(
@@ -88,10 +93,10 @@ pub async fn exec_xml_cmd (
             &config.fh_loader_path,
             &config.fh_port_conn_str,
             "--memoryname=ufs",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--skip_configure",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         return command_util::exec_cmd_with_msg(
             "execute xml cmd",
@@ -107,10 +112,10 @@ pub async fn exec_xml_cmd (
             &*config.fh_port_conn_str_linux,
             "--memoryname=ufs",
             "--showpercentagecomplete",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--zlpawarehost=1",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         return command_util::exec_cmd_with_msg(
             "execute xml cmd",
@@ -144,7 +149,7 @@ pub async fn flash_patch_xml (
             &sendxml_str,
             "--noprompt",
             "--skip_configure",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         match command_util::exec_cmd_with_msg(
             &format!("Flash patch file: {}...", file_name),
@@ -169,7 +174,7 @@ pub async fn flash_patch_xml (
             &sendxml_str,
             "--noprompt",
             "--zlpawarehost=1",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         match command_util::exec_cmd_with_msg(
             &format!("Flash patch file: {}...", file_name),
@@ -193,7 +198,7 @@ pub async fn flash_part(
     config: &Config,
 ) -> Result<String, String> {
     let dir_str = format!("--search_path={}", &dir_path);
-    let file_name = "res/cmd.xml";
+    let file_name = format!("{}/cmd.xml", config.work_dir);
     println!("file:{}", &file_name);
     if let Err(e) = fs::write(&file_name, xml_content) {
         let result = format!("file{}failed:{}", file_name, e);
@@ -213,10 +218,10 @@ pub async fn flash_part(
             "--memoryname=ufs",
             &dir_str,
             "--showpercentagecomplete",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--skip_configure",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         return command_util::exec_cmd_with_msg(
             &format!("Writ partition {}", part),
@@ -233,10 +238,10 @@ pub async fn flash_part(
             "--memoryname=ufs",
             &dir_str,
             "--showpercentagecomplete",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--zlpawarehost=1",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         return command_util::exec_cmd_with_msg(
             &format!("Writ partition {}", part),
@@ -254,7 +259,7 @@ pub async fn read_part(
     folder: &str,
     config: &Config,
 ) -> Result<String, String> {
-    let file_name = "res/cmd.xml";
+    let file_name = format!("{}/cmd.xml", config.work_dir);
     println!("file:{}", &file_name);
     if let Err(e) = fs::write(&file_name, xml_content) {
         let _ = app.emit(
@@ -278,7 +283,7 @@ pub async fn read_part(
             "--memoryname=ufs",
             "--convertprogram2read",
             "--showpercentagecomplete",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--special_rw_mode=oplus_gptmain",
             "--skip_configure",
@@ -300,7 +305,7 @@ pub async fn read_part(
             "--memoryname=ufs",
             "--convertprogram2read",
             "--showpercentagecomplete",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--zlpawarehost=1",
             "--special_rw_mode=oplus_gptbackup",
@@ -316,10 +321,14 @@ pub async fn read_part(
     }
 }
 
-pub async fn read_storage_info(app: &AppHandle, config: &Config) -> Result<String, String> {
-    let cmd =
-        "<?xml version=\"1.0\" ?><data><getstorageinfo physical_partition_number=\"0\" /></data>";
-    file_util::write_to_file("cmd.xml", "res", &cmd);
+/// fh_loader echoes the loader's \<log\> messages, which is where the
+/// storage info is reported
+pub async fn read_storage_info(app: &AppHandle, lun: u8, config: &Config) -> Result<StorageInfo, String> {
+    let cmd = format!(
+        "<?xml version=\"1.0\" ?><data><getstorageinfo physical_partition_number=\"{}\" /></data>",
+        lun
+    );
+    file_util::write_to_file("cmd.xml", &config.work_dir, &cmd);
     #[cfg(target_os = "windows")]
     {
         let cmd = [
@@ -328,12 +337,13 @@ pub async fn read_storage_info(app: &AppHandle, config: &Config) -> Result<Strin
             &config.fh_loader_path,
             &config.fh_port_conn_str,
             "--memoryname=ufs",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--skip_configure",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
-        return command_util::exec_cmd_with_msg("Read storage info", &app, &config, &cmd).await;
+        let output = command_util::exec_cmd_with_msg("Read storage info", &app, &config, &cmd).await?;
+        Ok(firehose_parse_storage_info(lun, &output.lines().collect::<Vec<_>>()))
     }
     #[cfg(target_os = "linux")]
     {
@@ -341,82 +351,68 @@ pub async fn read_storage_info(app: &AppHandle, config: &Config) -> Result<Strin
             &*config.fh_loader_path_linux,
             &*config.fh_port_conn_str_linux,
             "--memoryname=ufs",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--zlpawarehost=1",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
-        return command_util::exec_cmd_with_msg("Read storage info", &app, &config, &cmd).await;
+        let output = command_util::exec_cmd_with_msg("Read storage info", app, config, &cmd).await?;
+        Ok(firehose_parse_storage_info(lun, &output.lines().collect::<Vec<_>>()))
     }
 }
 
-pub async fn reboot_to(app: &AppHandle, msg: &str, xml: &str, config: &Config) {
-    file_util::write_to_file("cmd1.xml", "res", &xml);
-    let cmd = "<?xml version=\"1.0\" ?><data><power DelayInSeconds=\"0\" value=\"reset\" /></data>";
-    file_util::write_to_file("cmd.xml", "res", &cmd);
-
+/// Like the storage info, the digest only shows up in the echoed \<log\>
+pub async fn read_sha256_digest(
+    app: &AppHandle,
+    lun: u8,
+    start_sector: &str,
+    num_sectors: u64,
+    sector_size: u64,
+    config: &Config,
+) -> Result<[u8; 32], String> {
+    let cmd = format!(
+        "<?xml version=\"1.0\" ?><data><getsha256digest SECTOR_SIZE_IN_BYTES=\"{}\" num_partition_sectors=\"{}\" physical_partition_number=\"{}\" start_sector=\"{}\" /></data>",
+        sector_size, num_sectors, lun, start_sector
+    );
+    file_util::write_to_file("cmd.xml", &config.work_dir, &cmd);
+    let msg = format!("SHA256 of LUN {} from sector {}", lun, start_sector);
     #[cfg(target_os = "windows")]
     {
-        // flash misc partition
         let cmd = [
             "cmd",
             "/c",
             &config.fh_loader_path,
             &config.fh_port_conn_str,
             "--memoryname=ufs",
-            "--search_path=res",
-            "--showpercentagecomplete",
-            "--sendxml=res/cmd1.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--skip_configure",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
-        let _ = command_util::exec_cmd_with_msg("Writ misc partition", &app, &config, &cmd).await;
-        // send reboot command
-        let cmd = [
-            "cmd",
-            "/c",
-            &config.fh_loader_path,
-            &config.fh_port_conn_str,
-            "--memoryname=ufs",
-            "--sendxml=res/cmd.xml",
-            "--noprompt",
-            "--skip_configure",
-            "--mainoutputdir=res",
-        ];
-        let _ = command_util::exec_cmd_with_msg(&msg, &app, &config, &cmd).await;
+        let output = command_util::exec_cmd_with_msg(&msg, app, config, &cmd).await?;
+        firehose_parse_sha256_digest(&output.lines().collect::<Vec<_>>())
+            .ok_or_else(|| "fh_loader printed no digest".to_string())
     }
     #[cfg(target_os = "linux")]
     {
         let cmd = [
-            &config.fh_loader_path_linux,
-            &config.fh_port_conn_str_linux,
-            "--memoryname=ufs",
-            "--search_path=res",
-            "--showpercentagecomplete",
-            "--sendxml=res/cmd1.xml",
-            "--noprompt",
-            "--zlpawarehost=1",
-            "--mainoutputdir=res",
-        ];
-        let _ = command_util::exec_cmd_with_msg("Writ misc partition", &app, &config, &cmd).await;
-        // send reboot command
-        let cmd = [
-            &config.fh_loader_path_linux,
-            &config.fh_port_conn_str_linux,
+            &*config.fh_loader_path_linux,
+            &*config.fh_port_conn_str_linux,
             "--memoryname=ufs",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--zlpawarehost=1",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
-        let _ = command_util::exec_cmd_with_msg(&msg, &app, &config, &cmd).await;
+        let output = command_util::exec_cmd_with_msg(&msg, app, config, &cmd).await?;
+        firehose_parse_sha256_digest(&output.lines().collect::<Vec<_>>())
+            .ok_or_else(|| "fh_loader printed no digest".to_string())
     }
 }
 
 pub async fn reboot_to_edl(app: &AppHandle, config: &Config) {
     let cmd = "<?xml version=\"1.0\" ?><data><power DelayInSeconds=\"0\" value=\"reset_to_edl\" /></data>";
-    file_util::write_to_file("cmd.xml", "res", &cmd);
+    file_util::write_to_file("cmd.xml", &config.work_dir, cmd);
     #[cfg(target_os = "windows")]
     {
         let cmd = [
@@ -425,10 +421,10 @@ pub async fn reboot_to_edl(app: &AppHandle, config: &Config) {
             &config.fh_loader_path,
             &config.fh_port_conn_str,
             "--memoryname=ufs",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--skip_configure",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         let _ = command_util::exec_cmd_with_msg("Reboot to EDL", &app, &config, &cmd).await;
     }
@@ -438,10 +434,10 @@ pub async fn reboot_to_edl(app: &AppHandle, config: &Config) {
             &config.fh_loader_path_linux,
             &config.fh_port_conn_str_linux,
             "--memoryname=ufs",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--zlpawarehost=1",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         let _ = command_util::exec_cmd_with_msg("Reboot to EDL", &app, &config, &cmd).await;
     }
@@ -449,7 +445,7 @@ pub async fn reboot_to_edl(app: &AppHandle, config: &Config) {
 
 pub async fn reboot_to_system(app: &AppHandle, config: &Config) {
     let cmd = "<?xml version=\"1.0\" ?><data><power DelayInSeconds=\"0\" value=\"reset\" /></data>";
-    file_util::write_to_file("cmd.xml", "res", &cmd);
+    file_util::write_to_file("cmd.xml", &config.work_dir, cmd);
     #[cfg(target_os = "windows")]
     {
         let cmd = [
@@ -458,10 +454,10 @@ pub async fn reboot_to_system(app: &AppHandle, config: &Config) {
             &config.fh_loader_path,
             &config.fh_port_conn_str,
             "--memoryname=ufs",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--skip_configure",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         let _ = command_util::exec_cmd_with_msg("Reboot to System", &app, &config, &cmd).await;
     }
@@ -471,10 +467,10 @@ pub async fn reboot_to_system(app: &AppHandle, config: &Config) {
             &config.fh_loader_path_linux,
             &config.fh_port_conn_str_linux,
             "--memoryname=ufs",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--zlpawarehost=1",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         let _ = command_util::exec_cmd_with_msg("Reboot to EDL", &app, &config, &cmd).await;
     }
@@ -503,7 +499,7 @@ pub async fn send_loader(app: &AppHandle, loader: &str, digest: &str, sig: &str,
             "--testvipimpact",
             "--noprompt",
             "--skip_configure",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         command_util::exec_cmd_with_msg("Send Digest", &app, &config, &cmd).await?;
 
@@ -515,7 +511,7 @@ pub async fn send_loader(app: &AppHandle, loader: &str, digest: &str, sig: &str,
             "--sendxml=res/transfercfg.xml",
             "--noprompt",
             "--skip_configure",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         command_util::exec_cmd_with_msg("Send Transfer Config", &app, &config, &cmd).await?;
 
@@ -527,7 +523,7 @@ pub async fn send_loader(app: &AppHandle, loader: &str, digest: &str, sig: &str,
             "--sendxml=res/verify.xml",
             "--noprompt",
             "--skip_configure",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         command_util::exec_cmd_with_msg("Send Verify", &app, &config, &cmd).await?;
 
@@ -540,7 +536,7 @@ pub async fn send_loader(app: &AppHandle, loader: &str, digest: &str, sig: &str,
             "--testvipimpact",
             "--noprompt",
             "--skip_configure",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         command_util::exec_cmd_with_msg("Send Sig", &app, &config, &cmd).await?;
 
@@ -552,7 +548,7 @@ pub async fn send_loader(app: &AppHandle, loader: &str, digest: &str, sig: &str,
             "--sendxml=res/sha256init.xml",
             "--noprompt",
             "--skip_configure",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         command_util::exec_cmd_with_msg("Send SHA256 init", &app, &config, &cmd).await?;
 
@@ -565,7 +561,7 @@ pub async fn send_loader(app: &AppHandle, loader: &str, digest: &str, sig: &str,
             "--sendxml=res/cfg.xml",
             "--search_path=res",
             "--noprompt",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         command_util::exec_cmd_with_msg("Send Storage Config", &app, &config, &cmd).await?;
     }
@@ -586,7 +582,7 @@ pub async fn send_loader(app: &AppHandle, loader: &str, digest: &str, sig: &str,
             &digest,
             "--testvipimpact",
             "--noprompt",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         command_util::exec_cmd_with_msg("Send Digest", &app, &config, &cmd).await?;
 
@@ -595,7 +591,7 @@ pub async fn send_loader(app: &AppHandle, loader: &str, digest: &str, sig: &str,
             &config.fh_port_conn_str_linux,
             "--sendxml=res/transfercfg.xml",
             "--noprompt",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         command_util::exec_cmd_with_msg("Send Transfer Config", &app, &config, &cmd).await?;
 
@@ -604,7 +600,7 @@ pub async fn send_loader(app: &AppHandle, loader: &str, digest: &str, sig: &str,
             &config.fh_port_conn_str_linux,
             "--sendxml=res/verify.xml",
             "--noprompt",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         command_util::exec_cmd_with_msg("Send Verify", &app, &config, &cmd).await?;
 
@@ -614,7 +610,7 @@ pub async fn send_loader(app: &AppHandle, loader: &str, digest: &str, sig: &str,
             &sig,
             "--testvipimpact",
             "--noprompt",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         command_util::exec_cmd_with_msg("Send Sig", &app, &config, &cmd).await?;
 
@@ -625,17 +621,17 @@ pub async fn send_loader(app: &AppHandle, loader: &str, digest: &str, sig: &str,
             "--memoryname=ufs",
             "--zlpawarehost=1",
             "--noprompt",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
         command_util::exec_cmd_with_msg("Send SHA256 init", &app, &config, &cmd).await?;
     }
     Ok("".to_string())
 }
 
-pub async fn send_nop(app: &AppHandle, config: &Config) {
+pub async fn send_nop(app: &AppHandle, config: &Config) -> Result<String, String> {
     let cmd =
         "<?xml version=\"1.0\" ?><data><nop verbose=\"0\" value=\"ping\"/></data>".to_string();
-    file_util::write_to_file("cmd.xml", "res", &cmd);
+    file_util::write_to_file("cmd.xml", &config.work_dir, &cmd);
     #[cfg(target_os = "windows")]
     {
         let cmd = [
@@ -644,12 +640,12 @@ pub async fn send_nop(app: &AppHandle, config: &Config) {
             &config.fh_loader_path,
             &config.fh_port_conn_str,
             "--memoryname=ufs",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--skip_configure",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
-        let _ = command_util::exec_cmd_with_msg("Send Ping Command", &app, &config, &cmd).await;
+        command_util::exec_cmd_with_msg("Send Ping Command", &app, &config, &cmd).await?;
     }
     #[cfg(target_os = "linux")]
     {
@@ -657,22 +653,23 @@ pub async fn send_nop(app: &AppHandle, config: &Config) {
             &*config.fh_loader_path_linux,
             &*config.fh_port_conn_str_linux,
             "--memoryname=ufs",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--zlpawarehost=1",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
-        let _ = command_util::exec_cmd_with_msg("Send Ping Command", &app, &config, &cmd).await;
+        command_util::exec_cmd_with_msg("Send Ping Command", &app, &config, &cmd).await?;
     }
+    Ok(String::new())
 }
 
-pub async fn switch_slot(app: &AppHandle, slot: &str, config: &Config) -> bool {
+pub async fn switch_slot(app: &AppHandle, slot: &str, config: &Config) -> Result<String, String> {
     let cmd = if slot == "A" {
         "<?xml version=\"1.0\" ?><data><setbootablestoragedrive value=\"1\" /></data>".to_string()
     } else {
         "<?xml version=\"1.0\" ?><data><setbootablestoragedrive value=\"2\" /></data>".to_string()
     };
-    file_util::write_to_file("cmd.xml", "res", &cmd);
+    file_util::write_to_file("cmd.xml", &config.work_dir, &cmd);
     #[cfg(target_os = "windows")]
     {
         let cmd = [
@@ -681,12 +678,12 @@ pub async fn switch_slot(app: &AppHandle, slot: &str, config: &Config) -> bool {
             &config.fh_loader_path,
             &config.fh_port_conn_str,
             "--memoryname=ufs",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--skip_configure",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
-        let _ = command_util::exec_cmd_with_msg("Set active slot", &app, &config, &cmd).await;
+        command_util::exec_cmd_with_msg("Set active slot", &app, &config, &cmd).await?;
     }
     #[cfg(target_os = "linux")]
     {
@@ -694,12 +691,80 @@ pub async fn switch_slot(app: &AppHandle, slot: &str, config: &Config) -> bool {
             &*config.fh_loader_path_linux,
             &*config.fh_port_conn_str_linux,
             "--memoryname=ufs",
-            "--sendxml=res/cmd.xml",
+            &config.sendxml_str,
             "--noprompt",
             "--zlpawarehost=1",
-            "--mainoutputdir=res",
+            &config.output_dir_str,
         ];
-        let _ = command_util::exec_cmd_with_msg("Set active slot", &app, &config, &cmd).await;
+        command_util::exec_cmd_with_msg("Set active slot", &app, &config, &cmd).await?;
+    }
+    Ok(String::new())
+}
+
+/// `FlashBackend` running every operation through fh_loader, blocking on a
+/// runtime of its own so it can be driven from worker threads
+pub struct FhLoaderBackend {
+    app: AppHandle,
+    config: Config,
+    runtime: Runtime,
+}
+
+impl FhLoaderBackend {
+    pub fn new(app: &AppHandle, config: &Config) -> Result<Self, String> {
+        let runtime = Runtime::new().map_err(|e| format!("Failed to start runtime: {}", e))?;
+        Ok(Self {
+            app: app.clone(),
+            config: config.clone(),
+            runtime,
+        })
+    }
+}
+
+impl FlashBackend for FhLoaderBackend {
+    fn erase(&mut self, part: &str, xml: &str) -> Result<String, String> {
+        self.runtime.block_on(erase_part(&self.app, part, xml, &self.config))
+    }
+
+    fn read(&mut self, part: &str, xml: &str, folder: &str) -> Result<String, String> {
+        self.runtime.block_on(read_part(&self.app, part, xml, folder, &self.config))
+    }
+
+    fn program(&mut self, part: &str, xml: &str, dir: &str) -> Result<String, String> {
+        self.runtime.block_on(flash_part(&self.app, part, xml, dir, &self.config))
+    }
+
+    fn patch(&mut self, folder: &str, file: &str) -> Result<String, String> {
+        match self.runtime.block_on(flash_patch_xml(&self.app, folder, file, &self.config)) {
+            true => Ok(String::new()),
+            false => Err(format!("Failed to flash patch: {}", file)),
+        }
+    }
+
+    fn power(&mut self, mode: &FirehoseResetMode) -> Result<String, String> {
+        match mode {
+            FirehoseResetMode::ResetToEdl => self.runtime.block_on(reboot_to_edl(&self.app, &self.config)),
+            FirehoseResetMode::Reset => self.runtime.block_on(reboot_to_system(&self.app, &self.config)),
+            FirehoseResetMode::Off => {
+                let cmd = "<?xml version=\"1.0\" ?><data><power DelayInSeconds=\"0\" value=\"off\" /></data>";
+                return self.runtime.block_on(exec_xml_cmd(&self.app, cmd, &self.config));
+            }
+        }
+        Ok(String::new())
+    }
+
+    fn set_active_slot(&mut self, slot: &str) -> Result<String, String> {
+        self.runtime.block_on(switch_slot(&self.app, slot, &self.config))
+    }
+
+    fn nop(&mut self) -> Result<String, String> {
+        self.runtime.block_on(send_nop(&self.app, &self.config))
+    }
+
+    fn storage_info(&mut self, lun: u8) -> Result<StorageInfo, String> {
+        self.runtime.block_on(read_storage_info(&self.app, lun, &self.config))
+    }
+
+    fn digest(&mut self, lun: u8, start_sector: &str, num_sectors: u64, sector_size: u64) -> Result<[u8; 32], String> {
+        self.runtime.block_on(read_sha256_digest(&self.app, lun, start_sector, num_sectors, sector_size, &self.config))
     }
-    return true;
 }
@@ -0,0 +1,151 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tauri::{AppHandle, Emitter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Finished,
+    Failed,
+    Canceled,
+    Disconnected,
+}
+
+/// Payload of the "update_job_status" event
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub device_id: String,
+    pub state: JobState,
+    pub percentage: usize,
+}
+
+/// Payload of the "job_log_event" event
+#[derive(Debug, Clone, Serialize)]
+pub struct JobLog {
+    pub device_id: String,
+    pub message: String,
+}
+
+/// A flash job running on one device
+pub struct FlashJob {
+    pub device_id: String,
+    app: AppHandle,
+    running: AtomicBool,
+    // set by the hotplug watcher when the device goes away mid-flash
+    disconnected: AtomicBool,
+    percentage: AtomicUsize,
+    thread_handle: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl FlashJob {
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    pub fn cancel(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    pub fn mark_disconnected(&self) {
+        self.disconnected.store(true, Ordering::SeqCst);
+        self.running.store(false, Ordering::SeqCst);
+        self.emit_status(JobState::Disconnected);
+    }
+
+    /// Why the job stopped early, once `is_running()` went false
+    pub fn stop_state(&self) -> JobState {
+        if self.disconnected.load(Ordering::SeqCst) {
+            JobState::Disconnected
+        } else {
+            JobState::Canceled
+        }
+    }
+
+    pub fn stop_reason(&self) -> &'static str {
+        match self.stop_state() {
+            JobState::Disconnected => "Device disconnected",
+            _ => "Operation canceled by user",
+        }
+    }
+
+    /// Log to the job's own stream, and to the shared log tagged with the device
+    pub fn log(&self, message: &str) {
+        let _ = self
+            .app
+            .emit("log_event", format!("[{}] {}", self.device_id, message));
+        let _ = self.app.emit(
+            "job_log_event",
+            JobLog {
+                device_id: self.device_id.clone(),
+                message: message.to_string(),
+            },
+        );
+    }
+
+    pub fn set_percentage(&self, percentage: usize) {
+        self.percentage.store(percentage, Ordering::SeqCst);
+        self.emit_status(JobState::Running);
+    }
+
+    pub fn finish(&self, state: JobState) {
+        self.running.store(false, Ordering::SeqCst);
+        self.emit_status(state);
+    }
+
+    fn emit_status(&self, state: JobState) {
+        let _ = self.app.emit(
+            "update_job_status",
+            JobStatus {
+                device_id: self.device_id.clone(),
+                state,
+                percentage: self.percentage.load(Ordering::SeqCst),
+            },
+        );
+    }
+
+    pub fn set_thread(&self, handle: thread::JoinHandle<()>) {
+        if let Ok(mut thread_handle) = self.thread_handle.lock() {
+            *thread_handle = Some(handle);
+        }
+    }
+}
+
+/// Keeps track of the flash jobs of every device, one job per device at a time
+#[derive(Default)]
+pub struct SessionManager {
+    jobs: Mutex<HashMap<String, Arc<FlashJob>>>,
+    /// Jobs sharing an EDL package must not build its super image concurrently
+    pub super_image_lock: Mutex<()>,
+}
+
+impl SessionManager {
+    /// Register a new job, None if the device already has one running
+    pub fn create_job(&self, app: &AppHandle, device_id: &str) -> Option<Arc<FlashJob>> {
+        let mut jobs = self.jobs.lock().ok()?;
+        if jobs.get(device_id).is_some_and(|job| job.is_running()) {
+            return None;
+        }
+
+        let job = Arc::new(FlashJob {
+            device_id: device_id.to_string(),
+            app: app.clone(),
+            running: AtomicBool::new(true),
+            disconnected: AtomicBool::new(false),
+            percentage: AtomicUsize::new(0),
+            thread_handle: Mutex::new(None),
+        });
+        jobs.insert(device_id.to_string(), job.clone());
+        job.emit_status(JobState::Running);
+        Some(job)
+    }
+
+    /// The running job of a device, if any
+    pub fn running_job(&self, device_id: &str) -> Option<Arc<FlashJob>> {
+        let jobs = self.jobs.lock().ok()?;
+        jobs.get(device_id).filter(|job| job.is_running()).cloned()
+    }
+}
@@ -20,6 +20,72 @@ pub enum GptError {
 
 pub type Result<T> = std::result::Result<T, GptError>;
 
+// Android A/B attributes, in byte 6 of the attributes of the `_a` and `_b`
+// entries, as the Qualcomm bootloaders and boot_control HAL use them
+const AB_FLAG_OFFSET: usize = 48 + 6;
+const AB_PRIORITY_MASK: u8 = 0x03;
+const AB_SLOT_ACTIVE: u8 = 0x04;
+const AB_RETRY_COUNT_MASK: u8 = 0x07 << 3;
+const AB_SUCCESSFUL: u8 = 0x40;
+const AB_UNBOOTABLE: u8 = 0x80;
+
+/// The fields of a GPT header telling where things are
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GptHeader {
+    pub header_size: u32,
+    pub current_lba: u64,
+    pub alternate_lba: u64,
+    pub entries_lba: u64,
+    pub num_entries: u32,
+    pub entry_size: u32,
+}
+
+impl GptHeader {
+    /// Read the header at the start of `sector`
+    pub fn parse(sector: &[u8]) -> Result<Self> {
+        if sector.len() < 92 {
+            return Err(GptError::InsufficientData);
+        }
+        if &sector[..8] != b"EFI PART" {
+            return Err(GptError::InvalidSignature);
+        }
+
+        let mut cursor = Cursor::new(sector);
+        cursor.seek(SeekFrom::Start(12))?;
+        let header_size = cursor.read_u32::<LittleEndian>()?;
+        cursor.seek(SeekFrom::Start(24))?;
+        let current_lba = cursor.read_u64::<LittleEndian>()?;
+        let alternate_lba = cursor.read_u64::<LittleEndian>()?;
+        cursor.seek(SeekFrom::Start(72))?;
+        let entries_lba = cursor.read_u64::<LittleEndian>()?;
+        let num_entries = cursor.read_u32::<LittleEndian>()?;
+        let entry_size = cursor.read_u32::<LittleEndian>()?;
+
+        if !(92..=sector.len() as u32).contains(&header_size) || entry_size < 128 {
+            return Err(GptError::InsufficientData);
+        }
+
+        Ok(Self {
+            header_size,
+            current_lba,
+            alternate_lba,
+            entries_lba,
+            num_entries,
+            entry_size,
+        })
+    }
+
+    /// Size of the partition entry array in bytes
+    pub fn entries_len(&self) -> usize {
+        self.num_entries as usize * self.entry_size as usize
+    }
+
+    /// Sectors taken by the partition entry array
+    pub fn entries_sectors(&self, sector_size: u64) -> u64 {
+        (self.entries_len() as u64).div_ceil(sector_size)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct PartitionEntry {
     pub name: String,
@@ -210,6 +276,59 @@ impl GptParser {
     }
 }
 
+/// Make the entries of `slot` ("a" or "b") the active ones in the GPT made of
+/// `header` and its `entries`, the way fastboot set_active does: the new
+/// slot gets the highest priority, the active bit and a full retry count,
+/// and has to boot successfully again, the other slot loses the active bit
+/// and drops below it. The CRCs of the header are updated to match.
+/// Returns how many entries of `slot` were found.
+pub fn switch_ab_slot(header: &mut [u8], entries: &mut [u8], slot: &str) -> Result<usize> {
+    let gpt = GptHeader::parse(header)?;
+    if entries.len() < gpt.entries_len() {
+        return Err(GptError::EntryOutOfBounds);
+    }
+    let entries = &mut entries[..gpt.entries_len()];
+    let active = format!("_{}", slot.to_lowercase());
+    let inactive = if active == "_a" { "_b" } else { "_a" };
+
+    let mut switched = 0;
+    for entry in entries.chunks_exact_mut(gpt.entry_size as usize) {
+        if entry[..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let name = GptParser::decode_utf16le(&entry[56..128])?;
+        let flags = &mut entry[AB_FLAG_OFFSET];
+        if name.ends_with(&active) {
+            *flags &= !(AB_SUCCESSFUL | AB_UNBOOTABLE);
+            *flags |= AB_PRIORITY_MASK | AB_SLOT_ACTIVE | AB_RETRY_COUNT_MASK;
+            switched += 1;
+        } else if name.ends_with(inactive) {
+            *flags &= !(AB_SLOT_ACTIVE | AB_PRIORITY_MASK);
+            *flags |= AB_PRIORITY_MASK - 1;
+        }
+    }
+
+    header[88..92].copy_from_slice(&crc32(entries).to_le_bytes());
+    header[16..20].fill(0);
+    let header_crc = crc32(&header[..gpt.header_size as usize]);
+    header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+
+    Ok(switched)
+}
+
+/// Plain CRC32 (IEEE), as used for GPT headers and entry arrays
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
 // Implement Display trait for PartitionEntry
 impl std::fmt::Display for PartitionEntry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -223,3 +342,77 @@ impl std::fmt::Display for PartitionEntry {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR_SIZE: usize = 512;
+
+    fn entry(name: &str, flags: u8) -> [u8; 128] {
+        let mut entry = [0u8; 128];
+        entry[..16].fill(0xa5);
+        entry[AB_FLAG_OFFSET] = flags;
+        for (i, c) in name.encode_utf16().enumerate() {
+            entry[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        entry
+    }
+
+    fn gpt(entries: &[[u8; 128]]) -> (Vec<u8>, Vec<u8>) {
+        let mut header = vec![0u8; SECTOR_SIZE];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&1u64.to_le_bytes());
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        (header, entries.concat())
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn switch_updates_crcs() {
+        let (mut header, mut entries) = gpt(&[entry("boot_a", 0x7f), entry("boot_b", 0)]);
+        switch_ab_slot(&mut header, &mut entries, "b").unwrap();
+
+        let entries_crc = u32::from_le_bytes(header[88..92].try_into().unwrap());
+        assert_eq!(entries_crc, crc32(&entries));
+        let header_crc = u32::from_le_bytes(header[16..20].try_into().unwrap());
+        header[16..20].fill(0);
+        assert_eq!(header_crc, crc32(&header[..92]));
+    }
+
+    #[test]
+    fn switch_a_to_b() {
+        // boot_a: active, priority 3, retries left, booted successfully
+        // boot_b: marked unbootable after a failed update
+        let (mut header, mut entries) = gpt(&[
+            entry("boot_a", 0x7f),
+            entry("boot_b", AB_UNBOOTABLE | AB_SUCCESSFUL),
+            entry("xbl", 0x11),
+        ]);
+        let switched = switch_ab_slot(&mut header, &mut entries, "B").unwrap();
+        assert_eq!(switched, 1);
+
+        let flags = |idx: usize| entries[idx * 128 + AB_FLAG_OFFSET];
+        // The new slot: active, top priority, full retry count, neither
+        // successful nor unbootable
+        assert_eq!(flags(1) & AB_SLOT_ACTIVE, AB_SLOT_ACTIVE);
+        assert_eq!(flags(1) & AB_PRIORITY_MASK, 3);
+        assert_eq!(flags(1) & AB_RETRY_COUNT_MASK, AB_RETRY_COUNT_MASK);
+        assert_eq!(flags(1) & (AB_SUCCESSFUL | AB_UNBOOTABLE), 0);
+        // The old slot: inactive, right below, the rest left alone
+        assert_eq!(flags(0) & AB_SLOT_ACTIVE, 0);
+        assert_eq!(flags(0) & AB_PRIORITY_MASK, 2);
+        assert_eq!(flags(0) & AB_RETRY_COUNT_MASK, AB_RETRY_COUNT_MASK);
+        assert_eq!(flags(0) & AB_SUCCESSFUL, AB_SUCCESSFUL);
+        // Unslotted entries aren't touched
+        assert_eq!(flags(2), 0x11);
+    }
+}
@@ -1,117 +1,330 @@
 mod command_util;
+mod device_util;
 mod edl_loader_util;
 mod file_util;
+mod firehose_backend;
 mod firehose_service;
+mod flash_session;
 mod gpt_parser;
 mod qdl;
 mod super_image_creater;
 mod xml_file_util;
 
-use crate::xml_file_util::DataRoot;
-use serialport::{SerialPortType, available_ports};
+use crate::firehose_backend::FlashBackend;
+use crate::qdl::types::StorageInfo;
+use crate::xml_file_util::{DataRoot, UfsProvision};
 use std::env;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::sync::{
-    Arc, Mutex,
-    atomic::{AtomicBool, Ordering},
-};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
-use tauri::{AppHandle, Emitter, Error, State};
-use tokio::runtime::Runtime;
+use tauri::{AppHandle, Emitter, Error, Manager, State};
 
-struct ThreadState {
-    running: AtomicBool,
-    thread_handle: Option<thread::JoinHandle<()>>,
-}
-
-impl Default for ThreadState {
-    fn default() -> Self {
-        ThreadState {
-            running: AtomicBool::new(false),
-            thread_handle: None,
+fn on_hotplug_event(app: &AppHandle, event: device_util::HotplugEvent) {
+    match event {
+        device_util::HotplugEvent::Arrived(device) => {
+            let _ = app.emit("edl_device_arrived", &device);
+        }
+        device_util::HotplugEvent::Removed(device) => {
+            let _ = app.emit("edl_device_removed", &device);
+            if let Ok(mut sessions) = app.state::<RamdumpSessions>().lock() {
+                sessions.remove(&device.id);
+            }
+            drop_native_session(app, &device.id);
+            let sessions = app.state::<Arc<flash_session::SessionManager>>();
+            if let Some(job) = sessions.running_job(&device.id) {
+                job.mark_disconnected();
+                job.log("Device disconnected");
+            }
         }
     }
 }
 
 fn flash_patch_xml(
-    state: &Arc<std::sync::Mutex<ThreadState>>,
-    app: &AppHandle,
+    job: &flash_session::FlashJob,
+    backend: &mut dyn FlashBackend,
     folder: &str,
     files: Vec<String>,
-    config: &command_util::Config,
-    runtime: &Runtime,
 ) -> bool {
     let total = files.len();
     let mut count = 0;
 
     for file in files {
-        if state.lock().unwrap().running.load(Ordering::SeqCst) == false {
-            let _ = app.emit("log_event", "Operation canceled by user");
+        if !job.is_running() {
+            job.log(job.stop_reason());
             return false;
         }
         count += 1;
         thread::sleep(Duration::from_secs(1));
 
-        let result = runtime.block_on(firehose_service::flash_patch_xml(
-            &app, &folder, &file, &config,
-        ));
-        if result == false {
-            let _ = app.emit("log_event", format!("Failed to flash patch: {}", &file));
-            let _ = app.emit("stop_edl_flashing", "");
+        let result = backend.patch(folder, &file);
+        if result.is_err() {
+            job.log(&format!("Failed to flash patch: {}", &file));
             return false;
         } else {
             println!("Flash patch:{} / {}", (count * 15) / total, total);
-            let _ = app.emit("log_event", format!("Flash patch file: {}", &file));
-            let _ = app.emit("update_percentage", 80 + (count * 15) / total);
+            job.log(&format!("Flash patch file: {}", &file));
+            job.set_percentage(80 + (count * 15) / total);
         }
     }
-    return true;
+    true
 }
 
 fn flash_program_xml(
-    state: &Arc<std::sync::Mutex<ThreadState>>,
-    app: &AppHandle,
+    job: &flash_session::FlashJob,
+    backend: &mut dyn FlashBackend,
     folder: &str,
     programs: Vec<(String, String)>,
-    config: &command_util::Config,
-    runtime: &Runtime,
 ) -> bool {
     let total = programs.len();
     let mut count = 0;
 
     for (label, program) in programs {
-        if state.lock().unwrap().running.load(Ordering::SeqCst) == false {
-            let _ = app.emit("log_event", "Operation canceled by user");
+        if !job.is_running() {
+            job.log(job.stop_reason());
             return false;
         }
         count += 1;
         thread::sleep(Duration::from_secs(1));
 
-        let result = runtime.block_on(
-            firehose_service::flash_part(&app, &label, &program, &folder, &config,
-        ));
+        let result = backend.program(&label, &program, folder);
         match result {
             Ok(_output) => {
                 println!("Flash program:{} / {}", (count * 60) / total, total);
-                let _ = app.emit("log_event", format!("Flash partition: {}", label));
-                let _ = app.emit("update_percentage", 20 + (count * 60) / total);
+                job.log(&format!("Flash partition: {}", label));
+                job.set_percentage(20 + (count * 60) / total);
             },
             Err(_e) => {
-                let _ = app.emit("log_event", format!("Failed to flash partition: {}", label));
-                let _ = app.emit("stop_edl_flashing", "");
+                job.log(&format!("Failed to flash partition: {}", label));
                 return false;
             },
         };
     }
-    return true;
+    true
+}
+
+fn run_flash_job(
+    job: &flash_session::FlashJob,
+    sessions: &flash_session::SessionManager,
+    app: &AppHandle,
+    path: &str,
+    is_protect_lun5: bool,
+    is_debug: bool,
+) -> flash_session::JobState {
+    job.set_percentage(5);
+    let package = match file_util::check_necessary_files_in_edl_folder(path, is_protect_lun5) {
+        Ok(package) if !package.is_miss_file => package,
+        _ => {
+            job.log("Check necessary files...Error");
+            return flash_session::JobState::Failed;
+        }
+    };
+    job.log("Check necessary files...OK");
+    job.set_percentage(10);
+    job.log("Merging Super image...");
+    let merged = {
+        let _guard = sessions.super_image_lock.lock();
+        super_image_creater::creat_super_image(&package.super_define)
+    };
+    if !merged {
+        job.log("Failed to create Super image.");
+        return flash_session::JobState::Failed;
+    }
+    if !job.is_running() {
+        job.log(job.stop_reason());
+        return job.stop_state();
+    }
+    job.log("Merge Super image...OK");
+    job.set_percentage(20);
+    let mut config = command_util::Config::setup_env(&job.device_id, is_debug);
+    if !device_reachable(app, &job.device_id, &config) {
+        job.log("port not available");
+        return flash_session::JobState::Failed;
+    }
+    config.log_prefix = format!("[{}] ", job.device_id);
+    let result = with_backend(app, &job.device_id, &config, |backend| {
+        Ok(flash_package(job, backend, package))
+    });
+    match result {
+        Ok(state) => state,
+        Err(e) => {
+            job.log(&e);
+            flash_session::JobState::Failed
+        }
+    }
+}
+
+fn flash_package(
+    job: &flash_session::FlashJob,
+    backend: &mut dyn FlashBackend,
+    package: file_util::EdlPackage,
+) -> flash_session::JobState {
+    let (_file_name, dir_path) = file_util::parse_file_path("", &package.patch_files[0]);
+    if !flash_program_xml(job, backend, &dir_path, package.raw_programs) {
+        return match job.is_running() {
+            true => flash_session::JobState::Failed,
+            false => job.stop_state(),
+        };
+    }
+    job.set_percentage(80);
+    if !flash_patch_xml(job, backend, &dir_path, package.patch_files) {
+        return match job.is_running() {
+            true => flash_session::JobState::Failed,
+            false => job.stop_state(),
+        };
+    }
+    job.set_percentage(95);
+    if backend.set_active_slot("A").is_err() {
+        return flash_session::JobState::Failed;
+    }
+    job.set_percentage(100);
+    flash_session::JobState::Finished
+}
+
+/// Devices that got their loader through the native backend, the session
+/// stays open for the Firehose operations that follow
+type FirehoseSessions = Mutex<HashMap<String, Arc<Mutex<firehose_backend::NativeBackend>>>>;
+
+fn has_native_session(app: &AppHandle, device_id: &str) -> bool {
+    match app.state::<FirehoseSessions>().lock() {
+        Ok(sessions) => sessions.contains_key(device_id),
+        Err(_e) => false,
+    }
+}
+
+fn drop_native_session(app: &AppHandle, device_id: &str) {
+    if let Ok(mut sessions) = app.state::<FirehoseSessions>().lock() {
+        sessions.remove(device_id);
+    }
+}
+
+/// Firehose commands whose informational \<log\> messages make it to the
+/// log panel, as ticked in the settings panel
+type FirehoseLogOps = Mutex<HashSet<String>>;
+
+/// Firehose timeouts of the native sessions, as set in the settings panel
+#[derive(Clone, Copy)]
+struct FirehoseTimeouts {
+    read: Duration,
+    write: Duration,
+    per_mib: Duration,
+}
+
+impl Default for FirehoseTimeouts {
+    fn default() -> Self {
+        let config = qdl::types::FirehoseConfiguration::default();
+        Self {
+            read: config.read_timeout,
+            write: config.write_timeout,
+            per_mib: config.xfer_timeout_per_mib,
+        }
+    }
+}
+
+/// Chained VIP digest table size of the native sessions, as set in the
+/// settings panel
+struct DigestTableSize(AtomicUsize);
+
+impl Default for DigestTableSize {
+    fn default() -> Self {
+        Self(AtomicUsize::new(qdl::firehose::FIREHOSE_DEFAULT_DIGEST_TABLE_SIZE))
+    }
+}
+
+/// Whether native sessions are recorded for replay, as ticked in the
+/// settings panel. Off unless asked for, a flash records every byte.
+#[derive(Default)]
+struct RecordSessions(AtomicBool);
+
+/// Recordings kept per device, the oldest go first
+const CAPTURES_KEPT: usize = 5;
+
+/// Payload of the "log_event"s carrying a \<log\> message of the loader,
+/// the other ones are plain strings
+#[derive(Clone, serde::Serialize)]
+struct FirehoseLogEvent {
+    device_id: String,
+    #[serde(flatten)]
+    log: qdl::types::FirehoseLog,
+}
+
+/// Emit the loader's \<log\> messages as "log_event"s, for the commands
+/// picked in the settings panel
+fn firehose_log_sink(app: &AppHandle, device_id: &str) -> qdl::types::FirehoseLogSink {
+    let app = app.clone();
+    let device_id = device_id.to_string();
+    Box::new(move |log| {
+        // Errors and warnings always make it, the ticked ops only pick the chatter
+        let enabled = match log.severity {
+            qdl::types::FirehoseLogSeverity::Info => match app.state::<FirehoseLogOps>().lock() {
+                Ok(ops) => ops.contains(&log.op),
+                Err(_e) => false,
+            },
+            _ => true,
+        };
+        if enabled {
+            let event = FirehoseLogEvent {
+                device_id: device_id.clone(),
+                log: log.clone(),
+            };
+            let _ = app.emit("log_event", event);
+        }
+    })
+}
+
+/// fh_loader needs the serial port, a native session brings its own channel
+fn device_reachable(app: &AppHandle, device_id: &str, config: &command_util::Config) -> bool {
+    config.is_connect || has_native_session(app, device_id)
+}
+
+/// Run `op` on the device's native Firehose session if the loader was sent
+/// natively, through fh_loader otherwise. Blocks until the device is done.
+fn with_backend<R>(
+    app: &AppHandle,
+    device_id: &str,
+    config: &command_util::Config,
+    op: impl FnOnce(&mut dyn FlashBackend) -> Result<R, String>,
+) -> Result<R, String> {
+    let session = match app.state::<FirehoseSessions>().lock() {
+        Ok(sessions) => sessions.get(device_id).cloned(),
+        Err(_e) => None,
+    };
+    match session {
+        Some(session) => {
+            // One operation at a time per device, like fh_loader holding the port
+            let mut backend = session.lock().map_err(|e| e.to_string())?;
+            backend.set_log_prefix(&config.log_prefix);
+            op(&mut *backend)
+        }
+        None => {
+            let mut backend = firehose_service::FhLoaderBackend::new(app, config)?;
+            op(&mut backend)
+        }
+    }
+}
+
+/// `with_backend` for the async commands, off the async runtime since both
+/// backends block
+async fn on_backend<R: Send + 'static>(
+    app: &AppHandle,
+    device_id: &str,
+    config: &command_util::Config,
+    op: impl FnOnce(&mut dyn FlashBackend) -> Result<R, String> + Send + 'static,
+) -> Result<R, String> {
+    let (app, device_id, config) = (app.clone(), device_id.to_string(), config.clone());
+    match tauri::async_runtime::spawn_blocking(move || with_backend(&app, &device_id, &config, op)).await {
+        Ok(result) => result,
+        Err(e) => Err(e.to_string()),
+    }
 }
 
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
-async fn erase_part(app: AppHandle, xml: &str, is_debug: bool) -> Result<(), Error> {
-    let config = command_util::Config::setup_env(is_debug);
-    if config.is_connect == false {
+async fn erase_part(app: AppHandle, xml: &str, device_id: String, is_debug: bool) -> Result<(), Error> {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    if !device_reachable(&app, &device_id, &config) {
         let _ = app.emit("log_event", "port not available");
         return Err(tauri::Error::AssetNotFound(
             "port not available".to_string(),
@@ -120,16 +333,27 @@ async fn erase_part(app: AppHandle, xml: &str, is_debug: bool) -> Result<(), Err
     let _ = app.emit("update_command_running_status", true);
     // Call the parsing function
     let items = xml_file_util::parser_erase_xml(xml);
-    for (part, xml_content) in items {
-        if config.is_connect == false {
-            return Err(tauri::Error::AssetNotFound(
-                "port not available".to_string(),
-            ));
+    let result = on_backend(&app, &device_id, &config, move |backend| {
+        // Go on with the others, then tell which ones failed
+        let mut failed = Vec::new();
+        for (part, xml_content) in items {
+            if let Err(e) = backend.erase(&part, &xml_content) {
+                failed.push(format!("{} ({})", part, e.trim()));
+            }
         }
-        firehose_service::erase_part(&app, &part, &xml_content, &config).await;
-    }
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(format!("Failed to erase {}", failed.join(", "))),
+        }
+    }).await;
     let _ = app.emit("update_command_running_status", false);
-    Ok(())
+    result.map_err(|e| command_failed(&app, e))
+}
+
+/// Log why a command failed and hand the error back to the frontend
+fn command_failed(app: &AppHandle, e: String) -> Error {
+    let _ = app.emit("log_event", &e);
+    tauri::Error::Anyhow(anyhow::anyhow!(e))
 }
 
 #[tauri::command]
@@ -149,28 +373,143 @@ async fn identify_loader(app: AppHandle, path: String) {
     }
 }
 
+/// The storage info of every LUN, LUN 0 telling how many there are
+fn read_all_storage_info(backend: &mut dyn FlashBackend) -> Result<Vec<StorageInfo>, String> {
+    let mut infos = vec![backend.storage_info(0)?];
+    for lun in 1..infos[0].num_physical {
+        infos.push(backend.storage_info(lun)?);
+    }
+    Ok(infos)
+}
+
+/// LUN and sector size of the LUNs to look for partitions on. Falls back to
+/// the usual UFS layout if the loader didn't report any.
+fn storage_layout(app: &AppHandle, infos: Result<Vec<StorageInfo>, String>) -> Vec<(u8, u64)> {
+    match infos {
+        Ok(infos) if infos[0].num_physical > 0 => infos
+            .iter()
+            .map(|info| match info.block_size {
+                0 => (info.physical_partition, 4096),
+                block_size => (info.physical_partition, block_size as u64),
+            })
+            .collect(),
+        _ => {
+            let _ = app.emit("log_event", "No storage info, assuming 6 LUNs of 4096 bytes sectors");
+            (0..6).map(|lun| (lun, 4096)).collect()
+        }
+    }
+}
+
+fn format_storage_info(infos: &[StorageInfo]) -> String {
+    let mut output = String::new();
+    let Some(info) = infos.first() else {
+        return output;
+    };
+    output = format!("{}\n Device Total Physical Partitions:{}", output, info.num_physical);
+    output = format!("{}\n Device Serial Number:0x{:08x}", output, info.serial_num);
+    let model = [&info.manufacturer, &info.product, &info.firmware_revision]
+        .iter()
+        .filter(|s| !s.is_empty())
+        .map(|s| s.as_str())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if !model.is_empty() {
+        output = format!("{}\n Storage:{}", output, model);
+    }
+    if let Some(boot_lun) = info.boot_lun_enabled {
+        let slot = if boot_lun == 1 { "A" } else { "B" };
+        output = format!("{}\n Active slot:{}", output, slot);
+    }
+    for info in infos {
+        output = format!(
+            "{}\n LUN {}:{} MB ({} x {} bytes)",
+            output,
+            info.physical_partition,
+            info.size_in_bytes() / 1024 / 1024,
+            info.total_blocks,
+            info.block_size
+        );
+    }
+    output
+}
+
+fn format_ufs_provision(provision: &UfsProvision) -> String {
+    let common = &provision.common;
+    let mut output = format!(" UFS LUNs:{}", common.number_lu);
+    let boot = if common.boot_enable != 0 { "enabled" } else { "disabled" };
+    output = format!("{}\n Boot LUNs:{}", output, boot);
+    if let Some(size) = common.shared_wb_buffer_size_in_kb {
+        output = format!("{}\n Write booster buffer:{} MB", output, size / 1024);
+    }
+    for lun in &provision.luns {
+        if lun.enable == 0 {
+            output = format!("{}\n LUN {}:disabled", output, lun.lun);
+            continue;
+        }
+        let size = if provision.lun_to_grow == Some(lun.lun) {
+            "rest of the device".to_string()
+        } else if lun.size_in_kb < 1024 {
+            format!("{} KB", lun.size_in_kb)
+        } else {
+            format!("{} MB", lun.size_in_kb / 1024)
+        };
+        let memory = match lun.memory_type {
+            0 => "normal".to_string(),
+            1 => "system code".to_string(),
+            2 => "non-persistent".to_string(),
+            n => format!("enhanced {}", n - 2),
+        };
+        output = format!(
+            "{}\n LUN {}:{}, {} memory, {} bytes blocks",
+            output,
+            lun.lun,
+            size,
+            memory,
+            1u64 << lun.logical_block_size.min(63)
+        );
+        match lun.boot_lun_id {
+            0 => {}
+            1 => output = format!("{}, boot LUN A", output),
+            2 => output = format!("{}, boot LUN B", output),
+            n => output = format!("{}, boot LUN {}", output, n),
+        }
+        if lun.write_protect != 0 {
+            output = format!("{}, write protected", output);
+        }
+        if !lun.desc.is_empty() {
+            output = format!("{} ({})", output, lun.desc);
+        }
+    }
+    if common.config_descr_lock != 0 {
+        output = format!("{}\n bConfigDescrLock is set, the layout can never be changed once committed", output);
+    }
+    let commit = if provision.commit { "yes" } else { "no" };
+    output = format!("{}\n Commit asked for by the XML:{}", output, commit);
+    output
+}
+
 #[tauri::command]
-async fn read_device_info(app: AppHandle, is_debug: bool) -> String {
-    let config = command_util::Config::setup_env(is_debug);
-    if config.is_connect == false {
+async fn read_device_info(app: AppHandle, device_id: String, is_debug: bool) -> String {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    if !device_reachable(&app, &device_id, &config) {
         return "Device not found".to_string();
     }
 
     let _ = app.emit("update_command_running_status", true);
-    let output = match firehose_service::read_storage_info(&app, &config).await {
-        Ok(result) => result,
+    let output = match on_backend(&app, &device_id, &config, read_all_storage_info).await {
+        Ok(infos) => format_storage_info(&infos),
         Err(_e) => "".to_string(),
     };
     let _ = app.emit("update_command_running_status", false);
-    return file_util::analysis_info(&output);
+    output
 }
 
 #[tauri::command]
-async fn read_gpt(app: AppHandle, is_debug: bool) {
-    let config = command_util::Config::setup_env(is_debug);
-    if config.is_connect == false {
+async fn read_gpt(app: AppHandle, device_id: String, is_debug: bool) {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    if !device_reachable(&app, &device_id, &config) {
         let _ = app.emit("log_event", "port not available");
-        return ();
+        return;
     }
 
     let _ = app.emit("update_command_running_status", true);
@@ -178,40 +517,53 @@ async fn read_gpt(app: AppHandle, is_debug: bool) {
         programs: Vec::new(),
         read_tags: Vec::new(),
         erase_tags: Vec::new(),
+        patches: Vec::new(),
     };
-    for i in 0..6 {
+    let infos = on_backend(&app, &device_id, &config, read_all_storage_info).await;
+    for (i, sector_size) in storage_layout(&app, infos) {
+        // Protective MBR, GPT header and the 16 KiB of partition entries
         let read_tag = xml_file_util::create_read_tag_dynamic(
             &format!("gpt_main{}.bin", i),
             i,
             0,
-            6,
+            2 + 16384 / sector_size,
+            sector_size,
             "PrimaryGPT",
         );
 
         let read_xml = xml_file_util::to_xml(&read_tag);
         let xml_content = format!("<?xml version=\"1.0\" ?>\n<data>\n{}\n</data>\n", read_xml);
-        let _ = firehose_service::read_part(&app, &format!("LUN {}", i), &xml_content, "img", &config).await;
+        let read = on_backend(&app, &device_id, &config, move |backend| {
+            backend.read(&format!("LUN {}", i), &xml_content, "img")
+        }).await;
+        // A LUN that can't be read is reported, the others are still listed
+        if let Err(e) = read {
+            let _ = app.emit("log_event", &format!("Failed to read the GPT of LUN {}: {}", i, e));
+            continue;
+        }
 
         //parser gpt
         let file_path = format!("img/gpt_main{}.bin", i).to_string();
         let mut parser = gpt_parser::GptParser::new();
-        if file_util::check_file_exist(&file_path) == false {
-            println!("error");
-            return;
-        } else {
-            match parser.parse_file(file_path, 4096) {
-                Ok(_) => {
-                    for (_i, partition) in parser.partitions().iter().enumerate() {
-                        let program = xml_file_util::create_program_dynamic(
-                            i,
-                            partition.first_lba,
-                            partition.size_in_sectors(),
-                            &partition.name,
-                        );
-                        root.programs.push(program);
-                    }
+        if !file_util::check_file_exist(&file_path) {
+            let _ = app.emit("log_event", &format!("The GPT of LUN {} is missing: {}", i, file_path));
+            continue;
+        }
+        match parser.parse_file(file_path, sector_size as u32) {
+            Ok(_) => {
+                for partition in parser.partitions() {
+                    let program = xml_file_util::create_program_dynamic(
+                        i,
+                        partition.first_lba,
+                        partition.size_in_sectors(),
+                        sector_size,
+                        &partition.name,
+                    );
+                    root.programs.push(program);
                 }
-                Err(_e) => {}
+            }
+            Err(e) => {
+                let _ = app.emit("log_event", &format!("Failed to parse the GPT of LUN {}: {}", i, e));
             }
         }
     }
@@ -222,10 +574,10 @@ async fn read_gpt(app: AppHandle, is_debug: bool) {
 }
 
 #[tauri::command]
-async fn read_part(app: AppHandle, xml: &str, folder: &str, is_debug: bool) -> Result<(), Error> {
+async fn read_part(app: AppHandle, xml: &str, folder: &str, device_id: String, is_debug: bool) -> Result<(), Error> {
     // Call the parsing function
-    let config = command_util::Config::setup_env(is_debug);
-    if config.is_connect == false {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    if !device_reachable(&app, &device_id, &config) {
         let _ = app.emit("log_event", "Device not found");
         return Err(tauri::Error::AssetNotFound(
             "port not available".to_string(),
@@ -233,66 +585,95 @@ async fn read_part(app: AppHandle, xml: &str, folder: &str, is_debug: bool) -> R
     }
     let _ = app.emit("update_command_running_status", true);
     let items = xml_file_util::parser_read_xml(xml);
-    for (part, xml_content) in items {
-        if config.is_connect == false {
-            return Err(tauri::Error::AssetNotFound(
-                "port not available".to_string(),
-            ));
+    let folder = folder.to_string();
+    let result = on_backend(&app, &device_id, &config, move |backend| {
+        // Go on with the others, then tell which ones failed
+        let mut failed = Vec::new();
+        for (part, xml_content) in items {
+            if let Err(e) = backend.read(&part, &xml_content, &folder) {
+                failed.push(format!("{} ({})", part, e.trim()));
+            }
         }
-
-        let _ = firehose_service::read_part(&app, &part, &xml_content, &folder, &config).await;
-    }
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(format!("Failed to read {}", failed.join(", "))),
+        }
+    }).await;
     let _ = app.emit("update_command_running_status", false);
-    Ok(())
+    result.map_err(|e| command_failed(&app, e))
 }
 
 #[tauri::command]
-async fn reboot_to_edl(app: AppHandle, is_debug: bool) {
-    let config = command_util::Config::setup_env(is_debug);
-    if config.is_connect == false {
+async fn reboot_to_edl(app: AppHandle, device_id: String, is_debug: bool) {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    if !device_reachable(&app, &device_id, &config) {
         let _ = app.emit("log_event", "port not available");
         return ();
     }
-    firehose_service::reboot_to_edl(&app, &config).await;
+    let _ = on_backend(&app, &device_id, &config, |backend| {
+        backend.power(&qdl::types::FirehoseResetMode::ResetToEdl)
+    }).await;
+    drop_native_session(&app, &device_id);
     let _ = app.emit("update_loader_status", false);
 }
 
 #[tauri::command]
-async fn reboot_to_fastboot(app: AppHandle, xml: &str, is_debug: bool) -> Result<(), Error> {
-    let config = command_util::Config::setup_env(is_debug);
-    if config.is_connect == false {
+async fn reboot_to_fastboot(app: AppHandle, xml: &str, device_id: String, is_debug: bool) -> Result<(), Error> {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    if !device_reachable(&app, &device_id, &config) {
         let _ = app.emit("log_event", "port not available");
         return Err(tauri::Error::AssetNotFound(
             "port not available".to_string(),
         ));
     }
-    firehose_service::reboot_to(&app, "Reboot to fastbootD", &xml, &config).await;
+    let _ = app.emit("log_event", "Reboot to fastbootD");
+    reboot_with_misc(&app, &device_id, xml, &config).await;
     let _ = app.emit("update_loader_status", false);
     Ok(())
 }
 
+/// Write the boot command into misc, then reset into it
+async fn reboot_with_misc(app: &AppHandle, device_id: &str, xml: &str, config: &command_util::Config) {
+    let xml = xml.to_string();
+    let result = on_backend(app, device_id, config, move |backend| {
+        // Rebooting without the boot command would land in the OS instead
+        backend.program("misc", &xml, "res")?;
+        backend.power(&qdl::types::FirehoseResetMode::Reset)
+    }).await;
+    match result {
+        Ok(_) => drop_native_session(app, device_id),
+        Err(e) => {
+            let _ = app.emit("log_event", &format!("Failed to write misc: {}", e));
+        }
+    }
+}
+
 #[tauri::command]
-async fn reboot_to_recovery(app: AppHandle, xml: &str, is_debug: bool) -> Result<(), Error> {
-    let config = command_util::Config::setup_env(is_debug);
-    if config.is_connect == false {
+async fn reboot_to_recovery(app: AppHandle, xml: &str, device_id: String, is_debug: bool) -> Result<(), Error> {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    if !device_reachable(&app, &device_id, &config) {
         let _ = app.emit("log_event", "port not available");
         return Err(tauri::Error::AssetNotFound(
             "port not available".to_string(),
         ));
     }
-    firehose_service::reboot_to(&app, "Reboot to recovery", &xml, &config).await;
+    let _ = app.emit("log_event", "Reboot to recovery");
+    reboot_with_misc(&app, &device_id, xml, &config).await;
     let _ = app.emit("update_loader_status", false);
     Ok(())
 }
 
 #[tauri::command]
-async fn reboot_to_system(app: AppHandle, is_debug: bool) {
-    let config = command_util::Config::setup_env(is_debug);
-    if config.is_connect == false {
+async fn reboot_to_system(app: AppHandle, device_id: String, is_debug: bool) {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    if !device_reachable(&app, &device_id, &config) {
         let _ = app.emit("log_event", "port not available");
         return ();
     }
-    firehose_service::reboot_to_system(&app, &config).await;
+    let _ = on_backend(&app, &device_id, &config, |backend| {
+        backend.power(&qdl::types::FirehoseResetMode::Reset)
+    }).await;
+    drop_native_session(&app, &device_id);
     let _ = app.emit("update_loader_status", false);
 }
 
@@ -305,10 +686,11 @@ async fn run_command(
     digest: String,
     sig: String,
     content: String, 
+    device_id: String,
     is_debug: bool) -> String {
     let mut result = String::new();
-    let config = command_util::Config::setup_env(is_debug);
-    if config.is_connect == false {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    if !device_reachable(&app, &device_id, &config) {
         let _ = app.emit("log_event", "port not available");
         return result;
     }
@@ -395,9 +777,13 @@ async fn run_command(
             output = command_util::exec_cmd_with_msg("Send Sig", &app, &config, &cmd).await;
         }
     } else if cmd_type == "read" {
-        output = firehose_service::read_part(&app, "", &content, &path, &config).await;
+        output = on_backend(&app, &device_id, &config, move |backend| {
+            backend.read("", &content, &path)
+        }).await;
     } else if cmd_type == "program" {
-        output = firehose_service::flash_part(&app, "", &content, &path, &config).await;
+        output = on_backend(&app, &device_id, &config, move |backend| {
+            backend.program("", &content, &path)
+        }).await;
     } else {
         output = firehose_service::exec_xml_cmd(&app, &content, &config).await;
     }
@@ -428,22 +814,66 @@ async fn send_loader(
     digest: String,
     sig: String,
     native: bool,
+    device_id: String,
     is_debug: bool,
 ) -> String {
-    let config = command_util::Config::setup_env(is_debug);
-    if config.is_connect == false {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    // The native USB backend talks to the device directly, no serial port needed
+    let backend = native_backend();
+    let needs_port = !native || backend == qdl::types::QdlBackend::Serial;
+    if needs_port && !config.is_connect {
         let _ = app.emit("log_event", "port not available");
         return format!("port not available");
     }
     if native {
-        let mut client = match qdl::SaharaClient::new(Some(config.port_path.clone())) {
+        let dev_path = native_dev_path(backend, &config, &device_id);
+        // Recorded on request, so that a failure can be replayed later
+        let capture_path = match app.state::<RecordSessions>().0.load(Ordering::Relaxed) {
+            true => new_capture_path(&config.work_dir),
+            false => None,
+        };
+        if let Some(path) = &capture_path {
+            let _ = app.emit("log_event", &format!("Recording session to {}", path));
+        }
+        let mut client = match qdl::SaharaClient::new(backend, dev_path, capture_path) {
             Ok(client) => client,
             Err(_e) => return format!("Sahara connect error: {}", _e),
         };
-        let _ = app.emit("log_event", &format!("Chip serial number: {}", client.get_chip_sn()));
-        let _ = app.emit("log_event", &format!("OEM Key hash: {}", client.get_oem_key_hash()));
-        client.send_loader(&loader);
+        emit_sahara_info(&app, &device_id, &client);
+        client.set_log_sink(firehose_log_sink(&app, &device_id));
+        let timeouts = match app.state::<Mutex<FirehoseTimeouts>>().lock() {
+            Ok(timeouts) => *timeouts,
+            Err(_e) => FirehoseTimeouts::default(),
+        };
+        if let Err(e) = client.set_timeouts(timeouts.read, timeouts.write, timeouts.per_mib) {
+            let _ = app.emit("log_event", &e);
+            return e;
+        }
+        client.set_digest_table_size(app.state::<DigestTableSize>().0.load(Ordering::Relaxed));
+        // Targets that ask for more than the programmer take "13:prog.elf;21:devcfg.mbn"
+        let images = match qdl::parse_sahara_images(&loader) {
+            Ok(images) => images,
+            Err(e) => {
+                let _ = app.emit("log_event", &e);
+                return e;
+            }
+        };
+        // Authenticated loaders want the VIP digest tables before anything else
+        let sent = match digest.is_empty() {
+            true => client.send_images(&images),
+            false => client.send_images_vip(&images, &digest, &sig),
+        };
+        if let Err(e) = sent {
+            let _ = app.emit("log_event", &e);
+            return e;
+        }
+        let backend = firehose_backend::NativeBackend::new(&app, client);
+        if let Ok(mut sessions) = app.state::<FirehoseSessions>().lock() {
+            sessions.insert(device_id.clone(), Arc::new(Mutex::new(backend)));
+        }
+        let _ = app.emit("update_loader_status", true);
     } else {
+        drop_native_session(&app, &device_id);
         let loader_str = r"13:".to_owned() + &loader;
         let digest_str = r"--signeddigests=".to_owned() + &digest;
         let sig_str = r"--signeddigests=".to_owned() + &sig;
@@ -455,13 +885,403 @@ async fn send_loader(
 }
 
 #[tauri::command]
-async fn send_ping(app: AppHandle, is_debug: bool) {
-    let config = command_util::Config::setup_env(is_debug);
-    if config.is_connect == false {
+fn set_firehose_log_ops(app: AppHandle, ops: Vec<String>) {
+    if let Ok(mut log_ops) = app.state::<FirehoseLogOps>().lock() {
+        *log_ops = ops.into_iter().collect();
+    }
+}
+
+/// Chained VIP digest table size of the sessions started from now on, in bytes
+#[tauri::command]
+fn set_digest_table_size(app: AppHandle, bytes: usize) {
+    app.state::<DigestTableSize>().0.store(bytes, Ordering::Relaxed);
+}
+
+/// Record the native sessions started from now on, for `replay_capture`
+#[tauri::command]
+fn set_record_sessions(app: AppHandle, enabled: bool) {
+    app.state::<RecordSessions>().0.store(enabled, Ordering::Relaxed);
+}
+
+/// Timeouts of the Firehose sessions started from now on, in milliseconds
+#[tauri::command]
+fn set_firehose_timeouts(app: AppHandle, read_ms: u64, write_ms: u64, per_mib_ms: u64) {
+    if let Ok(mut timeouts) = app.state::<Mutex<FirehoseTimeouts>>().lock() {
+        *timeouts = FirehoseTimeouts {
+            read: Duration::from_millis(read_ms),
+            write: Duration::from_millis(write_ms),
+            per_mib: Duration::from_millis(per_mib_ms),
+        };
+    }
+}
+
+/// Payload of the "update_device_info" event, what the PBL tells over Sahara
+#[derive(Clone, serde::Serialize)]
+struct SaharaInfo {
+    device_id: String,
+    serial_num: String,
+    oem_key_hash: String,
+    hw_id: String,
+    msm_id: String,
+    oem_id: String,
+    model_id: String,
+    chipset: String,
+}
+
+fn emit_sahara_info(app: &AppHandle, device_id: &str, client: &qdl::SaharaClient) -> SaharaInfo {
+    let mut info = SaharaInfo {
+        device_id: device_id.to_string(),
+        serial_num: client.get_chip_sn(),
+        oem_key_hash: client.get_oem_key_hash(),
+        hw_id: String::new(),
+        msm_id: String::new(),
+        oem_id: String::new(),
+        model_id: String::new(),
+        chipset: String::new(),
+    };
+    let _ = app.emit("log_event", &format!("Chip serial number: {}", info.serial_num));
+    if let Some(hw_id) = client.get_hw_id() {
+        info.hw_id = format!("0x{:016x}", hw_id.0);
+        info.msm_id = format!("0x{:06x}", hw_id.msm_id());
+        info.oem_id = format!("0x{:04x}", hw_id.oem_id());
+        info.model_id = format!("0x{:04x}", hw_id.model_id());
+        info.chipset = match edl_loader_util::chipset_by_msm_id(hw_id.msm_id()) {
+            Some(chipset) => format!("{} ({})", chipset.name, chipset.marketing_name),
+            None => "Unknown".to_string(),
+        };
+        let _ = app.emit("log_event", &format!("HW ID: {} (MSM ID: {}, OEM ID: {}, Model ID: {})", info.hw_id, info.msm_id, info.oem_id, info.model_id));
+        let _ = app.emit("log_event", &format!("Chipset: {}", info.chipset));
+    }
+    let _ = app.emit("log_event", &format!("OEM Key hash: {}", info.oem_key_hash));
+    let _ = app.emit("update_device_info", &info);
+    info
+}
+
+#[tauri::command]
+async fn read_sahara_info(app: AppHandle, device_id: String, is_debug: bool) -> Result<SaharaInfo, String> {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    let backend = native_backend();
+    if backend == qdl::types::QdlBackend::Serial && !config.is_connect {
+        let _ = app.emit("log_event", "port not available");
+        return Err("port not available".to_string());
+    }
+
+    let dev_path = native_dev_path(backend, &config, &device_id);
+    let connected = tauri::async_runtime::spawn_blocking(move || qdl::SaharaClient::new(backend, dev_path, None)).await;
+    let client = match connected {
+        Ok(Ok(client)) => client,
+        Ok(Err(e)) => {
+            let _ = app.emit("log_event", &format!("Sahara connect error: {}", e));
+            return Err(e);
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+    Ok(emit_sahara_info(&app, &device_id, &client))
+}
+
+/// Transport of the native backend, QDL_BACKEND=sim runs against the simulator
+fn native_backend() -> qdl::types::QdlBackend {
+    match env::var("QDL_BACKEND") {
+        Ok(name) => name.parse().unwrap_or_default(),
+        Err(_e) => qdl::types::QdlBackend::default(),
+    }
+}
+
+/// What the native backend needs to find the device, see `qdl::SaharaClient::new`
+fn native_dev_path(backend: qdl::types::QdlBackend, config: &command_util::Config, device_id: &str) -> Option<String> {
+    match backend {
+        qdl::types::QdlBackend::Serial => Some(config.port_path.clone()),
+        qdl::types::QdlBackend::Usb => Some(device_id.to_string()),
+        // The simulated device works on the lunN.img files of the device's work dir
+        qdl::types::QdlBackend::Sim => Some(format!("{}/sim", config.work_dir)),
+        qdl::types::QdlBackend::Replay => None,
+    }
+}
+
+/// A new recording under the device's work dir, making room for it among
+/// the last `CAPTURES_KEPT` ones
+fn new_capture_path(work_dir: &str) -> Option<String> {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let dir = format!("{}/captures", work_dir);
+    if fs::create_dir_all(&dir).is_err() {
+        return None;
+    }
+    // The timestamps in the names sort the same as the recordings
+    let mut captures: Vec<_> = fs::read_dir(&dir)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "qdlcap"))
+        .collect();
+    captures.sort();
+    let excess = (captures.len() + 1).saturating_sub(CAPTURES_KEPT);
+    for path in &captures[..excess] {
+        let _ = fs::remove_file(path);
+    }
+    Some(format!("{}/qdl-{}.qdlcap", dir, timestamp))
+}
+
+#[tauri::command]
+async fn replay_capture(app: AppHandle, capture: String, loader: String) -> String {
+    let mut client = match qdl::SaharaClient::new(qdl::types::QdlBackend::Replay, Some(capture), None) {
+        Ok(client) => client,
+        Err(_e) => return format!("Sahara connect error: {}", _e),
+    };
+    emit_sahara_info(&app, "replay", &client);
+    if let Err(e) = client.send_loader(&loader) {
+        let _ = app.emit("log_event", &e);
+        return e;
+    }
+    let _ = app.emit("log_event", "Replay finished");
+    "OK".to_string()
+}
+
+/// Crash-dump sessions, kept open between listing the regions and dumping them
+type RamdumpSessions = Mutex<HashMap<String, qdl::RamdumpClient>>;
+
+/// Payload of the "update_ramdump_progress" event
+#[derive(Clone, serde::Serialize)]
+struct RamdumpProgress {
+    device_id: String,
+    region: String,
+    bytes: u64,
+    total: u64,
+}
+
+#[tauri::command]
+async fn list_ramdump_regions(app: AppHandle, device_id: String, is_debug: bool) -> Result<Vec<qdl::RamdumpRegion>, String> {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    let backend = native_backend();
+    if backend == qdl::types::QdlBackend::Serial && !config.is_connect {
+        let _ = app.emit("log_event", "port not available");
+        return Err("port not available".to_string());
+    }
+
+    let dev_path = native_dev_path(backend, &config, &device_id);
+    let connected = tauri::async_runtime::spawn_blocking(move || qdl::RamdumpClient::new(backend, dev_path)).await;
+    let client = match connected {
+        Ok(Ok(client)) => client,
+        Ok(Err(e)) => {
+            let _ = app.emit("log_event", &e);
+            return Err(e);
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+    let regions = client.regions();
+    let _ = app.emit("log_event", &format!("[{}] Device is in crash-dump mode, {} regions available", device_id, regions.len()));
+    if let Ok(mut sessions) = app.state::<RamdumpSessions>().lock() {
+        sessions.insert(device_id, client);
+    }
+    Ok(regions)
+}
+
+#[tauri::command]
+async fn dump_ramdump_regions(
+    app: AppHandle,
+    device_id: String,
+    regions: Vec<String>,
+    folder: String,
+    format: qdl::ramdump::RamdumpFormat,
+) -> Result<(), String> {
+    // Take the session out while dumping, so other devices aren't held up
+    let client = match app.state::<RamdumpSessions>().lock() {
+        Ok(mut sessions) => sessions.remove(&device_id),
+        Err(_e) => None,
+    };
+    let Some(mut client) = client else {
+        return Err(format!("No crash-dump session for {}, list the regions first", device_id));
+    };
+
+    let _ = app.emit("log_event", &format!("[{}] Dumping memory to {}", device_id, folder));
+    let handle = app.clone();
+    let id = device_id.clone();
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        let mut last_percentage = u64::MAX;
+        let result = client.dump(regions, std::path::Path::new(&folder), format, &mut |region, bytes, total| {
+            // One event per percent, the device is read in 4 KiB chunks
+            let percentage = match total {
+                0 => 100,
+                _ => bytes * 100 / total,
+            };
+            if percentage == last_percentage && bytes != total {
+                return;
+            }
+            last_percentage = percentage;
+            let _ = handle.emit("update_ramdump_progress", RamdumpProgress {
+                device_id: id.clone(),
+                region: region.to_string(),
+                bytes,
+                total,
+            });
+            if bytes == total {
+                let _ = handle.emit("log_event", &format!("[{}] Dumped {} (0x{:x} bytes)", id, region, total));
+            }
+        });
+        result.map(|_| client)
+    }).await;
+
+    match result {
+        Ok(Ok(client)) => {
+            let _ = app.emit("log_event", &format!("[{}] Memory dump finished", device_id));
+            if let Ok(mut sessions) = app.state::<RamdumpSessions>().lock() {
+                sessions.insert(device_id, client);
+            }
+            Ok(())
+        }
+        Ok(Err(e)) => {
+            let _ = app.emit("log_event", &format!("[{}] {}", device_id, e));
+            Err(e)
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Pull `size` bytes of memory from `address` through Firehose \<peek\>s,
+/// e.g. IMEM or boot logs of a device that won't boot
+#[tauri::command]
+async fn dump_memory(
+    app: AppHandle,
+    device_id: String,
+    address: u64,
+    size: u64,
+    folder: String,
+    resume: bool,
+) -> Result<(), String> {
+    let session = match app.state::<FirehoseSessions>().lock() {
+        Ok(sessions) => sessions.get(&device_id).cloned(),
+        Err(_e) => None,
+    };
+    let Some(session) = session else {
+        return Err(format!("No Firehose session for {}, send the loader natively first", device_id));
+    };
+
+    let path = std::path::Path::new(&folder).join(format!("peek_0x{:x}.bin", address));
+    let _ = app.emit("update_command_running_status", true);
+    let handle = app.clone();
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        let region = format!("0x{:x}", address);
+        let mut last_percentage = u64::MAX;
+        let mut backend = session.lock().map_err(|e| e.to_string())?;
+        backend.set_log_prefix(&format!("[{}] ", device_id));
+        backend.dump_memory(address, size, &path, resume, &mut |bytes, total| {
+            let percentage = match total {
+                0 => 100,
+                _ => bytes * 100 / total,
+            };
+            if percentage == last_percentage && bytes != total {
+                return;
+            }
+            last_percentage = percentage;
+            let _ = handle.emit("update_ramdump_progress", RamdumpProgress {
+                device_id: device_id.clone(),
+                region: region.clone(),
+                bytes,
+                total,
+            });
+        })
+    }).await;
+    let _ = app.emit("update_command_running_status", false);
+    match result {
+        Ok(result) => result.map(|_| ()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Run \<benchmark\> read and write trials with each payload size (every
+/// power of two up to the negotiated one if none), to tell a slow cable or
+/// hub from slow storage
+#[tauri::command]
+async fn benchmark(
+    app: AppHandle,
+    device_id: String,
+    trials: u32,
+    payload_sizes: Vec<usize>,
+) -> Result<Vec<qdl::types::BenchmarkResult>, String> {
+    let session = match app.state::<FirehoseSessions>().lock() {
+        Ok(sessions) => sessions.get(&device_id).cloned(),
+        Err(_e) => None,
+    };
+    let Some(session) = session else {
+        return Err(format!("No Firehose session for {}, send the loader natively first", device_id));
+    };
+
+    let _ = app.emit("update_command_running_status", true);
+    let handle = app.clone();
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        let mut backend = session.lock().map_err(|e| e.to_string())?;
+        backend.set_log_prefix(&format!("[{}] ", device_id));
+        let results = backend.benchmark(trials, &payload_sizes)?;
+        for result in &results {
+            let throughput = match result.throughput_mbps {
+                Some(mbps) => format!("{:.2} MB/s", mbps),
+                None => "no timing reported".to_string(),
+            };
+            let _ = handle.emit("log_event", &format!("[{}] {} {} bytes x {}: {}",
+                device_id, result.operation, result.payload_size, result.trials, throughput));
+        }
+        Ok(results)
+    }).await;
+    let _ = app.emit("update_command_running_status", false);
+    match result {
+        Ok(result) => result,
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Dry run of `provision_ufs`: the LUN layout the provision XML at `path`
+/// sets up, without a device
+#[tauri::command]
+fn read_ufs_provision(path: String) -> Result<String, String> {
+    let xml = file_util::read_text_file(&path).map_err(|e| format!("Error reading file: {}", e))?;
+    let provision = xml_file_util::parser_provision_xml(&xml).map_err(|e| e.to_string())?;
+    Ok(format_ufs_provision(&provision))
+}
+
+/// Send the \<ufs\> tags of the provision XML at `path`, e.g. after a UFS
+/// chip replacement. Unless `commit` is given and true the loader only
+/// checks the layout, with it the layout is written and there is no going
+/// back.
+#[tauri::command]
+async fn provision_ufs(app: AppHandle, device_id: String, path: String, commit: Option<bool>) -> Result<(), String> {
+    let commit = commit.unwrap_or(false);
+    let xml = file_util::read_text_file(&path).map_err(|e| format!("Error reading file: {}", e))?;
+    let provision = xml_file_util::parser_provision_xml(&xml).map_err(|e| e.to_string())?;
+    let session = match app.state::<FirehoseSessions>().lock() {
+        Ok(sessions) => sessions.get(&device_id).cloned(),
+        Err(_e) => None,
+    };
+    let Some(session) = session else {
+        return Err(format!("No Firehose session for {}, send the loader natively first", device_id));
+    };
+
+    let _ = app.emit("update_command_running_status", true);
+    let handle = app.clone();
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        let mut backend = session.lock().map_err(|e| e.to_string())?;
+        backend.set_log_prefix(&format!("[{}] ", device_id));
+        backend.provision_ufs(&provision, commit)?;
+        if commit {
+            let _ = handle.emit("log_event", &format!("[{}] Power cycle the device for the new LUNs to show up", device_id));
+        }
+        Ok(())
+    }).await;
+    let _ = app.emit("update_command_running_status", false);
+    match result {
+        Ok(result) => result,
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[tauri::command]
+async fn send_ping(app: AppHandle, device_id: String, is_debug: bool) {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    if !device_reachable(&app, &device_id, &config) {
         let _ = app.emit("log_event", "port not found");
         return;
     }
-    firehose_service::send_nop(&app, &config).await;
+    let _ = on_backend(&app, &device_id, &config, |backend| backend.nop()).await;
 }
 
 #[tauri::command]
@@ -469,184 +1289,103 @@ fn start_flashing(
     app: AppHandle,
     path: String,
     is_protect_lun5: bool,
+    device_id: String,
     is_debug: bool,
-    thread_state: State<Arc<Mutex<ThreadState>>>,
+    sessions: State<Arc<flash_session::SessionManager>>,
 ) -> Result<(), String> {
-    // lock thread state
-    let mut state_guard = thread_state
-        .lock()
-        .map_err(|e| format!("lock thread state faild: {}", e))?;
-
-    // if running then return
-    if state_guard.running.load(Ordering::SeqCst) {
-        return Ok(());
-    }
-    // set status to running
-    state_guard.running.store(true, Ordering::SeqCst);
+    let device = match device_util::find_edl_device(&device_id) {
+        Some(device) => device,
+        None => {
+            let _ = app.emit("log_event", "Port not available");
+            return Err("Device not found".to_string());
+        }
+    };
+    // if this device is already flashing then return
+    let job = match sessions.create_job(&app, &device.id) {
+        Some(job) => job,
+        None => return Ok(()),
+    };
 
-    // clone state, app for thread using
-    let state_clone = thread_state.inner().clone();
+    // clone sessions, app, job for thread using
+    let sessions_clone = sessions.inner().clone();
     let app_clone = app.clone();
+    let job_clone = job.clone();
 
     // create thread
     let handle = thread::spawn(move || {
-        let _ = app_clone.emit("update_command_running_status", true);
-        let _ = app_clone.emit("update_percentage", 5);
-        match file_util::check_necessary_files_in_edl_folder(&path, is_protect_lun5) {
-            Ok(package) => {
-                if package.is_miss_file == false {
-                    let _ = app_clone.emit("log_event", &format!("Check necessary files...OK"));
-                    let _ = app_clone.emit("update_percentage", 10);
-                    let _ = app_clone.emit("log_event", &format!("Merging Super image..."));
-                    if super_image_creater::creat_super_image(&package.super_define) == false {
-                        let _ = app_clone.emit("stop_edl_flashing", "");
-                        let _ = app_clone.emit("log_event", "Failed to create Super image.");
-                        let _ = app_clone.emit("log_event", "The flashing operation has been stopped");
-                        state_clone.lock().unwrap().running.store(false, Ordering::SeqCst);
-                        let _ = app_clone.emit("update_command_running_status", false);
-                        return;
-                    }
-                    if state_clone.lock().unwrap().running.load(Ordering::SeqCst) == false {
-                        let _ = app_clone.emit("log_event", "Operation canceled by user");
-                        let _ = app_clone.emit("log_event", "The flashing operation has been stopped");
-                        let _ = app_clone.emit("update_command_running_status", false);
-                        return;
-                    }
-                    let _ = app_clone.emit("log_event", &format!("Merge Super image...OK"));
-                    let _ = app_clone.emit("update_percentage", 20);
-                    let (port_path, _port_info) = update_port();
-                    if port_path == "Not found" {
-                        let _ = app_clone.emit("log_event", &format!("Port not available"));
-                        let _ = app_clone.emit("update_command_running_status", false);
-                        return;
-                    }
-                    let config = command_util::Config::setup_env(is_debug);
-                    if config.is_connect == false {
-                        let _ = app_clone.emit("log_event", "port not available");
-                        return;
-                    }
-                    let rt = Runtime::new().unwrap();
-                    let (_file_name, dir_path) =
-                        file_util::parse_file_path("", &package.patch_files[0]);
-                    if flash_program_xml(
-                        &state_clone,
-                        &app_clone,
-                        &dir_path,
-                        package.raw_programs,
-                        &config,
-                        &rt,
-                    ) == false
-                    {
-                        let _ = app_clone.emit("log_event", "The flashing operation has been stopped");
-                        state_clone.lock().unwrap().running.store(false, Ordering::SeqCst);
-                        let _ = app_clone.emit("update_command_running_status", false);
-                        return;
-                    }
-                    let _ = app_clone.emit("update_percentage", 80);
-                    if flash_patch_xml(
-                        &state_clone,
-                        &app_clone,
-                        &dir_path,
-                        package.patch_files,
-                        &config,
-                        &rt,
-                    ) == false
-                    {
-                        let _ = app_clone.emit("log_event", "The flashing operation has been stopped");
-                        state_clone.lock().unwrap().running.store(false, Ordering::SeqCst);
-                        let _ = app_clone.emit("update_command_running_status", false);
-                        return;
-                    }
-                    let _ = app_clone.emit("update_percentage", 95);
-                    let result = rt.block_on(firehose_service::switch_slot(&app_clone, "A", &config));
-                    if result == false {
-                        let _ = app_clone.emit("log_event", "The flashing operation has been stopped");
-                        state_clone.lock().unwrap().running.store(false, Ordering::SeqCst);
-                        let _ = app_clone.emit("update_command_running_status", false);
-                        return;
-                    }
-                    let _ = app_clone.emit("update_percentage", 100);
-                    let _ = app_clone.emit("update_command_running_status", false);
-                } else {
-                    let _ = app_clone.emit("log_event", &format!("Check necessary files...Error"));
-                }
-            }
-            Err(_e) => {
-                let _ = app_clone.emit("log_event", &format!("Check necessary files...Error"));
-            }
-        }
-
-        state_clone.lock().unwrap().running.store(false, Ordering::SeqCst);
-        let _ = app_clone.emit("log_event", "The flashing operation has been stopped");
+        let state = run_flash_job(&job_clone, &sessions_clone, &app_clone, &path, is_protect_lun5, is_debug);
+        job_clone.log("The flashing operation has been stopped");
+        job_clone.finish(state);
     });
 
-    // store handler to global state
-    state_guard.thread_handle = Some(handle);
+    // store handler to the job
+    job.set_thread(handle);
     Ok(())
 }
 
 #[tauri::command]
 fn stop_flashing(
     app: AppHandle,
-    thread_state: State<Arc<Mutex<ThreadState>>>,
+    device_id: String,
+    sessions: State<Arc<flash_session::SessionManager>>,
 ) -> Result<(), String> {
-    // lock thread state
-    let state_guard = thread_state.lock().map_err(|e| format!("lock thread state faild: {}", e))?;
+    let device_id = match device_util::find_edl_device(&device_id) {
+        Some(device) => device.id,
+        None => device_id,
+    };
 
     // if not running then return
-    if state_guard.running.load(Ordering::SeqCst) == false {
-        return Ok(());
-    }
-
-    // set running state to false
-    state_guard.running.store(false, Ordering::SeqCst);
+    let job = match sessions.running_job(&device_id) {
+        Some(job) => job,
+        None => return Ok(()),
+    };
 
-    let _ = app.emit("log_event", "Stopping the EDL flashing operation");
+    job.cancel();
+    let _ = app.emit("log_event", format!("[{}] Stopping the EDL flashing operation", device_id));
 
     Ok(())
 }
 
+/// Switch the active A/B slot: the attribute bits of the slotted GPT
+/// entries the bootloader goes by, then the boot LUN to match
 #[tauri::command]
-async fn switch_slot(app: AppHandle, slot: &str, is_debug: bool) -> Result<(), Error> {
-    let config = command_util::Config::setup_env(is_debug);
-    if config.is_connect == false {
+async fn switch_slot(app: AppHandle, slot: &str, device_id: String, is_debug: bool) -> Result<(), Error> {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    if !device_reachable(&app, &device_id, &config) {
         let _ = app.emit("log_event", "Device not found");
         return Err(tauri::Error::AssetNotFound("Device not found".to_string()));
     }
     let _ = app.emit("update_command_running_status", true);
-    firehose_service::switch_slot(&app, &slot, &config).await;
+    let infos = on_backend(&app, &device_id, &config, read_all_storage_info).await;
+    let layout = storage_layout(&app, infos);
+    let slot = slot.to_string();
+    let work_dir = config.work_dir.clone();
+    let result = on_backend(&app, &device_id, &config, move |backend| {
+        let switched = firehose_backend::switch_slot_gpt(backend, &slot, &layout, &work_dir)?;
+        backend.set_active_slot(&slot)?;
+        Ok((slot, switched))
+    }).await;
+    match result {
+        Ok((slot, switched)) => {
+            let _ = app.emit("log_event", format!("Slot {} is active, {} partitions switched", slot, switched));
+        }
+        Err(e) => {
+            let _ = app.emit("log_event", format!("Slot switch failed: {}", e));
+        }
+    }
     let _ = app.emit("update_command_running_status", false);
     return Ok(());
 }
 
 #[tauri::command]
-fn update_port() -> (String, String) {
-    let ports = available_ports().expect("Not found");
-    let mut port = String::new();
-    let mut product = String::new();
-    for p in ports {
-        match p.port_type {
-            SerialPortType::UsbPort(info) => {
-                port = p.port_name;
-                if let Some(pinfo) = info.product {
-                    println!("product : {}", pinfo);
-                    product = pinfo;
-                }
-            }
-            SerialPortType::PciPort | SerialPortType::BluetoothPort | SerialPortType::Unknown => {}
-        }
-    }
-    if port.is_empty() {
-        ("Not found".to_string(), "N/A".to_string())
-    } else {
-        (port, product)
-    }
+fn list_edl_devices() -> Vec<device_util::EdlDevice> {
+    device_util::list_edl_devices()
 }
 
 #[tauri::command]
-async fn write_from_xml(app: AppHandle, file_path: &str, is_debug: bool) -> Result<(), Error> {
-    let config = command_util::Config::setup_env(is_debug);
-    if config.is_connect == false {
+async fn write_from_xml(app: AppHandle, file_path: &str, device_id: String, is_debug: bool) -> Result<(), Error> {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    if !device_reachable(&app, &device_id, &config) {
         let _ = app.emit("log_event", "port not found");
         return Err(tauri::Error::AssetNotFound(
             "port not available".to_string(),
@@ -655,27 +1394,68 @@ async fn write_from_xml(app: AppHandle, file_path: &str, is_debug: bool) -> Resu
     let _ = app.emit("update_command_running_status", true);
     let xml = match file_util::read_text_file(file_path) {
         Ok(content) => content,
-        Err(e) => format!("Error reading file: {}", e),
+        Err(e) => {
+            let _ = app.emit("update_command_running_status", false);
+            return Err(command_failed(&app, format!("Error reading file: {}", e)));
+        }
     };
     let (_file_name, dir_path) = file_util::parse_file_path("", file_path);
 
     let items = xml_file_util::parser_program_xml(&dir_path, &xml);
-    for (part, xml_content, _dir_path) in items {
-        if config.is_connect == false {
-            return Err(tauri::Error::AssetNotFound(
-                "port not available".to_string(),
-            ));
+    let result = on_backend(&app, &device_id, &config, move |backend| {
+        for (part, xml_content, _dir_path) in items {
+            program_or_stop(backend, &part, &xml_content, &dir_path)?;
         }
-        let _ = firehose_service::flash_part(&app, &part, &xml_content, &dir_path, &config).await;
+        Ok(())
+    }).await;
+    let _ = app.emit("update_command_running_status", false);
+    result.map_err(|e| command_failed(&app, e))
+}
+
+/// Program one partition of a batch, the batch stops at the first failure
+/// rather than leaving a device with a mix of old and new images behind
+fn program_or_stop(backend: &mut dyn FlashBackend, part: &str, xml: &str, dir: &str) -> Result<(), String> {
+    match backend.program(part, xml, dir) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to write {} ({}), stopped there", part, e.trim())),
     }
+}
+
+/// Check the partitions of a rawprogram XML against its images, by SHA256
+#[tauri::command]
+async fn verify_from_xml(app: AppHandle, file_path: &str, device_id: String, is_debug: bool) -> Result<Vec<firehose_backend::VerifyResult>, String> {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    if !device_reachable(&app, &device_id, &config) {
+        let _ = app.emit("log_event", "port not found");
+        return Err("port not available".to_string());
+    }
+    let xml = file_util::read_text_file(file_path).map_err(|e| format!("Error reading file: {}", e))?;
+    let (_file_name, dir_path) = file_util::parse_file_path("", file_path);
+
+    let _ = app.emit("update_command_running_status", true);
+    let results = on_backend(&app, &device_id, &config, move |backend| {
+        firehose_backend::verify_programs(backend, &xml, &dir_path)
+    }).await;
     let _ = app.emit("update_command_running_status", false);
-    Ok(())
+
+    let results = results?;
+    for result in &results {
+        let status = match (result.passed, result.error.is_empty()) {
+            (true, _) => "PASS".to_string(),
+            (false, true) => format!("FAIL (device {})", result.device_sha256),
+            (false, false) => format!("ERROR ({})", result.error),
+        };
+        let _ = app.emit("log_event", format!("{}Verify {}: {}", config.log_prefix, result.label, status));
+    }
+    let passed = results.iter().filter(|result| result.passed).count();
+    let _ = app.emit("log_event", format!("{}Verify: {}/{} partitions match", config.log_prefix, passed, results.len()));
+    Ok(results)
 }
 
 #[tauri::command]
-async fn write_part(app: AppHandle, xml: &str, is_debug: bool) -> Result<(), Error> {
-    let config = command_util::Config::setup_env(is_debug);
-    if config.is_connect == false {
+async fn write_part(app: AppHandle, xml: &str, device_id: String, is_debug: bool) -> Result<(), Error> {
+    let config = command_util::Config::setup_env(&device_id, is_debug);
+    if !device_reachable(&app, &device_id, &config) {
         let _ = app.emit("log_event", "port not found");
         return Err(tauri::Error::AssetNotFound(
             "port not available".to_string(),
@@ -683,11 +1463,14 @@ async fn write_part(app: AppHandle, xml: &str, is_debug: bool) -> Result<(), Err
     }
     let _ = app.emit("update_command_running_status", true);
     let items = xml_file_util::parser_program_xml("", xml);
-    for (part, xml_content, dir_path) in items {
-        let _ = firehose_service::flash_part(&app, &part, &xml_content, &dir_path, &config).await;
-    }
+    let result = on_backend(&app, &device_id, &config, move |backend| {
+        for (part, xml_content, dir_path) in items {
+            program_or_stop(backend, &part, &xml_content, &dir_path)?;
+        }
+        Ok(())
+    }).await;
     let _ = app.emit("update_command_running_status", false);
-    Ok(())
+    result.map_err(|e| command_failed(&app, e))
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -697,25 +1480,51 @@ pub fn run() {
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_opener::init())
-        .manage(Arc::new(Mutex::new(ThreadState::default())))
+        .manage(Arc::new(flash_session::SessionManager::default()))
+        .manage(RamdumpSessions::default())
+        .manage(FirehoseSessions::default())
+        .manage(FirehoseLogOps::default())
+        .manage(Mutex::new(FirehoseTimeouts::default()))
+        .manage(RecordSessions::default())
+        .manage(DigestTableSize::default())
+        .setup(|app| {
+            let handle = app.handle().clone();
+            device_util::spawn_hotplug_watcher(Duration::from_secs(1), move |event| {
+                on_hotplug_event(&handle, event)
+            });
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
+            benchmark,
+            dump_memory,
+            dump_ramdump_regions,
             erase_part,
             identify_loader,
+            list_edl_devices,
+            list_ramdump_regions,
+            provision_ufs,
             read_device_info,
             read_gpt,
             read_part,
+            read_sahara_info,
+            read_ufs_provision,
             reboot_to_edl,
             reboot_to_fastboot,
             reboot_to_recovery,
             reboot_to_system,
+            replay_capture,
             run_command,
             save_to_xml,
             send_ping,
             send_loader,
+            set_digest_table_size,
+            set_firehose_log_ops,
+            set_firehose_timeouts,
+            set_record_sessions,
             start_flashing,
             stop_flashing,
             switch_slot,
-            update_port,
+            verify_from_xml,
             write_from_xml,
             write_part
         ])
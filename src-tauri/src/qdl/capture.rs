@@ -0,0 +1,404 @@
+// SPDX-License-Identifier: BSD-3-Clause
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+
+//! Wire capture of Sahara/Firehose sessions and replay of such captures.
+//!
+//! A capture file starts with `CAPTURE_MAGIC`, followed by records of:
+//! direction (u8), timestamp in us since the start (u64 LE), original
+//! length (u32 LE), stored length (u32 LE) and the stored bytes.
+
+use anyhow::{Result, bail};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::qdl::types::{FirehoseConfiguration, FirehoseLog, QdlChan, QdlReadWrite};
+
+const CAPTURE_MAGIC: &[u8; 8] = b"QDLCAP01";
+
+/// Payload bytes kept per frame by default, enough for every Sahara packet
+/// and Firehose XML while leaving the bulk of image data out of the capture
+pub const CAPTURE_MAX_PAYLOAD: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum CaptureDir {
+    HostToDevice = 0,
+    DeviceToHost = 1,
+    /// A read that timed out, which Firehose uses as an end-of-data marker
+    DeviceTimeout = 2,
+    /// Any other read error (e.g. the device went away)
+    DeviceError = 3,
+}
+
+impl CaptureDir {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(CaptureDir::HostToDevice),
+            1 => Some(CaptureDir::DeviceToHost),
+            2 => Some(CaptureDir::DeviceTimeout),
+            3 => Some(CaptureDir::DeviceError),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CaptureFrame {
+    pub dir: CaptureDir,
+    pub timestamp_us: u64,
+    /// Length on the wire, `data` may be shorter if the payload was truncated
+    pub len: usize,
+    pub data: Vec<u8>,
+}
+
+/// Recording wrapper, usable both around a transport (QdlReadWrite) and
+/// around a whole channel (QdlChan)
+pub struct QdlRecorder<T: ?Sized> {
+    out: BufWriter<File>,
+    start: Instant,
+    /// Store at most this many bytes of every frame (e.g. to skip image data)
+    max_payload_bytes: Option<usize>,
+    /// Bytes of the inner buffer already recorded but not consumed yet
+    buffered: usize,
+    inner: Box<T>,
+}
+
+impl<T: ?Sized> QdlRecorder<T> {
+    pub fn new(
+        inner: Box<T>,
+        capture_path: impl AsRef<Path>,
+        max_payload_bytes: Option<usize>,
+    ) -> Result<Self> {
+        let mut out = BufWriter::new(File::create(capture_path)?);
+        out.write_all(CAPTURE_MAGIC)?;
+
+        Ok(Self {
+            out,
+            start: Instant::now(),
+            max_payload_bytes,
+            buffered: 0,
+            inner,
+        })
+    }
+
+    fn record(&mut self, dir: CaptureDir, data: &[u8]) {
+        let stored = match self.max_payload_bytes {
+            Some(max) => &data[..data.len().min(max)],
+            None => data,
+        };
+        let ts = self.start.elapsed().as_micros() as u64;
+
+        // A broken capture must never break the session it's watching
+        let _ = self.out.write_all(&[dir as u8]);
+        let _ = self.out.write_all(&ts.to_le_bytes());
+        let _ = self.out.write_all(&(data.len() as u32).to_le_bytes());
+        let _ = self.out.write_all(&(stored.len() as u32).to_le_bytes());
+        let _ = self.out.write_all(stored);
+        let _ = self.out.flush();
+    }
+
+    fn record_err(&mut self, e: &std::io::Error) {
+        match e.kind() {
+            ErrorKind::TimedOut => self.record(CaptureDir::DeviceTimeout, &[]),
+            _ => self.record(CaptureDir::DeviceError, e.to_string().as_bytes()),
+        }
+    }
+}
+
+impl<T: Read + ?Sized> Read for QdlRecorder<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(n) => {
+                // Whatever fill_buf() handed out before is on record already
+                let fresh = n.saturating_sub(self.buffered);
+                self.buffered = self.buffered.saturating_sub(n);
+                self.record(CaptureDir::DeviceToHost, &buf[n - fresh..n]);
+                Ok(n)
+            }
+            Err(e) => {
+                self.record_err(&e);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<T: Write + ?Sized> Write for QdlRecorder<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.record(CaptureDir::HostToDevice, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: BufRead + ?Sized> BufRead for QdlRecorder<T> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        // Record every transfer as it comes off the wire, whether or not
+        // the host ends up consuming it
+        let fresh = match self.inner.fill_buf() {
+            Ok(buf) if buf.len() > self.buffered => buf[self.buffered..].to_vec(),
+            Ok(_) => Vec::new(),
+            Err(e) => {
+                self.record_err(&e);
+                return Err(e);
+            }
+        };
+        if !fresh.is_empty() {
+            self.buffered += fresh.len();
+            self.record(CaptureDir::DeviceToHost, &fresh);
+        }
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.buffered = self.buffered.saturating_sub(amt);
+        self.inner.consume(amt);
+    }
+}
+
+impl<T: QdlReadWrite + ?Sized> QdlReadWrite for QdlRecorder<T> {
+    fn set_timeouts(&mut self, read: Duration, write: Duration) -> std::io::Result<()> {
+        self.inner.set_timeouts(read, write)
+    }
+}
+
+impl<T: QdlChan + ?Sized> QdlChan for QdlRecorder<T> {
+    fn fh_config(&self) -> &FirehoseConfiguration {
+        self.inner.fh_config()
+    }
+
+    fn mut_fh_config(&mut self) -> &mut FirehoseConfiguration {
+        self.inner.mut_fh_config()
+    }
+
+    fn firehose_op(&self) -> &str {
+        self.inner.firehose_op()
+    }
+
+    fn set_firehose_op(&mut self, op: &str) {
+        self.inner.set_firehose_op(op)
+    }
+
+    fn firehose_log(&mut self, log: &FirehoseLog) -> bool {
+        self.inner.firehose_log(log)
+    }
+
+    fn set_timeouts(&mut self, read: Duration, write: Duration) -> std::io::Result<()> {
+        self.inner.set_timeouts(read, write)
+    }
+}
+
+/// Load all frames of a capture file
+pub fn read_capture(capture_path: impl AsRef<Path>) -> Result<Vec<CaptureFrame>> {
+    let mut f = BufReader::new(File::open(capture_path)?);
+
+    let mut magic = [0u8; 8];
+    f.read_exact(&mut magic)?;
+    if &magic != CAPTURE_MAGIC {
+        bail!("Not a QDL capture file");
+    }
+
+    let mut frames = Vec::new();
+    loop {
+        let mut hdr = [0u8; 17];
+        match f.read_exact(&mut hdr) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let Some(dir) = CaptureDir::from_u8(hdr[0]) else {
+            bail!("Corrupted capture record at frame {}", frames.len());
+        };
+        let timestamp_us = u64::from_le_bytes(hdr[1..9].try_into()?);
+        let len = u32::from_le_bytes(hdr[9..13].try_into()?) as usize;
+        let stored = u32::from_le_bytes(hdr[13..17].try_into()?) as usize;
+
+        let mut data = vec![0u8; stored];
+        f.read_exact(&mut data)?;
+        frames.push(CaptureFrame {
+            dir,
+            timestamp_us,
+            len,
+            data,
+        });
+    }
+
+    Ok(frames)
+}
+
+/// Transport that plays the device side of a capture back to the host
+pub struct QdlReplay {
+    device_frames: VecDeque<CaptureFrame>,
+    host_frames: VecDeque<CaptureFrame>,
+    host_frame_idx: usize,
+    mismatches: usize,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl QdlReplay {
+    pub fn new(frames: Vec<CaptureFrame>) -> Self {
+        let (host_frames, device_frames): (VecDeque<_>, VecDeque<_>) = frames
+            .into_iter()
+            .partition(|f| f.dir == CaptureDir::HostToDevice);
+
+        Self {
+            device_frames,
+            host_frames,
+            host_frame_idx: 0,
+            mismatches: 0,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// How many host writes differed from the recorded session so far
+    #[allow(dead_code)]
+    pub fn mismatches(&self) -> usize {
+        self.mismatches
+    }
+}
+
+impl Write for QdlReplay {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.host_frames.pop_front() {
+            Some(frame) => {
+                let stored = frame.data.len();
+                if frame.len != buf.len() || frame.data[..] != buf[..stored.min(buf.len())] {
+                    self.mismatches += 1;
+                    eprintln!(
+                        "replay: host frame {} (+{}us) differs from the capture ({} vs {} bytes)",
+                        self.host_frame_idx,
+                        frame.timestamp_us,
+                        buf.len(),
+                        frame.len
+                    );
+                }
+            }
+            None => eprintln!(
+                "replay: host frame {} is past the end of the capture",
+                self.host_frame_idx
+            ),
+        }
+        self.host_frame_idx += 1;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for QdlReplay {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        // A recorded zero-length read (e.g. a ZLP) is replayed as such
+        if self.pos >= self.buf.len()
+            && self
+                .device_frames
+                .front()
+                .is_some_and(|f| f.dir == CaptureDir::DeviceToHost && f.len == 0)
+        {
+            self.device_frames.pop_front();
+            return Ok(0);
+        }
+
+        let n = {
+            let available = self.fill_buf()?;
+            let n = std::cmp::min(out.len(), available.len());
+            out[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for QdlReplay {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        while self.pos >= self.buf.len() {
+            let Some(frame) = self.device_frames.pop_front() else {
+                return Err(ErrorKind::TimedOut.into());
+            };
+            match frame.dir {
+                CaptureDir::DeviceTimeout => return Err(ErrorKind::TimedOut.into()),
+                CaptureDir::DeviceError => {
+                    return Err(std::io::Error::other(
+                        String::from_utf8_lossy(&frame.data).into_owned(),
+                    ));
+                }
+                _ => {
+                    // Truncated payloads are padded back to their wire length
+                    self.buf = frame.data;
+                    self.buf.resize(frame.len, 0);
+                    self.pos = 0;
+                }
+            }
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = std::cmp::min(self.pos + amt, self.buf.len());
+    }
+}
+
+impl QdlReadWrite for QdlReplay {}
+
+pub fn setup_replay_device(capture_path: Option<String>) -> Result<QdlReplay> {
+    let Some(capture_path) = capture_path else {
+        bail!("Capture file unspecified");
+    };
+
+    Ok(QdlReplay::new(read_capture(capture_path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device_frame(data: &[u8]) -> CaptureFrame {
+        CaptureFrame {
+            dir: CaptureDir::DeviceToHost,
+            timestamp_us: 0,
+            len: data.len(),
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn records_whole_transfers() {
+        let path = std::env::temp_dir().join("qdl-capture-test.qdlcap");
+        let device = QdlReplay::new(vec![device_frame(b"first"), device_frame(b"second")]);
+        let mut rec = QdlRecorder::new(Box::new(device), &path, None).unwrap();
+
+        // Peeked but only partly eaten, still a single frame on record
+        assert_eq!(rec.fill_buf().unwrap(), b"first");
+        rec.consume(2);
+        assert_eq!(rec.fill_buf().unwrap(), b"rst");
+        rec.consume(3);
+        let mut buf = [0u8; 16];
+        assert_eq!(rec.read(&mut buf).unwrap(), 6);
+        rec.write_all(b"ack").unwrap();
+        assert!(rec.fill_buf().is_err());
+        drop(rec);
+
+        let frames = read_capture(&path).unwrap();
+        let frames: Vec<_> = frames.iter().map(|f| (f.dir, &f.data[..])).collect();
+        assert_eq!(
+            frames,
+            [
+                (CaptureDir::DeviceToHost, &b"first"[..]),
+                (CaptureDir::DeviceToHost, &b"second"[..]),
+                (CaptureDir::HostToDevice, &b"ack"[..]),
+                (CaptureDir::DeviceTimeout, &b""[..]),
+            ]
+        );
+    }
+}
@@ -7,11 +7,16 @@ use pbr::{ProgressBar, Units};
 use std::cmp::min;
 use std::io::{Read, Write};
 use std::str::{self, FromStr};
+use std::time::Duration;
 use xmltree::{Element, XMLNode};
 
-use crate::qdl::parsers::firehose_parser_ack_nak;
+use crate::qdl::parsers::{
+    firehose_parse_benchmark, firehose_parse_peek_bytes, firehose_parse_sha256_digest,
+    firehose_parse_storage_info, firehose_parser_ack_nak,
+};
 use crate::qdl::types::{
-    FirehoseResetMode, FirehoseStatus, FirehoseStorageType, QdlBackend, QdlChan,
+    BenchmarkResult, FirehoseError, FirehoseLog, FirehoseLogSeverity, FirehoseResetMode,
+    FirehoseStatus, FirehoseStorageType, QdlBackend, QdlChan, StorageInfo,
 };
 
 /// Reboot or power off the Device
@@ -35,7 +40,7 @@ pub fn firehose_reset<T: QdlChan>(
         ],
     )?;
 
-    firehose_write_getack(channel, &mut xml, "reset the Device".to_owned())
+    firehose_write_getack(channel, "power", &mut xml, "reset the Device".to_owned())
 }
 
 /// Wrapper for easily creating Firehose-y XML packets
@@ -59,29 +64,125 @@ fn firehose_xml_setup(op: &str, kvps: &[(&str, &str)]) -> anyhow::Result<Vec<u8>
     Ok(buf)
 }
 
-/// Send a Firehose packet and check for ack/nak
+/// Send the `op` Firehose packet and check for ack/nak, retrying as its
+/// retry policy says
 pub fn firehose_write_getack<T: QdlChan>(
     channel: &mut T,
+    op: &str,
     buf: &mut [u8],
     couldnt_what: String,
 ) -> anyhow::Result<()> {
-    firehose_write(channel, buf)?;
-
-    match firehose_read::<T>(channel, firehose_parser_ack_nak) {
-        Ok(FirehoseStatus::Ack) => Ok(()),
-        Ok(FirehoseStatus::Nak) => {
-            // Assume FH will hang after NAK..
-            firehose_reset(channel, &FirehoseResetMode::ResetToEdl, 0)?;
-            Err(anyhow::Error::msg(format!("Couldn't {couldnt_what}")))
+    firehose_with_retry(channel, op, |channel| {
+        firehose_write(channel, buf)?;
+        firehose_expect_ack(channel, || format!("Couldn't {couldnt_what}"))
+    })
+}
+
+/// Read a response, turning a NAK into `FirehoseError::Nak(what())`
+fn firehose_expect_ack<T: QdlChan>(
+    channel: &mut T,
+    what: impl FnOnce() -> String,
+) -> anyhow::Result<()> {
+    match firehose_read::<T>(channel, firehose_parser_ack_nak)? {
+        FirehoseStatus::Ack => Ok(()),
+        FirehoseStatus::Nak => Err(FirehoseError::Nak(what()).into()),
+    }
+}
+
+/// Run `op`, the `what` Firehose command, again for as long as the loader
+/// NAKs it and the command's retry policy allows. Other errors, and NAKs
+/// leaving the loader in rawmode, are returned right away.
+pub fn firehose_with_retry<T: QdlChan, R>(
+    channel: &mut T,
+    what: &str,
+    mut op: impl FnMut(&mut T) -> anyhow::Result<R>,
+) -> anyhow::Result<R> {
+    let policy = channel.fh_config().retry_policy(what);
+    let mut attempt = 0;
+    loop {
+        let e = match op(channel) {
+            Ok(ret) => return Ok(ret),
+            Err(e) => e,
+        };
+        let nak = matches!(e.downcast_ref(), Some(FirehoseError::Nak(_)));
+        if !nak || channel.fh_config().rawmode {
+            return Err(e);
+        }
+        if attempt == policy.retries {
+            // Assume FH will hang after NAK.. (<power> itself excepted)
+            if policy.reset_after && what != "power" {
+                let _ = firehose_reset(channel, &FirehoseResetMode::ResetToEdl, 0);
+            }
+            return Err(e);
         }
-        Err(e) => Err(e),
+        attempt += 1;
+        println!(
+            "{}",
+            format!(
+                "<{what}> was NAKed, retrying ({attempt}/{})",
+                policy.retries
+            )
+            .bright_yellow()
+        );
+        std::thread::sleep(policy.delay);
     }
 }
 
+/// Run `op` with the channel timeouts stretched to fit a transfer of `bytes`,
+/// restoring the channel-wide defaults afterwards
+pub fn firehose_with_xfer_timeout<T: QdlChan, R>(
+    channel: &mut T,
+    bytes: usize,
+    op: impl FnOnce(&mut T) -> anyhow::Result<R>,
+) -> anyhow::Result<R> {
+    let timeout = channel.fh_config().xfer_timeout(bytes);
+    firehose_with_timeout(channel, timeout, op)
+}
+
+/// Run `op` with both channel timeouts set to `timeout`, restoring the
+/// channel-wide defaults afterwards
+fn firehose_with_timeout<T: QdlChan, R>(
+    channel: &mut T,
+    timeout: Duration,
+    op: impl FnOnce(&mut T) -> anyhow::Result<R>,
+) -> anyhow::Result<R> {
+    channel.set_timeouts(timeout, timeout)?;
+
+    let ret = op(channel);
+
+    let (read, write) = (
+        channel.fh_config().read_timeout,
+        channel.fh_config().write_timeout,
+    );
+    channel.set_timeouts(read, write)?;
+
+    ret
+}
+
 /// Main Firehose XML reading function
 pub fn firehose_read<T: QdlChan>(
     channel: &mut T,
     response_parser: fn(&mut T, &IndexMap<String, String>) -> Result<FirehoseStatus, anyhow::Error>,
+) -> Result<FirehoseStatus, anyhow::Error> {
+    firehose_read_inner(channel, response_parser, None)
+}
+
+/// Like `firehose_read`, but the \<log\> messages preceding the response
+/// are collected into `logs` instead of being printed, for the operations
+/// that only report their results there. Warnings and errors still reach
+/// the log sink.
+pub fn firehose_read_logs<T: QdlChan>(
+    channel: &mut T,
+    response_parser: fn(&mut T, &IndexMap<String, String>) -> Result<FirehoseStatus, anyhow::Error>,
+    logs: &mut Vec<String>,
+) -> Result<FirehoseStatus, anyhow::Error> {
+    firehose_read_inner(channel, response_parser, Some(logs))
+}
+
+fn firehose_read_inner<T: QdlChan>(
+    channel: &mut T,
+    response_parser: fn(&mut T, &IndexMap<String, String>) -> Result<FirehoseStatus, anyhow::Error>,
+    mut logs: Option<&mut Vec<String>>,
 ) -> Result<FirehoseStatus, anyhow::Error> {
     let mut got_any_data = false;
     let mut pending: Vec<u8> = Vec::new();
@@ -90,18 +191,13 @@ pub fn firehose_read<T: QdlChan>(
         // Use BufRead to peek at available data
         let available = match channel.fill_buf() {
             Ok(buf) => buf,
-            Err(e) => match e.kind() {
-                // In some cases (like with welcome messages), there's no acking
-                // and a timeout is the "end of data" marker instead..
-                std::io::ErrorKind::TimedOut => {
-                    if got_any_data {
-                        return Ok(FirehoseStatus::Ack);
-                    } else {
-                        return Err(e.into());
-                    }
-                }
-                _ => return Err(e.into()),
-            },
+            // Logs alone aren't an answer, only a parsed <response> is. The
+            // callers that merely drain the welcome logs ignore the timeout.
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut && got_any_data => {
+                return Err(anyhow::Error::new(e)
+                    .context("Timed out waiting for a Firehose response after its logs"));
+            }
+            Err(e) => return Err(e.into()),
         };
 
         got_any_data = true;
@@ -151,20 +247,25 @@ pub fn firehose_read<T: QdlChan>(
 
             // The spec expects there's always a single node only
             if let Some(XMLNode::Element(e)) = xml.children.first() {
-                // Check for a 'log' node and print out the message
+                // Check for a 'log' node and pass the message on
                 if e.name == "log" {
-                    if channel.fh_config().skip_firehose_log {
-                        continue;
-                    }
-
-                    println!(
-                        "LOG: {}",
+                    let log = FirehoseLog::new(
+                        channel.firehose_op(),
                         e.attributes
                             .get("value")
-                            .to_owned()
-                            .unwrap_or(&String::from("<garbage log data>"))
-                            .bright_black()
+                            .cloned()
+                            .unwrap_or(String::from("<garbage log data>")),
                     );
+                    if let Some(logs) = logs.as_deref_mut() {
+                        logs.push(log.message.clone());
+                        // The results are in there, only pass the trouble on
+                        if log.severity == FirehoseLogSeverity::Info {
+                            continue;
+                        }
+                    }
+                    if !channel.firehose_log(&log) && !channel.fh_config().skip_firehose_log {
+                        println!("LOG: {}", log.message.bright_black());
+                    }
 
                     continue;
                 }
@@ -175,13 +276,21 @@ pub fn firehose_read<T: QdlChan>(
                     println!("RECV: {}", format!("{e:?}").magenta());
                 }
 
+                // Follow the loader in and out of rawmode, XML can't be sent
+                // while it's in there
+                if let Some(rawmode) = e.attributes.get("rawmode") {
+                    channel.mut_fh_config().rawmode = rawmode == "true";
+                }
+
                 // TODO: Use std::intrinsics::unlikely after it exits nightly
                 if e.attributes.get("AttemptRetry").is_some() {
-                    return firehose_read::<T>(channel, response_parser);
+                    // The actual response is still to come
+                    continue;
                 } else if e.attributes.get("AttemptRestart").is_some() {
-                    // TODO: handle this automagically
+                    // SaharaClient::with_recovery() loads it again from here
+                    channel.mut_fh_config().rawmode = false;
                     firehose_reset(channel, &FirehoseResetMode::ResetToEdl, 0)?;
-                    bail!("Firehose requested a restart. Run the program again.");
+                    return Err(FirehoseError::RestartRequested.into());
                 }
 
                 // Pass other nodes to specialized parsers
@@ -197,8 +306,24 @@ pub fn firehose_read<T: QdlChan>(
     }
 }
 
+/// Tag of the command in a packet made by `firehose_xml_setup`
+fn firehose_op_name(buf: &[u8]) -> Option<&str> {
+    let marker = b"<data>";
+    let start = buf.windows(marker.len()).position(|w| w == marker)? + marker.len();
+    let rest = str::from_utf8(&buf[start..]).ok()?.trim_start();
+    let rest = rest.strip_prefix('<')?;
+    let end = rest.find(|c: char| c.is_whitespace() || c == '/' || c == '>')?;
+    Some(&rest[..end])
+}
+
 /// Send a Firehose packet
 pub fn firehose_write<T: QdlChan>(channel: &mut T, buf: &mut [u8]) -> anyhow::Result<()> {
+    if channel.fh_config().rawmode {
+        return Err(FirehoseError::InRawMode.into());
+    }
+    if let Some(op) = firehose_op_name(buf) {
+        channel.set_firehose_op(op);
+    }
     let mut b = buf.to_vec();
 
     // XML can't be n * 512 bytes long by fh spec
@@ -207,11 +332,15 @@ pub fn firehose_write<T: QdlChan>(channel: &mut T, buf: &mut [u8]) -> anyhow::Re
         b.push(b'\n');
     }
 
-    match channel.write_all(&b) {
-        Ok(_) => Ok(()),
-        // Assume FH will hang after NAK..
-        Err(_) => firehose_reset(channel, &FirehoseResetMode::ResetToEdl, 0),
-    }
+    // A timed out write is reported as such, resetting the device here
+    // would only hide it
+    channel.write_all(&b).map_err(|e| {
+        let what = match firehose_op_name(buf) {
+            Some(op) => format!("<{op}>"),
+            None => "the Firehose XML".to_owned(),
+        };
+        anyhow::Error::new(e).context(format!("Failed to send {what}"))
+    })
 }
 
 /// Send a "Hello"-type packet to the Device
@@ -245,7 +374,10 @@ pub fn firehose_configure<T: QdlChan>(
             ("MemoryName", &config.storage_type.to_string()),
             ("AlwaysValidate", &(config.hash_packets as u32).to_string()),
             ("Verbose", &(config.verbose_firehose as u32).to_string()),
-            ("MaxDigestTableSizeInBytes", "8192"), // TODO: (low prio)
+            (
+                "MaxDigestTableSizeInBytes",
+                &config.max_digest_table_size.to_string(),
+            ),
             (
                 "MaxPayloadSizeToTargetInBytes",
                 &config.send_buffer_size.to_string(),
@@ -258,13 +390,134 @@ pub fn firehose_configure<T: QdlChan>(
     firehose_write(channel, &mut xml)
 }
 
+/// Send a table of hashes for Validated Image Programming as raw data.
+/// Unlike XML, the table must go out as-is, even if it's n * 512 bytes long.
+fn firehose_send_digest_table<T: QdlChan>(
+    channel: &mut T,
+    table: &[u8],
+    what: &str,
+) -> anyhow::Result<()> {
+    if table.is_empty() {
+        bail!("The {what} is empty");
+    }
+    channel.set_firehose_op("digests");
+    channel.write_all(table)?;
+
+    // Send a Zero-Length Packet to indicate end of stream
+    if channel.fh_config().backend == QdlBackend::Usb && !channel.fh_config().skip_usb_zlp {
+        let _ = channel.write(&[])?;
+    }
+
+    if firehose_read::<T>(channel, firehose_parser_ack_nak)? != FirehoseStatus::Ack {
+        bail!("The {what} was rejected");
+    }
+    Ok(())
+}
+
+/// Send the signed digest table (e.g. DigestsToSign.bin.mbn), the loader
+/// authenticates it and then only accepts packets whose hashes it lists
+pub fn firehose_send_signed_digests<T: QdlChan>(
+    channel: &mut T,
+    table: &[u8],
+) -> anyhow::Result<()> {
+    firehose_send_digest_table(channel, table, "signed digest table")
+}
+
+/// Largest chained digest table sent in one go unless told otherwise. The
+/// loader only reports its own limit (MaxDigestTableSizeInBytes) in the
+/// \<configure\> response, after the VIP handshake, so this is what
+/// fh_loader assumes too.
+pub const FIREHOSE_DEFAULT_DIGEST_TABLE_SIZE: usize = 8192;
+
+/// Send the chained tables of digests (e.g. ChainedTableOfDigests.bin), no
+/// more than `max_digest_table_size` bytes at a time. Each table is vouched
+/// for by the last hash of the one before it.
+pub fn firehose_send_chained_digests<T: QdlChan>(
+    channel: &mut T,
+    tables: &[u8],
+) -> anyhow::Result<()> {
+    let max = channel.fh_config().max_digest_table_size;
+    if max == 0 {
+        bail!("The digest table size is 0");
+    }
+    for (idx, table) in tables.chunks(max).enumerate() {
+        firehose_send_digest_table(channel, table, &format!("chained digest table {idx}"))?;
+    }
+    Ok(())
+}
+
+/// Tell the loader what to do if the transfer stalls for `timeout_in_sec`
+pub fn firehose_transfer_cfg<T: QdlChan>(
+    channel: &mut T,
+    reboot_type: &str,
+    timeout_in_sec: u32,
+) -> anyhow::Result<()> {
+    let mut xml = firehose_xml_setup(
+        "transfercfg",
+        &[
+            ("reboot_type", reboot_type),
+            ("timeout_in_sec", &timeout_in_sec.to_string()),
+        ],
+    )?;
+
+    firehose_write_getack(
+        channel,
+        "transfercfg",
+        &mut xml,
+        "set the transfer config".to_owned(),
+    )
+}
+
+/// Turn Validated Image Programming on (or off) for the following packets
+pub fn firehose_verify_vip<T: QdlChan>(channel: &mut T, enable: bool) -> anyhow::Result<()> {
+    let mut xml = firehose_xml_setup(
+        "verify",
+        &[
+            ("value", "ping"),
+            ("EnableVip", &(enable as u32).to_string()),
+        ],
+    )?;
+
+    firehose_write_getack(channel, "verify", &mut xml, "enable VIP".to_owned())
+}
+
+/// Reset the running SHA256 the loader checks the digest tables against
+pub fn firehose_sha256_init<T: QdlChan>(channel: &mut T) -> anyhow::Result<()> {
+    // As in res/sha256init.xml, the packet the VIP tables were made against
+    let mut xml = firehose_xml_setup("sha256init", &[("Verbose", "1")])?;
+
+    firehose_write_getack(
+        channel,
+        "sha256init",
+        &mut xml,
+        "initialize SHA256".to_owned(),
+    )
+}
+
+/// The VIP handshake of authenticated (e.g. Oplus) loaders, to be run right
+/// after the loader comes up and before \<configure\>. An empty `chained`
+/// skips the chained tables.
+pub fn firehose_vip_handshake<T: QdlChan>(
+    channel: &mut T,
+    signed: &[u8],
+    chained: &[u8],
+) -> anyhow::Result<()> {
+    firehose_send_signed_digests(channel, signed)?;
+    firehose_transfer_cfg(channel, "off", 90)?;
+    firehose_verify_vip(channel, true)?;
+    if !chained.is_empty() {
+        firehose_send_chained_digests(channel, chained)?;
+    }
+    firehose_sha256_init(channel)
+}
+
 /// Test performance without sample data
-#[allow(dead_code)]
+/// The device answers through \<log\> only, see `firehose_parse_benchmark`
 pub fn firehose_benchmark<T: QdlChan>(
     channel: &mut T,
     trials: u32,
     test_write_perf: bool,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<BenchmarkResult> {
     let mut xml = firehose_xml_setup(
         "benchmark",
         &[
@@ -280,36 +533,128 @@ pub fn firehose_benchmark<T: QdlChan>(
         ],
     )?;
 
-    firehose_write_getack(channel, &mut xml, "issue a NOP".to_owned())
+    // The loader moves MaxPayloadSizeToTargetInBytes at a time, and only
+    // answers once every trial is done
+    let payload_size = channel.fh_config().send_buffer_size;
+    let bytes = payload_size.saturating_mul(trials as usize);
+    let logs = firehose_with_xfer_timeout(channel, bytes, |channel| {
+        firehose_with_retry(channel, "benchmark", |channel| {
+            firehose_write(channel, &mut xml)?;
+
+            let mut logs = Vec::new();
+            if firehose_read_logs::<T>(channel, firehose_parser_ack_nak, &mut logs)?
+                != FirehoseStatus::Ack
+            {
+                return Err(FirehoseError::Nak("<benchmark> was NAKed".to_owned()).into());
+            }
+            Ok(logs)
+        })
+    })?;
+
+    let (time_us, reported) = firehose_parse_benchmark(trials, &logs);
+    let throughput_mbps = reported.or(match time_us {
+        Some(us) if us > 0 => Some((payload_size as u64 * trials as u64) as f64 / us as f64),
+        _ => None,
+    });
+    Ok(BenchmarkResult {
+        operation: match test_write_perf {
+            true => "write",
+            false => "read",
+        }
+        .to_owned(),
+        payload_size,
+        trials,
+        time_us,
+        throughput_mbps,
+    })
+}
+
+/// Benchmark reads and writes with each of `payload_sizes`, by reconfiguring
+/// the loader's payload size for each of them. Sizes past the negotiated one
+/// can't be used and are left out, the others are rounded up to whole
+/// sectors. No sizes means every power of two from a sector up to the
+/// negotiated size. The negotiated size is restored afterwards.
+pub fn firehose_benchmark_sweep<T: QdlChan>(
+    channel: &mut T,
+    trials: u32,
+    payload_sizes: &[usize],
+) -> anyhow::Result<Vec<BenchmarkResult>> {
+    let negotiated = channel.fh_config().send_buffer_size;
+    let sector_size = channel.fh_config().storage_sector_size;
+    let payload_sizes = match payload_sizes.is_empty() {
+        true => std::iter::successors(Some(sector_size), |size| Some(size * 2))
+            .take_while(|&size| size <= negotiated)
+            .collect(),
+        false => payload_sizes.to_vec(),
+    };
+
+    let mut results = Vec::new();
+    let mut ret = Ok(());
+    for size in payload_sizes {
+        let size = size.next_multiple_of(sector_size);
+        if size == 0 || size > negotiated {
+            continue;
+        }
+        ret = firehose_set_payload_size(channel, size).and_then(|_| {
+            results.push(firehose_benchmark(channel, trials, true)?);
+            results.push(firehose_benchmark(channel, trials, false)?);
+            Ok(())
+        });
+        if ret.is_err() {
+            break;
+        }
+    }
+
+    let restored = firehose_set_payload_size(channel, negotiated);
+    ret.and(restored)?;
+    Ok(results)
+}
+
+/// Reconfigure the loader to take `size` bytes at a time, without the
+/// renegotiation `firehose_parser_configure_response` would do
+fn firehose_set_payload_size<T: QdlChan>(channel: &mut T, size: usize) -> anyhow::Result<()> {
+    channel.mut_fh_config().send_buffer_size = size;
+    firehose_configure(channel, true)?;
+    firehose_expect_ack(channel, || {
+        format!("Couldn't set the payload size to {size}")
+    })
 }
 
 /// Do nothing, hopefully succesfully
-#[allow(dead_code)]
 pub fn firehose_nop<T: QdlChan>(channel: &mut T) -> anyhow::Result<()> {
     let mut xml = firehose_xml_setup("nop", &[("value", "ping")])?;
 
-    firehose_write_getack(channel, &mut xml, "issue a NOP".to_owned())
+    firehose_write_getack(channel, "nop", &mut xml, "issue a NOP".to_owned())
 }
 
 /// Get information about the physical partition of a storage medium (e.g. LUN)
-/// Prints to \<log\> only
-#[allow(dead_code)]
+/// The device answers through \<log\> only, see `firehose_parse_storage_info`
 pub fn firehose_get_storage_info<T: QdlChan>(
     channel: &mut T,
     phys_part_idx: u8,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<StorageInfo> {
     let mut xml = firehose_xml_setup(
         "getstorageinfo",
         &[("physical_partition_number", &phys_part_idx.to_string())],
     )?;
 
-    firehose_write(channel, &mut xml)?;
+    let logs = firehose_with_retry(channel, "getstorageinfo", |channel| {
+        firehose_write(channel, &mut xml)?;
+
+        let mut logs = Vec::new();
+        if firehose_read_logs::<T>(channel, firehose_parser_ack_nak, &mut logs)?
+            != FirehoseStatus::Ack
+        {
+            let msg = format!("<getstorageinfo> of LUN {phys_part_idx} was NAKed");
+            return Err(FirehoseError::Nak(msg).into());
+        }
+        Ok(logs)
+    })?;
 
-    firehose_read::<T>(channel, firehose_parser_ack_nak).and(Ok(()))
+    Ok(firehose_parse_storage_info(phys_part_idx, &logs))
 }
 
 /// Alter Device (TODO: or Host) storage
-#[allow(dead_code)]
 pub fn firehose_patch<T: QdlChan>(
     channel: &mut T,
     byte_off: u64,
@@ -336,25 +681,19 @@ pub fn firehose_patch<T: QdlChan>(
         ],
     )?;
 
-    firehose_write_getack(channel, &mut xml, "patch".to_string())
+    firehose_write_getack(channel, "patch", &mut xml, "patch".to_string())
 }
 
+/// Bytes asked for per \<peek\>, the device logs them 16 or so per message
+pub const FIREHOSE_PEEK_CHUNK_SIZE: u64 = 4096;
+
 /// Peek at memory
-/// Prints to \<log\> only
-#[allow(dead_code)]
+/// The device answers through \<log\> only, see `firehose_parse_peek_bytes`
 pub fn firehose_peek<T: QdlChan>(
     channel: &mut T,
     addr: u64,
     byte_count: u64,
-) -> anyhow::Result<()> {
-    if channel.fh_config().skip_firehose_log {
-        println!(
-            "{}",
-            "Warning: firehose <peek> only prints to <log>, remove --skip-firehose-log"
-                .bright_red()
-        );
-    }
-
+) -> anyhow::Result<Vec<u8>> {
     let mut xml: Vec<u8> = firehose_xml_setup(
         "peek",
         &[
@@ -363,7 +702,54 @@ pub fn firehose_peek<T: QdlChan>(
         ],
     )?;
 
-    firehose_write_getack(channel, &mut xml, format!("peek @ {addr:#x}"))
+    let logs = firehose_with_retry(channel, "peek", |channel| {
+        firehose_write(channel, &mut xml)?;
+
+        let mut logs = Vec::new();
+        if firehose_read_logs::<T>(channel, firehose_parser_ack_nak, &mut logs)?
+            != FirehoseStatus::Ack
+        {
+            return Err(FirehoseError::Nak(format!("<peek> @ {addr:#x} was NAKed")).into());
+        }
+        Ok(logs)
+    })?;
+
+    let data = firehose_parse_peek_bytes(&logs);
+    if data.len() as u64 != byte_count {
+        bail!(
+            "<peek> @ {addr:#x} returned {} bytes instead of {byte_count}",
+            data.len()
+        );
+    }
+    Ok(data)
+}
+
+/// Progress callback of `firehose_dump_memory`: bytes dumped, total
+pub type PeekProgress<'a> = &'a mut dyn FnMut(u64, u64);
+
+/// Dump `len` bytes of memory from `addr` into `out` through successive
+/// \<peek\>s. The first `offset` bytes are skipped, for resuming a dump
+/// whose beginning `out` already holds.
+pub fn firehose_dump_memory<T: QdlChan>(
+    channel: &mut T,
+    addr: u64,
+    len: u64,
+    offset: u64,
+    out: &mut impl Write,
+    progress: PeekProgress,
+) -> anyhow::Result<()> {
+    let mut done = min(offset, len);
+    progress(done, len);
+    while done < len {
+        let count = min(FIREHOSE_PEEK_CHUNK_SIZE, len - done);
+        let data = firehose_peek(channel, addr + done, count)?;
+        out.write_all(&data)?;
+        done += count;
+        progress(done, len);
+    }
+    out.flush()?;
+
+    Ok(())
 }
 
 /// Poke at memory
@@ -386,11 +772,10 @@ pub fn firehose_poke<T: QdlChan>(
         ],
     )?;
 
-    firehose_write_getack(channel, &mut xml, format!("peek @ {addr:#x}"))
+    firehose_write_getack(channel, "poke", &mut xml, format!("peek @ {addr:#x}"))
 }
 
 /// Write to Device storage
-#[allow(dead_code)]
 pub fn firehose_program_storage<T: QdlChan>(
     channel: &mut T,
     data: &mut impl Read,
@@ -399,6 +784,44 @@ pub fn firehose_program_storage<T: QdlChan>(
     slot: u8,
     phys_part_idx: u8,
     start_sector: &str,
+) -> anyhow::Result<()> {
+    let bytes = num_sectors * channel.fh_config().storage_sector_size;
+    firehose_with_xfer_timeout(channel, bytes, |channel| {
+        firehose_program_storage_inner(
+            channel,
+            data,
+            label,
+            num_sectors,
+            slot,
+            phys_part_idx,
+            start_sector,
+        )
+    })
+}
+
+/// Fill `buf` from `data`, which may hand out less than asked for (e.g. at
+/// sparse chunk boundaries). Whatever lies past its end is left zeroed.
+fn fill_buf(data: &mut impl Read, buf: &mut [u8]) -> std::io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match data.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn firehose_program_storage_inner<T: QdlChan>(
+    channel: &mut T,
+    data: &mut impl Read,
+    label: &str,
+    num_sectors: usize,
+    slot: u8,
+    phys_part_idx: u8,
+    start_sector: &str,
 ) -> anyhow::Result<()> {
     let mut sectors_left = num_sectors;
     let mut xml = firehose_xml_setup(
@@ -419,11 +842,13 @@ pub fn firehose_program_storage<T: QdlChan>(
         ],
     )?;
 
-    firehose_write(channel, &mut xml)?;
-
-    if firehose_read::<T>(channel, firehose_parser_ack_nak)? != FirehoseStatus::Ack {
-        bail!("<program> was NAKed. Did you set sector-size correctly?");
-    }
+    // Nothing was sent yet if the command itself is NAKed, it can be retried
+    firehose_with_retry(channel, "program", |channel| {
+        firehose_write(channel, &mut xml)?;
+        firehose_expect_ack(channel, || {
+            "<program> was NAKed. Did you set sector-size correctly?".to_owned()
+        })
+    })?;
 
     let mut pb = ProgressBar::new((sectors_left * channel.fh_config().storage_sector_size) as u64);
     pb.show_time_left = true;
@@ -442,9 +867,9 @@ pub fn firehose_program_storage<T: QdlChan>(
                 chunk_size_sectors * channel.fh_config().storage_sector_size,
             )
         ];
-        let _ = data.read(&mut buf).unwrap();
+        fill_buf(data, &mut buf)?;
 
-        let n = channel.write(&buf).expect("Error sending data");
+        let n = channel.write(&buf)?;
         if n != chunk_size_sectors * channel.fh_config().storage_sector_size {
             bail!("Wrote an unexpected number of bytes ({})", n);
         }
@@ -455,7 +880,7 @@ pub fn firehose_program_storage<T: QdlChan>(
 
     // Send a Zero-Length Packet to indicate end of stream
     if channel.fh_config().backend == QdlBackend::Usb && !channel.fh_config().skip_usb_zlp {
-        let _ = channel.write(&[]).expect("Error sending ZLP");
+        let _ = channel.write(&[])?;
     }
 
     if firehose_read::<T>(channel, firehose_parser_ack_nak)? != FirehoseStatus::Ack {
@@ -465,14 +890,86 @@ pub fn firehose_program_storage<T: QdlChan>(
     Ok(())
 }
 
+/// Erase (discard) a range of Device storage
+pub fn firehose_erase<T: QdlChan>(
+    channel: &mut T,
+    num_sectors: usize,
+    slot: u8,
+    phys_part_idx: u8,
+    start_sector: &str,
+) -> anyhow::Result<()> {
+    firehose_clear_storage(
+        channel,
+        "erase",
+        num_sectors,
+        slot,
+        phys_part_idx,
+        start_sector,
+    )
+}
+
+/// Overwrite a range of Device storage with zeroes, unlike \<erase\> this
+/// doesn't depend on the medium reading back discarded blocks as zero
+pub fn firehose_zeroout<T: QdlChan>(
+    channel: &mut T,
+    num_sectors: usize,
+    slot: u8,
+    phys_part_idx: u8,
+    start_sector: &str,
+) -> anyhow::Result<()> {
+    firehose_clear_storage(
+        channel,
+        "zeroout",
+        num_sectors,
+        slot,
+        phys_part_idx,
+        start_sector,
+    )
+}
+
+fn firehose_clear_storage<T: QdlChan>(
+    channel: &mut T,
+    op: &str,
+    num_sectors: usize,
+    slot: u8,
+    phys_part_idx: u8,
+    start_sector: &str,
+) -> anyhow::Result<()> {
+    let mut xml = firehose_xml_setup(
+        op,
+        &[
+            (
+                "SECTOR_SIZE_IN_BYTES",
+                &channel.fh_config().storage_sector_size.to_string(),
+            ),
+            ("num_partition_sectors", &num_sectors.to_string()),
+            ("slot", &slot.to_string()),
+            ("physical_partition_number", &phys_part_idx.to_string()),
+            ("start_sector", start_sector),
+        ],
+    )?;
+
+    let bytes = num_sectors * channel.fh_config().storage_sector_size;
+    firehose_with_retry(channel, op, |channel| {
+        firehose_write(channel, &mut xml)?;
+
+        // The device only answers once the whole range is done
+        firehose_with_xfer_timeout(channel, bytes, |channel| {
+            firehose_expect_ack(channel, || {
+                format!("<{op}> of {num_sectors} sectors at {start_sector} was NAKed")
+            })
+        })
+    })
+}
+
 /// Get a SHA256 digest of a portion of Device storage
-#[allow(dead_code)]
+/// The device answers through \<log\> only, see `firehose_parse_sha256_digest`
 pub fn firehose_checksum_storage<T: QdlChan>(
     channel: &mut T,
     num_sectors: usize,
     phys_part_idx: u8,
-    start_sector: u32,
-) -> anyhow::Result<()> {
+    start_sector: &str,
+) -> anyhow::Result<[u8; 32]> {
     let mut xml = firehose_xml_setup(
         "getsha256digest",
         &[
@@ -482,22 +979,31 @@ pub fn firehose_checksum_storage<T: QdlChan>(
             ),
             ("num_partition_sectors", &num_sectors.to_string()),
             ("physical_partition_number", &phys_part_idx.to_string()),
-            ("start_sector", &start_sector.to_string()),
+            ("start_sector", start_sector),
         ],
     )?;
 
-    firehose_write(channel, &mut xml)?;
-
-    // TODO: figure out some sane way to figure out the timeout
-    if firehose_read::<T>(channel, firehose_parser_ack_nak)? != FirehoseStatus::Ack {
-        bail!("Checksum request was NAKed");
-    }
+    let bytes = num_sectors * channel.fh_config().storage_sector_size;
+    let logs = firehose_with_retry(channel, "getsha256digest", |channel| {
+        firehose_write(channel, &mut xml)?;
+
+        // The device hashes the whole range before answering
+        let mut logs = Vec::new();
+        firehose_with_xfer_timeout(channel, bytes, |channel| {
+            if firehose_read_logs::<T>(channel, firehose_parser_ack_nak, &mut logs)?
+                != FirehoseStatus::Ack
+            {
+                return Err(FirehoseError::Nak("Checksum request was NAKed".to_owned()).into());
+            }
+            Ok(())
+        })?;
+        Ok(logs)
+    })?;
 
-    Ok(())
+    firehose_parse_sha256_digest(&logs).ok_or_else(|| anyhow::anyhow!("The device sent no digest"))
 }
 
 /// Read (sector-aligned) parts of storage.
-#[allow(dead_code)]
 pub fn firehose_read_storage(
     channel: &mut impl QdlChan,
     out: &mut impl Write,
@@ -505,6 +1011,20 @@ pub fn firehose_read_storage(
     slot: u8,
     phys_part_idx: u8,
     start_sector: u32,
+) -> anyhow::Result<()> {
+    let bytes = num_sectors * channel.fh_config().storage_sector_size;
+    firehose_with_xfer_timeout(channel, bytes, |channel| {
+        firehose_read_storage_inner(channel, out, num_sectors, slot, phys_part_idx, start_sector)
+    })
+}
+
+fn firehose_read_storage_inner(
+    channel: &mut impl QdlChan,
+    out: &mut impl Write,
+    num_sectors: usize,
+    slot: u8,
+    phys_part_idx: u8,
+    start_sector: u32,
 ) -> anyhow::Result<()> {
     let mut bytes_left = num_sectors * channel.fh_config().storage_sector_size;
     let mut xml = firehose_xml_setup(
@@ -521,10 +1041,11 @@ pub fn firehose_read_storage(
         ],
     )?;
 
-    firehose_write(channel, &mut xml)?;
-    if firehose_read(channel, firehose_parser_ack_nak)? != FirehoseStatus::Ack {
-        bail!("Read request was NAKed");
-    }
+    // Nothing was received yet if the command itself is NAKed
+    firehose_with_retry(channel, "read", |channel| {
+        firehose_write(channel, &mut xml)?;
+        firehose_expect_ack(channel, || "Read request was NAKed".to_owned())
+    })?;
 
     let mut pb = ProgressBar::new(bytes_left as u64);
     pb.set_units(Units::Bytes);
@@ -534,7 +1055,7 @@ pub fn firehose_read_storage(
         let chunk_size_bytes = min(bytes_left, channel.fh_config().recv_buffer_size);
         let mut buf = vec![0; chunk_size_bytes];
 
-        let n = channel.read(&mut buf).expect("Error receiving data");
+        let n = channel.read(&mut buf)?;
         if n == 0 {
             // TODO: need more robustness here
             /* Every 2 or 3 packets should be empty? */
@@ -562,7 +1083,6 @@ pub fn firehose_read_storage(
 }
 
 /// Mark a physical storage partition as bootable
-#[allow(dead_code)]
 pub fn firehose_set_bootable<T: QdlChan>(channel: &mut T, drive_idx: u8) -> anyhow::Result<()> {
     let mut xml = firehose_xml_setup(
         "setbootablestoragedrive",
@@ -571,11 +1091,33 @@ pub fn firehose_set_bootable<T: QdlChan>(channel: &mut T, drive_idx: u8) -> anyh
 
     firehose_write_getack(
         channel,
+        "setbootablestoragedrive",
         &mut xml,
         format!("set partition {drive_idx} as bootable"),
     )
 }
 
+/// Extra time the loader is given to answer a `commit="1"` \<ufs\> tag,
+/// which rewrites the device's configuration descriptors
+const FIREHOSE_UFS_COMMIT_TIME: Duration = Duration::from_secs(60);
+
+/// Send one `<ufs>` provisioning tag, `what` it provisions goes in errors
+pub fn firehose_ufs<T: QdlChan>(
+    channel: &mut T,
+    attrs: &[(&str, &str)],
+    what: &str,
+) -> anyhow::Result<()> {
+    let mut xml = firehose_xml_setup("ufs", attrs)?;
+
+    if !attrs.contains(&("commit", "1")) {
+        return firehose_write_getack(channel, "ufs", &mut xml, format!("provision {what}"));
+    }
+    let timeout = channel.fh_config().xfer_timeout(0) + FIREHOSE_UFS_COMMIT_TIME;
+    firehose_with_timeout(channel, timeout, |channel| {
+        firehose_write_getack(channel, "ufs", &mut xml, format!("provision {what}"))
+    })
+}
+
 #[allow(dead_code)]
 pub fn firehose_get_default_sector_size(t: &str) -> Option<usize> {
     match FirehoseStorageType::from_str(t).unwrap() {
@@ -1,64 +1,198 @@
+pub mod capture;
 pub mod firehose;
 pub mod parsers;
+pub mod ramdump;
 pub mod sahara;
 pub mod serial;
+pub mod sim;
+pub mod sparse;
 pub mod types;
-use crate::qdl::firehose::{firehose_configure, firehose_read};
+pub mod usb;
+use crate::qdl::capture::{CAPTURE_MAX_PAYLOAD, QdlRecorder, setup_replay_device};
+use crate::qdl::firehose::{firehose_configure, firehose_read, firehose_vip_handshake};
 use crate::qdl::parsers::{firehose_parser_ack_nak, firehose_parser_configure_response};
+use crate::qdl::ramdump::{RamdumpFormat, dump_ramdump};
 use crate::qdl::sahara::SaharaCmdModeCmd;
 use crate::qdl::sahara::SaharaMode;
-use crate::qdl::sahara::sahara_run;
-use crate::qdl::serial::QdlSerialConfig;
+use crate::qdl::sahara::{RamdumpProgress, RamdumpTable64, sahara_get_ramdump_regions};
+use crate::qdl::sahara::{SAHARA_ANY_IMAGE_ID, SAHARA_PROGRAMMER_IMAGE_ID, SaharaImages};
+use crate::qdl::sahara::{SaharaError, sahara_reset_state, sahara_run};
 use crate::qdl::serial::setup_serial_device;
-use crate::qdl::types::FirehoseConfiguration;
+use crate::qdl::sim::setup_sim_device;
+use crate::qdl::types::{
+    FirehoseConfiguration, FirehoseError, FirehoseLogSink, QdlBackend, QdlChan, QdlReadWrite,
+};
+use crate::qdl::usb::setup_usb_device;
 use itertools::Itertools;
+use serde::Serialize;
+use std::collections::HashMap;
 use std::fs;
-use std::io;
+use std::path::Path;
+use std::time::Duration;
 use types::QdlDevice;
 
+/// Sahara HWID: the SoC's JTAG ID in the upper 32 bits, the OEM ID and the
+/// model ID in the lower ones
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HwId(pub u64);
+
+impl HwId {
+    /// JTAG ID without its version nibble, e.g. 0x22a0e1
+    pub fn msm_id(&self) -> u32 {
+        ((self.0 >> 32) & 0x00ff_ffff) as u32
+    }
+
+    pub fn oem_id(&self) -> u16 {
+        (self.0 >> 16) as u16
+    }
+
+    pub fn model_id(&self) -> u16 {
+        self.0 as u16
+    }
+}
+
 pub struct SaharaClient {
     chip_sn: String,
+    hw_id: Option<HwId>,
     oem_key_hash: String,
-    channel: QdlDevice<QdlSerialConfig>,
+    channel: QdlDevice<dyn QdlReadWrite>,
+
+    // What it takes to bring the loader up again, see recover()
+    backend: QdlBackend,
+    dev_path: Option<String>,
+    images: HashMap<u64, String>,
+    vip: Option<(Vec<u8>, Vec<u8>)>,
 }
 
-impl SaharaClient {
-    pub fn new(dev_path: Option<String>) -> Result<Self, String> {
-        // Set up the device
-        let rw_channel = match setup_serial_device(dev_path) {
+/// How long recover() waits for the device to come back in EDL
+const RECOVER_REOPEN_ATTEMPTS: u32 = 10;
+const RECOVER_REOPEN_DELAY: Duration = Duration::from_secs(1);
+
+/// Open the transport picked by `backend` and wrap it into a channel
+fn open_channel(
+    backend: QdlBackend,
+    dev_path: Option<String>,
+    capture_path: Option<String>,
+) -> Result<QdlDevice<dyn QdlReadWrite>, String> {
+    // Set up the device
+    let rw_channel: Box<dyn QdlReadWrite> = match backend {
+        QdlBackend::Serial => match setup_serial_device(dev_path) {
             Ok(config) => Box::new(config),
             Err(e) => return Err(format!("Failed to setup serial device: {}", e)),
+        },
+        QdlBackend::Usb => match setup_usb_device(dev_path) {
+            Ok(config) => Box::new(config),
+            Err(e) => return Err(format!("Failed to setup USB device: {}", e)),
+        },
+        QdlBackend::Sim => match setup_sim_device(dev_path) {
+            Ok(config) => Box::new(config),
+            Err(e) => return Err(format!("Failed to setup simulated device: {}", e)),
+        },
+        QdlBackend::Replay => match setup_replay_device(dev_path) {
+            Ok(config) => Box::new(config),
+            Err(e) => return Err(format!("Failed to load capture: {}", e)),
+        },
+    };
+    let rw_channel: Box<dyn QdlReadWrite> = match capture_path {
+        Some(path) => match QdlRecorder::new(rw_channel, &path, Some(CAPTURE_MAX_PAYLOAD)) {
+            Ok(recorder) => Box::new(recorder),
+            Err(e) => return Err(format!("Failed to create capture {}: {}", path, e)),
+        },
+        None => rw_channel,
+    };
+    let mut qdl_dev = QdlDevice {
+        rw: rw_channel,
+        fh_cfg: FirehoseConfiguration {
+            backend,
+            // The native backend flashes for real
+            bypass_storage: false,
+            ..Default::default()
+        },
+        reset_on_drop: false,
+        log_sink: None,
+        fh_op: String::new(),
+    };
+    let (read, write) = (qdl_dev.fh_cfg.read_timeout, qdl_dev.fh_cfg.write_timeout);
+    if let Err(e) = qdl_dev.set_timeouts(read, write) {
+        return Err(format!("Failed to set channel timeouts: {}", e));
+    }
+    Ok(qdl_dev)
+}
+
+impl SaharaClient {
+    /// `dev_path` is the serial port for `QdlBackend::Serial`, an optional
+    /// USB serial number or bus location for `QdlBackend::Usb`, the
+    /// directory holding the `lunN.img` files for `QdlBackend::Sim` or the
+    /// capture file to play back for `QdlBackend::Replay`. With
+    /// `capture_path` set, the session is recorded there.
+    pub fn new(
+        backend: QdlBackend,
+        dev_path: Option<String>,
+        capture_path: Option<String>,
+    ) -> Result<Self, String> {
+        let mut qdl_dev = open_channel(backend, dev_path.clone(), capture_path)?;
+        // Get some info about the device
+        let read_sn = |qdl_dev: &mut QdlDevice<dyn QdlReadWrite>| {
+            sahara_run(
+                qdl_dev,
+                SaharaMode::Command,
+                Some(SaharaCmdModeCmd::ReadSerialNum),
+                &SaharaImages::new(),
+                vec![],
+                true,
+            )
         };
-        let mut qdl_dev = QdlDevice {
-            rw: rw_channel,
-            fh_cfg: FirehoseConfiguration::default(),
-            reset_on_drop: false,
+        let output = match read_sn(&mut qdl_dev) {
+            // A device left half way through an earlier session won't say
+            // Hello again by itself, restart its state machine
+            Err(SaharaError::Timeout) => {
+                sahara_reset_state(&mut qdl_dev).and_then(|_| read_sn(&mut qdl_dev))
+            }
+            output => output,
         };
-        // Get some info about the device
-        let output = sahara_run(
-            &mut qdl_dev,
-            SaharaMode::Command,
-            Some(SaharaCmdModeCmd::ReadSerialNum),
-            &mut [],
-            vec![],
-            true,
-        );
         let sn = match output {
-            Ok(result) => result,
+            Ok(result) if result.len() >= 4 => result,
+            Ok(_) => return Err("Failed to get serial from device: short response".to_string()),
             Err(e) => return Err(format!("Failed to get serial from device: {}", e)),
         };
         let sn = u32::from_le_bytes([sn[0], sn[1], sn[2], sn[3]]);
         println!("Chip serial number: 0x{sn:x}");
 
+        // Not every PBL answers this one, it's only informative anyway
+        let hw_id = match sahara_run(
+            &mut qdl_dev,
+            SaharaMode::Command,
+            Some(SaharaCmdModeCmd::ReadHwId),
+            &SaharaImages::new(),
+            vec![],
+            true,
+        ) {
+            Ok(result) if result.len() >= 8 => {
+                let hw_id = HwId(u64::from_le_bytes(
+                    result[..8].try_into().unwrap_or_default(),
+                ));
+                println!("HW ID: 0x{:016x}", hw_id.0);
+                Some(hw_id)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                eprintln!("Failed to get HW ID from device: {}", e);
+                None
+            }
+        };
+
         let key_hash = sahara_run(
             &mut qdl_dev,
             SaharaMode::Command,
             Some(SaharaCmdModeCmd::ReadOemKeyHash),
-            &mut [],
+            &SaharaImages::new(),
             vec![],
             true,
-        )
-        .unwrap();
+        );
+        let key_hash = match key_hash {
+            Ok(result) => result,
+            Err(e) => return Err(format!("Failed to get OEM key hash from device: {}", e)),
+        };
         println!(
             "OEM Private Key hash: 0x{:02x}",
             key_hash[..key_hash.len() / 3].iter().format("")
@@ -66,8 +200,13 @@ impl SaharaClient {
 
         Ok(Self {
             chip_sn: format!("0x{sn:x}"),
+            hw_id,
             oem_key_hash: format!("0x{:02x}", key_hash[..key_hash.len() / 3].iter().format("")),
             channel: qdl_dev,
+            backend,
+            dev_path,
+            images: HashMap::new(),
+            vip: None,
         })
     }
 
@@ -79,37 +218,341 @@ impl SaharaClient {
         return self.oem_key_hash.clone();
     }
 
-    pub fn send_loader(&mut self, loader_path: &str) {
-        // Get the MBN loader binary
-        let mbn_loader: Result<Vec<u8>, io::Error> = fs::read(loader_path);
+    /// None if the device didn't answer ReadHwId
+    pub fn get_hw_id(&self) -> Option<HwId> {
+        self.hw_id
+    }
+
+    /// Channel-wide Firehose timeouts, and the extra time granted per MiB
+    /// moved by program/read/digest operations
+    pub fn set_timeouts(
+        &mut self,
+        read: Duration,
+        write: Duration,
+        per_mib: Duration,
+    ) -> Result<(), String> {
+        self.channel.fh_cfg.read_timeout = read;
+        self.channel.fh_cfg.write_timeout = write;
+        self.channel.fh_cfg.xfer_timeout_per_mib = per_mib;
+        self.channel
+            .set_timeouts(read, write)
+            .map_err(|e| format!("Failed to set channel timeouts: {}", e))
+    }
+
+    /// Send the chained VIP digest tables `size` bytes at a time, for
+    /// loaders that don't take `FIREHOSE_DEFAULT_DIGEST_TABLE_SIZE`
+    pub fn set_digest_table_size(&mut self, size: usize) {
+        self.channel.fh_cfg.max_digest_table_size = size;
+    }
+
+    /// Send the loader's \<log\> messages to `sink` rather than stdout
+    pub fn set_log_sink(&mut self, sink: FirehoseLogSink) {
+        self.channel.log_sink = Some(sink);
+    }
+
+    /// Raw access to the underlying Sahara/Firehose channel
+    #[allow(dead_code)]
+    pub fn channel(&mut self) -> &mut QdlDevice<dyn QdlReadWrite> {
+        &mut self.channel
+    }
+
+    /// Send a single programmer, under whatever image ID the PBL asks for
+    pub fn send_loader(&mut self, loader_path: &str) -> Result<(), String> {
+        let images = HashMap::from([(SAHARA_ANY_IMAGE_ID, loader_path.to_string())]);
+        self.send_images(&images)
+    }
+
+    /// Serve every image the device asks for by ID, then bring up the
+    /// Firehose programmer among them
+    pub fn send_images(&mut self, image_paths: &HashMap<u64, String>) -> Result<(), String> {
+        self.send_images_inner(image_paths, None)
+    }
+
+    /// Like `send_images`, for loaders that only take Validated Image
+    /// Programming: the signed digest table, and the chained ones if
+    /// `chained_path` isn't empty, are sent before \<configure\>
+    pub fn send_images_vip(
+        &mut self,
+        image_paths: &HashMap<u64, String>,
+        signed_path: &str,
+        chained_path: &str,
+    ) -> Result<(), String> {
+        let signed = match fs::read(signed_path) {
+            Ok(data) => data,
+            Err(e) => return Err(format!("Couldn't open digests {}: {}", signed_path, e)),
+        };
+        let chained = match chained_path.is_empty() {
+            true => Vec::new(),
+            false => match fs::read(chained_path) {
+                Ok(data) => data,
+                Err(e) => return Err(format!("Couldn't open digests {}: {}", chained_path, e)),
+            },
+        };
+        self.send_images_inner(image_paths, Some((&signed, &chained)))
+    }
+
+    fn send_images_inner(
+        &mut self,
+        image_paths: &HashMap<u64, String>,
+        vip: Option<(&[u8], &[u8])>,
+    ) -> Result<(), String> {
+        self.images = image_paths.clone();
+        self.vip = vip.map(|(signed, chained)| (signed.to_vec(), chained.to_vec()));
+
+        let mut images = SaharaImages::new();
+        for (id, path) in image_paths {
+            match fs::read(path) {
+                Ok(data) => {
+                    images.insert(*id, data);
+                }
+                Err(e) => {
+                    eprintln!("Couldn't open image {} ({}): {}", id, path, e);
+                    return Err(format!("Couldn't open image {} ({}): {}", id, path, e));
+                }
+            }
+        }
+
+        // Send the loader (and any other images)
+        if let Err(e) = sahara_run(
+            &mut self.channel,
+            SaharaMode::WaitingForImage,
+            None,
+            &images,
+            vec![],
+            true,
+        ) {
+            return Err(format!("Failed to send the loader: {}", e));
+        }
+
+        // If we're past Sahara, activate the Firehose reset-on-drop listener
+        //self.channel.reset_on_drop = true;
+
+        // Get any "welcome" logs, a silent loader simply times out here
+        let _ = firehose_read(&mut self.channel, firehose_parser_ack_nak);
 
-        match mbn_loader {
-            Ok(data) => {
-                // Send the loader (and any other images)
-                let _ = sahara_run(
-                    &mut self.channel,
-                    SaharaMode::WaitingForImage,
-                    None,
-                    &mut [data],
-                    vec![],
-                    true,
-                );
+        if let Some((signed, chained)) = vip
+            && let Err(e) = firehose_vip_handshake(&mut self.channel, signed, chained)
+        {
+            return Err(format!("VIP handshake failed: {}", e));
+        }
 
-                // If we're past Sahara, activate the Firehose reset-on-drop listener
-                //self.channel.reset_on_drop = true;
+        // Send the host capabilities to the device
+        if let Err(e) = firehose_configure(&mut self.channel, true) {
+            return Err(format!("Failed to configure the loader: {}", e));
+        }
 
-                // Get any "welcome" logs
-                let _ = firehose_read(&mut self.channel, firehose_parser_ack_nak);
+        // Parse some information from the device
+        if let Err(e) = firehose_read(&mut self.channel, firehose_parser_configure_response) {
+            return Err(format!("Loader didn't answer <configure>: {}", e));
+        }
+        Ok(())
+    }
 
-                // Send the host capabilities to the device
-                let _ = firehose_configure(&mut self.channel, true);
+    /// Bring the loader up again after it asked for a restart (and got reset
+    /// to EDL): wait for the device to come back, then go through Sahara,
+    /// the VIP tables and \<configure\> with what was sent the first time.
+    /// The settings of the channel are kept, a capture isn't resumed.
+    pub fn recover(&mut self) -> Result<(), String> {
+        if self.images.is_empty() {
+            return Err("No loader was sent yet, nothing to restart".to_string());
+        }
 
-                // Parse some information from the device
-                let _ = firehose_read(&mut self.channel, firehose_parser_configure_response);
+        let mut attempt = 0;
+        let mut channel = loop {
+            attempt += 1;
+            match open_channel(self.backend, self.dev_path.clone(), None) {
+                Ok(channel) => break channel,
+                Err(e) if attempt == RECOVER_REOPEN_ATTEMPTS => {
+                    return Err(format!("Device didn't come back in EDL: {}", e));
+                }
+                Err(_) => std::thread::sleep(RECOVER_REOPEN_DELAY),
             }
-            Err(e) => {
-                eprintln!("Couldn't open the programmer binary: {}", e);
+        };
+        channel.fh_cfg = FirehoseConfiguration {
+            rawmode: false,
+            ..self.channel.fh_cfg
+        };
+        channel.log_sink = self.channel.log_sink.take();
+        let (read, write) = (channel.fh_cfg.read_timeout, channel.fh_cfg.write_timeout);
+        if let Err(e) = channel.set_timeouts(read, write) {
+            return Err(format!("Failed to set channel timeouts: {}", e));
+        }
+        self.channel = channel;
+
+        let images = self.images.clone();
+        let vip = self.vip.clone();
+        self.send_images_inner(
+            &images,
+            vip.as_ref()
+                .map(|(signed, chained)| (signed.as_slice(), chained.as_slice())),
+        )
+    }
+
+    /// Run `op` on the channel. If the loader asks for a restart midway, it
+    /// is brought up again with `recover()` and `op` runs once more.
+    pub fn with_recovery<R>(
+        &mut self,
+        mut op: impl FnMut(&mut QdlDevice<dyn QdlReadWrite>) -> anyhow::Result<R>,
+    ) -> anyhow::Result<R> {
+        match op(&mut self.channel) {
+            Err(e) if matches!(e.downcast_ref(), Some(FirehoseError::RestartRequested)) => {
+                println!("Firehose requested a restart, loading it again");
+                self.recover().map_err(anyhow::Error::msg)?;
+                op(&mut self.channel)
+            }
+            ret => ret,
+        }
+    }
+}
+
+/// Parse a QSaharaServer style image list, "13:prog.elf;21:devcfg.mbn".
+/// A bare path is taken as the programmer, or served for any ID the device
+/// asks for if it's the only image.
+pub fn parse_sahara_images(spec: &str) -> Result<HashMap<u64, String>, String> {
+    let mut images = HashMap::new();
+    let mut any_bare = false;
+    for entry in spec.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        // Windows drive letters aren't image IDs, "C:\\..." is a bare path
+        let (id, path) = match entry.split_once(':') {
+            Some((id, path)) if id.chars().all(|c| c.is_ascii_digit()) && !id.is_empty() => {
+                match id.parse::<u64>() {
+                    Ok(id) => (id, path),
+                    Err(e) => return Err(format!("Invalid image ID {}: {}", id, e)),
+                }
+            }
+            _ => {
+                any_bare = true;
+                (SAHARA_PROGRAMMER_IMAGE_ID, entry)
             }
         };
+        if images.insert(id, path.to_string()).is_some() {
+            return Err(format!("Image ID {} is given more than once", id));
+        }
+    }
+    if images.is_empty() {
+        return Err("No image to send".to_string());
+    }
+    if any_bare && images.len() == 1 {
+        return Ok(images
+            .into_values()
+            .map(|path| (SAHARA_ANY_IMAGE_ID, path))
+            .collect());
+    }
+    Ok(images)
+}
+
+/// A dumpable memory region of a crashed device
+#[derive(Clone, Debug, Serialize)]
+pub struct RamdumpRegion {
+    pub name: String,
+    pub filename: String,
+    pub description: String,
+    pub base: u64,
+    pub len: u64,
+    /// The device suggests saving this one
+    pub save_pref: bool,
+}
+
+/// Session with a device that booted into crash-dump (MemoryDebug) mode
+pub struct RamdumpClient {
+    regions: Vec<RamdumpTable64>,
+    channel: QdlDevice<dyn QdlReadWrite>,
+}
+
+impl RamdumpClient {
+    /// Fails if the device doesn't offer MemoryDebug, see `SaharaClient::new`
+    /// for the meaning of `dev_path`
+    pub fn new(backend: QdlBackend, dev_path: Option<String>) -> Result<Self, String> {
+        let mut channel = open_channel(backend, dev_path, None)?;
+        let mut regions = match sahara_get_ramdump_regions(&mut channel, false) {
+            Ok(regions) => regions,
+            Err(e) => return Err(format!("Failed to get the ramdump table: {}", e)),
+        };
+        // Unused slots of the table, nothing to dump there
+        regions.retain(|r| !r.is_empty());
+
+        Ok(Self { regions, channel })
+    }
+
+    pub fn regions(&self) -> Vec<RamdumpRegion> {
+        self.regions
+            .iter()
+            .map(|r| RamdumpRegion {
+                name: r.name(),
+                filename: r.filename(),
+                description: r.description(),
+                base: r.base(),
+                len: r.len(),
+                save_pref: r.save_pref(),
+            })
+            .collect()
+    }
+
+    /// Dump the named regions (or the suggested ones, if empty) into
+    /// `output_dir`, packaged as `format`
+    pub fn dump(
+        &mut self,
+        names: Vec<String>,
+        output_dir: &Path,
+        format: RamdumpFormat,
+        progress: RamdumpProgress,
+    ) -> Result<(), String> {
+        if let Some(unknown) = names.iter().find(|n| {
+            !self
+                .regions
+                .iter()
+                .any(|r| r.name() == n.to_ascii_lowercase())
+        }) {
+            return Err(format!("Unknown ramdump region: {}", unknown));
+        }
+
+        dump_ramdump(
+            &mut self.channel,
+            self.regions.clone(),
+            names,
+            output_dir,
+            format,
+            progress,
+        )
+        .map_err(|e| format!("Failed to dump memory: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sahara_image_list() {
+        let images = parse_sahara_images("13:a.elf; 21:C:\\x\\devcfg.mbn").unwrap();
+        assert_eq!(images[&13], "a.elf");
+        assert_eq!(images[&21], "C:\\x\\devcfg.mbn");
+
+        // A lone bare path is served for any ID, as a single loader always was
+        let images = parse_sahara_images("C:\\prog.elf").unwrap();
+        assert_eq!(images[&SAHARA_ANY_IMAGE_ID], "C:\\prog.elf");
+        let images = parse_sahara_images("prog.elf;21:devcfg.mbn").unwrap();
+        assert_eq!(images[&SAHARA_PROGRAMMER_IMAGE_ID], "prog.elf");
+        // An explicit ID is taken as is
+        let images = parse_sahara_images("21:devcfg.mbn").unwrap();
+        assert_eq!(images[&21], "devcfg.mbn");
+
+        assert!(parse_sahara_images("13:a;13:b").is_err());
+        assert!(parse_sahara_images("prog.elf;13:b").is_err());
+        assert!(parse_sahara_images(" ; ").is_err());
+    }
+
+    #[test]
+    fn hw_id_fields() {
+        // SM8550 rev 2, Oplus (0x0051) model 0x0023
+        let hw_id = HwId(0x2022_a0e1_0051_0023);
+        assert_eq!(hw_id.msm_id(), 0x22a0e1);
+        assert_eq!(hw_id.oem_id(), 0x0051);
+        assert_eq!(hw_id.model_id(), 0x0023);
+
+        let chipset = crate::edl_loader_util::chipset_by_msm_id(hw_id.msm_id()).unwrap();
+        assert_eq!(chipset.name, "SM8550");
+        assert_eq!(chipset.marketing_name, "Snapdragon 8 Gen 2");
+        assert!(crate::edl_loader_util::chipset_by_msm_id(0x0009_60e1).is_none());
     }
 }
@@ -6,7 +6,7 @@ use indexmap::IndexMap;
 use anyhow::bail;
 use owo_colors::OwoColorize;
 
-use crate::qdl::types::{FirehoseResetMode, FirehoseStatus, QdlChan};
+use crate::qdl::types::{FirehoseResetMode, FirehoseStatus, QdlChan, StorageInfo};
 
 use crate::qdl::firehose::{firehose_configure, firehose_read, firehose_reset};
 
@@ -84,6 +84,13 @@ pub fn firehose_parser_configure_response<T: QdlChan>(
         .unwrap()
         .parse::<usize>()
         .unwrap();
+    // Only VIP-enabled loaders report it, stick to ours otherwise
+    if let Some(val) = attrs
+        .get("MaxDigestTableSizeInBytes")
+        .and_then(|v| v.parse::<usize>().ok())
+    {
+        channel.mut_fh_config().max_digest_table_size = val;
+    }
 
     // If the device can take a larger buffer, reconfigure it.
     if channel.fh_config().send_buffer_size < device_max_write_payload_size {
@@ -99,3 +106,289 @@ pub fn firehose_parser_configure_response<T: QdlChan>(
 
     Ok(FirehoseStatus::Ack)
 }
+
+/// The JSON flavour of the \<getstorageinfo\> report, newer loaders log
+/// it next to (or instead of) the plain text lines
+#[derive(Default, serde::Deserialize)]
+#[serde(default)]
+struct StorageInfoJson {
+    total_blocks: Option<u64>,
+    block_size: Option<usize>,
+    page_size: Option<usize>,
+    num_physical: Option<u8>,
+    serial_num: Option<u64>,
+    fw_version: Option<String>,
+    mem_type: Option<String>,
+    prod_name: Option<String>,
+    /// A name on some loaders, the JEDEC ID on others
+    #[serde(alias = "manufacturer_id")]
+    manufacturer: Option<serde_json::Value>,
+    #[serde(alias = "boot_partition_enabled")]
+    boot_lun_enabled: Option<u8>,
+}
+
+/// Microseconds a time unit is worth
+fn time_unit_us(unit: &str) -> Option<f64> {
+    match unit {
+        "us" | "usec" | "usecs" | "microseconds" => Some(1.0),
+        "ms" | "msec" | "msecs" | "milliseconds" => Some(1e3),
+        "s" | "sec" | "secs" | "seconds" => Some(1e6),
+        _ => None,
+    }
+}
+
+/// MB/s a throughput unit is worth
+fn throughput_unit_mbps(unit: &str) -> Option<f64> {
+    match unit {
+        "b/s" | "bytes/s" | "bytes/sec" => Some(1e-6),
+        "kb/s" | "kb/sec" => Some(1e-3),
+        "mb/s" | "mb/sec" => Some(1.0),
+        "gb/s" | "gb/sec" => Some(1e3),
+        _ => None,
+    }
+}
+
+/// Pull the timing out of the \<log\>s of a \<benchmark\> of `trials`
+/// trials, as (total time in us, throughput in MB/s). Loaders word it their
+/// own way, so any number followed by a time or throughput unit is taken. A
+/// total wins over averages (times `trials`), which win over per-trial
+/// times, added up.
+pub fn firehose_parse_benchmark<S: AsRef<str>>(
+    trials: u32,
+    lines: &[S],
+) -> (Option<u64>, Option<f64>) {
+    let mut total = None;
+    let mut average = None;
+    let mut sum = None;
+    let mut throughput = None;
+    for line in lines {
+        let line = line.as_ref().to_ascii_lowercase();
+        let words: Vec<&str> = line
+            .split(|c: char| c.is_whitespace() || ",;:=()[]".contains(c))
+            .filter(|w| !w.is_empty())
+            .collect();
+        let mut time = None;
+        for (idx, word) in words.iter().enumerate() {
+            let num_len = word
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(word.len());
+            let Ok(value) = word[..num_len].parse::<f64>() else {
+                continue;
+            };
+            let unit = match &word[num_len..] {
+                "" => words.get(idx + 1).copied().unwrap_or_default(),
+                unit => unit,
+            };
+            let unit = unit.trim_end_matches('.');
+            if let Some(scale) = time_unit_us(unit) {
+                time.get_or_insert(value * scale);
+            } else if let Some(scale) = throughput_unit_mbps(unit) {
+                throughput = Some(value * scale);
+            }
+        }
+        let Some(time) = time else {
+            continue;
+        };
+        if line.contains("total") {
+            total.get_or_insert(time);
+        } else if line.contains("average") || line.contains("avg") {
+            average.get_or_insert(time * trials as f64);
+        } else {
+            *sum.get_or_insert(0.0) += time;
+        }
+    }
+
+    let time_us = total.or(average).or(sum).map(|us: f64| us.round() as u64);
+    (time_us, throughput)
+}
+
+/// "0x1f" or "31"
+fn parse_log_number(s: &str) -> Option<u64> {
+    let s = s.trim().trim_end_matches('.');
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse::<u64>().ok(),
+    }
+}
+
+/// Parse the \<getstorageinfo\> report out of the loader's log messages
+/// (or fh_loader's output, which echoes them). Lines may carry prefixes
+/// such as "INFO: " or timestamps, anything unrecognized is skipped.
+pub fn firehose_parse_storage_info<S: AsRef<str>>(phys_part_idx: u8, lines: &[S]) -> StorageInfo {
+    let mut info = StorageInfo {
+        physical_partition: phys_part_idx,
+        ..Default::default()
+    };
+
+    for line in lines.iter().map(|l| l.as_ref()) {
+        if let (Some(start), Some(end)) = (line.find("{\"storage_info\""), line.rfind('}')) {
+            let Ok(json) = serde_json::from_str::<serde_json::Value>(&line[start..=end]) else {
+                continue;
+            };
+            let Ok(json) = serde_json::from_value::<StorageInfoJson>(json["storage_info"].clone())
+            else {
+                continue;
+            };
+            info.total_blocks = json.total_blocks.unwrap_or(info.total_blocks);
+            info.block_size = json.block_size.unwrap_or(info.block_size);
+            info.page_size = json.page_size.unwrap_or(info.page_size);
+            info.num_physical = json.num_physical.unwrap_or(info.num_physical);
+            info.serial_num = json.serial_num.unwrap_or(info.serial_num);
+            info.firmware_revision = json.fw_version.unwrap_or(info.firmware_revision);
+            info.mem_type = json.mem_type.unwrap_or(info.mem_type);
+            info.product = json.prod_name.unwrap_or(info.product);
+            match json.manufacturer {
+                Some(serde_json::Value::String(name)) => info.manufacturer = name,
+                // The Inquiry vendor name reads better than the bare ID
+                Some(serde_json::Value::Number(id)) if info.manufacturer.is_empty() => {
+                    info.manufacturer = format!("0x{:x}", id.as_u64().unwrap_or_default())
+                }
+                _ => {}
+            }
+            info.boot_lun_enabled = json.boot_lun_enabled.or(info.boot_lun_enabled);
+            continue;
+        }
+
+        let Some((key, value)) = line.rsplit_once(':') else {
+            continue;
+        };
+        if key.contains("Inquiry Command Output") {
+            // SCSI INQUIRY: vendor, product, revision
+            let fields: Vec<&str> = value
+                .split(|c: char| c.is_whitespace() || c == '\'')
+                .filter(|f| !f.is_empty())
+                .collect();
+            if let [vendor, product @ .., revision] = &fields[..] {
+                info.manufacturer = vendor.to_string();
+                info.product = product.join(" ");
+                info.firmware_revision = revision.to_string();
+            }
+            continue;
+        }
+        let Some(number) = parse_log_number(value) else {
+            continue;
+        };
+        if key.contains("Device Total Physical Partitions") {
+            info.num_physical = number as u8;
+        } else if key.contains("Device Serial Number") {
+            info.serial_num = number;
+        } else if key.contains("Device Total Logical Blocks") {
+            info.total_blocks = number;
+        } else if key.contains("Device Block Size in Bytes") {
+            info.block_size = number as usize;
+        } else if key.contains("Boot Partition Enabled") {
+            info.boot_lun_enabled = Some(number as u8);
+        }
+    }
+
+    info
+}
+
+/// Find the digest in the \<getsha256digest\> report, logged as 64 hex
+/// digits (e.g. "Digest 0x3F0A...") somewhere in the messages
+pub fn firehose_parse_sha256_digest<S: AsRef<str>>(lines: &[S]) -> Option<[u8; 32]> {
+    lines
+        .iter()
+        .flat_map(|line| line.as_ref().split(|c: char| !c.is_ascii_alphanumeric()))
+        .map(|word| word.strip_prefix("0x").unwrap_or(word))
+        .find(|word| word.len() == 64 && word.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(|hex| {
+            let mut digest = [0u8; 32];
+            for (i, b) in digest.iter_mut().enumerate() {
+                *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+            }
+            digest
+        })
+}
+
+/// Collect the bytes of a \<peek\> report, logged as lines of "0xNN" words.
+/// Other messages, like the "Using address 0x..." one, are skipped.
+pub fn firehose_parse_peek_bytes<S: AsRef<str>>(lines: &[S]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for line in lines.iter().map(|l| l.as_ref()) {
+        let bytes: Option<Vec<u8>> = line
+            .split_whitespace()
+            .map(|word| {
+                let hex = word
+                    .strip_prefix("0x")
+                    .or_else(|| word.strip_prefix("0X"))?;
+                match hex.len() {
+                    1 | 2 => u8::from_str_radix(hex, 16).ok(),
+                    _ => None,
+                }
+            })
+            .collect();
+        if let Some(bytes) = bytes {
+            data.extend(bytes);
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn storage_info_json() {
+        let lines = [
+            "INFO: {\"storage_info\": {\"total_blocks\":15616000, \"block_size\":4096, \"num_physical\":6, \"manufacturer_id\":462, \"boot_lun_enabled\":1, \"prod_name\":\"KLUDG4U1EA-B0C1\"}}",
+        ];
+        let info = firehose_parse_storage_info(0, &lines);
+        assert_eq!(info.total_blocks, 15616000);
+        assert_eq!(info.block_size, 4096);
+        assert_eq!(info.num_physical, 6);
+        assert_eq!(info.manufacturer, "0x1ce");
+        assert_eq!(info.boot_lun_enabled, Some(1));
+        assert_eq!(info.product, "KLUDG4U1EA-B0C1");
+
+        let lines = ["{\"storage_info\": {\"manufacturer\":\"SAMSUNG\"}}"];
+        let info = firehose_parse_storage_info(0, &lines);
+        assert_eq!(info.manufacturer, "SAMSUNG");
+        assert_eq!(info.boot_lun_enabled, None);
+    }
+
+    #[test]
+    fn peek_bytes() {
+        let lines = [
+            "Using address 0x146bf000",
+            "0x7F 0x45 0x4C 0x46 0x02 0x01 0x01 0x00",
+            "0x0 0xFF",
+            "Read 10 bytes",
+        ];
+        assert_eq!(
+            firehose_parse_peek_bytes(&lines),
+            [0x7f, 0x45, 0x4c, 0x46, 0x02, 0x01, 0x01, 0x00, 0x00, 0xff]
+        );
+    }
+
+    #[test]
+    fn benchmark_timing() {
+        // Per-trial times only, added up
+        let lines = [
+            "Benchmark TestReadPerformance, trials=2",
+            "Trial 0: read 1048576 bytes in 1250 us",
+            "Trial 1: read 1048576 bytes in 1300 us",
+        ];
+        assert_eq!(firehose_parse_benchmark(2, &lines), (Some(2550), None));
+
+        // An average wins over the per-trial times
+        let lines = ["Trial 0 took 1250 us", "Average time per trial: 1.3ms"];
+        assert_eq!(firehose_parse_benchmark(2, &lines), (Some(2600), None));
+
+        // A total wins over both, a throughput is taken as is
+        let lines = [
+            "Average: 1300 usec",
+            "Total time 3.8 ms, throughput: 822.5 MB/s",
+        ];
+        assert_eq!(
+            firehose_parse_benchmark(3, &lines),
+            (Some(3800), Some(822.5))
+        );
+
+        assert_eq!(
+            firehose_parse_benchmark(1, &["No timing here"]),
+            (None, None)
+        );
+    }
+}
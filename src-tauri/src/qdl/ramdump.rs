@@ -0,0 +1,204 @@
+// SPDX-License-Identifier: BSD-3-Clause
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+
+//! Packaging of ramdump regions for crash-analysis tooling, which needs the
+//! physical base of every region on top of its contents.
+//!
+//! The ELF core carries one PT_LOAD per region (p_paddr = p_vaddr = base)
+//! and a PT_NOTE with one "QCOM" note per region, in the same order, whose
+//! descriptor is "<filename>\0<description>\0". The load script is a T32
+//! style `load.cmm` placing each raw region file at its base address.
+
+use anyhow::Result;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::qdl::sahara::{
+    RamdumpProgress, RamdumpTable64, sahara_dump_region, sahara_dump_regions,
+    sahara_region_filename, sahara_select_regions,
+};
+use crate::qdl::types::QdlChan;
+
+/// How the dumped regions end up on disk
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RamdumpFormat {
+    /// One raw file per region, named as proposed by the device
+    #[default]
+    Raw,
+    /// A single ELF core file, `RAMDUMP_ELF_NAME`
+    Elf,
+    /// The raw files plus a `RAMDUMP_SCRIPT_NAME` load script
+    Cmm,
+}
+
+pub const RAMDUMP_ELF_NAME: &str = "ramdump.elf";
+pub const RAMDUMP_SCRIPT_NAME: &str = "load.cmm";
+
+const ELF_HDR_SIZE: u64 = 64;
+const ELF_PHDR_SIZE: u64 = 56;
+const ET_CORE: u16 = 4;
+const EM_AARCH64: u16 = 183;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_R: u32 = 4;
+const PF_RWX: u32 = 7;
+const NOTE_NAME: &[u8] = b"QCOM\0";
+/// Note type of the per-region filename/description notes, clear of the
+/// NT_* values core file readers interpret regardless of the note name
+const NT_RAMDUMP_REGION: u32 = 0x1000;
+/// Region data starts on a page boundary in the core file
+const SEGMENT_ALIGN: u64 = 0x1000;
+
+fn pad4(buf: &mut Vec<u8>) {
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+fn region_note(entry: &RamdumpTable64) -> Vec<u8> {
+    let mut desc = entry.filename().into_bytes();
+    desc.push(0);
+    desc.extend_from_slice(entry.description().as_bytes());
+    desc.push(0);
+
+    let mut note = Vec::new();
+    note.extend_from_slice(&(NOTE_NAME.len() as u32).to_le_bytes());
+    note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    note.extend_from_slice(&NT_RAMDUMP_REGION.to_le_bytes());
+    note.extend_from_slice(NOTE_NAME);
+    pad4(&mut note);
+    note.extend_from_slice(&desc);
+    pad4(&mut note);
+    note
+}
+
+fn elf_header(phnum: u16) -> Vec<u8> {
+    let mut hdr = Vec::with_capacity(ELF_HDR_SIZE as usize);
+    // 64-bit, little endian, current version, System V ABI
+    hdr.extend_from_slice(b"\x7fELF\x02\x01\x01\x00");
+    hdr.resize(16, 0);
+    hdr.extend_from_slice(&ET_CORE.to_le_bytes());
+    hdr.extend_from_slice(&EM_AARCH64.to_le_bytes());
+    hdr.extend_from_slice(&1u32.to_le_bytes()); // e_version
+    hdr.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+    hdr.extend_from_slice(&ELF_HDR_SIZE.to_le_bytes()); // e_phoff
+    hdr.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    hdr.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    hdr.extend_from_slice(&(ELF_HDR_SIZE as u16).to_le_bytes());
+    hdr.extend_from_slice(&(ELF_PHDR_SIZE as u16).to_le_bytes());
+    hdr.extend_from_slice(&phnum.to_le_bytes());
+    hdr.extend_from_slice(&[0u8; 6]); // no section headers
+    hdr
+}
+
+fn program_header(p_type: u32, flags: u32, offset: u64, addr: u64, size: u64) -> Vec<u8> {
+    let mut phdr = Vec::with_capacity(ELF_PHDR_SIZE as usize);
+    phdr.extend_from_slice(&p_type.to_le_bytes());
+    phdr.extend_from_slice(&flags.to_le_bytes());
+    phdr.extend_from_slice(&offset.to_le_bytes());
+    phdr.extend_from_slice(&addr.to_le_bytes()); // p_vaddr
+    phdr.extend_from_slice(&addr.to_le_bytes()); // p_paddr
+    phdr.extend_from_slice(&size.to_le_bytes()); // p_filesz
+    phdr.extend_from_slice(&size.to_le_bytes()); // p_memsz
+    let align = match p_type {
+        PT_LOAD => SEGMENT_ALIGN,
+        _ => 4,
+    };
+    phdr.extend_from_slice(&align.to_le_bytes());
+    phdr
+}
+
+/// Dump `regions` straight into an ELF core file at `path`
+fn dump_elf_core<T: QdlChan>(
+    channel: &mut T,
+    regions: &[RamdumpTable64],
+    path: &Path,
+    progress: RamdumpProgress,
+) -> Result<()> {
+    let notes: Vec<u8> = regions.iter().flat_map(region_note).collect();
+    let phnum = regions.len() + 1;
+    let notes_offset = ELF_HDR_SIZE + ELF_PHDR_SIZE * phnum as u64;
+
+    let mut headers = elf_header(phnum as u16);
+    headers.extend(program_header(
+        PT_NOTE,
+        PF_R,
+        notes_offset,
+        0,
+        notes.len() as u64,
+    ));
+    let mut offsets = Vec::with_capacity(regions.len());
+    let mut offset = (notes_offset + notes.len() as u64).next_multiple_of(SEGMENT_ALIGN);
+    for entry in regions {
+        headers.extend(program_header(
+            PT_LOAD,
+            PF_RWX,
+            offset,
+            entry.base(),
+            entry.len(),
+        ));
+        offsets.push(offset);
+        offset = (offset + entry.len()).next_multiple_of(SEGMENT_ALIGN);
+    }
+    headers.extend(notes);
+
+    let mut f = BufWriter::new(File::create(path)?);
+    f.write_all(&headers)?;
+    for (entry, offset) in regions.iter().zip(offsets) {
+        f.seek(SeekFrom::Start(offset))?;
+        sahara_dump_region(channel, *entry, &mut f, &mut *progress)?;
+    }
+    f.flush()?;
+
+    Ok(())
+}
+
+/// Write a T32 style script loading each raw region file at its base
+fn write_load_script(regions: &[RamdumpTable64], path: &Path) -> Result<()> {
+    let mut f = BufWriter::new(File::create(path)?);
+    for entry in regions {
+        writeln!(f, "; {}", entry.description())?;
+        writeln!(
+            f,
+            "d.load.binary {} 0x{:x} /noclear",
+            sahara_region_filename(entry)?,
+            entry.base()
+        )?;
+    }
+    f.flush()?;
+
+    Ok(())
+}
+
+/// Dump the regions named in `regions_to_dump` (or the ones the device
+/// suggests saving, if empty) into `output_dir`, packaged as `format`
+pub fn dump_ramdump<T: QdlChan>(
+    channel: &mut T,
+    dump_tbl: Vec<RamdumpTable64>,
+    regions_to_dump: Vec<String>,
+    output_dir: &Path,
+    format: RamdumpFormat,
+    progress: RamdumpProgress,
+) -> Result<()> {
+    let regions = sahara_select_regions(&dump_tbl, &regions_to_dump);
+
+    match format {
+        RamdumpFormat::Raw => {
+            sahara_dump_regions(channel, dump_tbl, regions_to_dump, output_dir, progress)
+        }
+        RamdumpFormat::Cmm => {
+            sahara_dump_regions(channel, dump_tbl, regions_to_dump, output_dir, progress)?;
+            write_load_script(&regions, &output_dir.join(RAMDUMP_SCRIPT_NAME))
+        }
+        RamdumpFormat::Elf => {
+            std::fs::create_dir_all(output_dir)?;
+            dump_elf_core(
+                channel,
+                &regions,
+                &output_dir.join(RAMDUMP_ELF_NAME),
+                progress,
+            )
+        }
+    }
+}
@@ -2,25 +2,128 @@
 // Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
 use anstream::println;
 use owo_colors::OwoColorize;
-use pbr::{ProgressBar, Units};
 use std::{
     cmp::min,
+    collections::HashMap,
     ffi::CStr,
     fs::File,
     io::{Read, Write},
     mem::{self, size_of_val},
+    path::Path,
 };
 
-use anyhow::{Result, anyhow, bail};
+use anyhow::{Result, bail};
 
 use bincode::serialize;
 use serde::{self, Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
+use thiserror::Error;
 
 use crate::qdl::types::{QdlBackend, QdlChan};
 
 const SAHARA_STATUS_SUCCESS: u32 = 0;
 
+/// Readable form of the status the device reports in End of Image
+/// (and Hello/Done) packets
+pub fn sahara_status_message(status: u32) -> &'static str {
+    match status {
+        0x00 => "Success",
+        0x01 => "Invalid command received in current state",
+        0x02 => "Protocol mismatch between host and target",
+        0x03 => "Invalid target protocol version",
+        0x04 => "Invalid host protocol version",
+        0x05 => "Invalid packet size received",
+        0x06 => "Unexpected image ID received",
+        0x07 => "Invalid image header size received",
+        0x08 => "Invalid image data size received",
+        0x09 => "Invalid image type received",
+        0x0a => "Invalid transmission length",
+        0x0b => "Invalid reception length",
+        0x0c => "General transmission or reception error",
+        0x0d => "Error while transmitting READ_DATA packet",
+        0x0e => "Cannot receive specified number of program headers",
+        0x0f => "Invalid data length received for program headers",
+        0x10 => "Multiple shared segments found in ELF image",
+        0x11 => "Uninitialized program header location",
+        0x12 => "Invalid destination address",
+        0x13 => "Invalid data size received in image header",
+        0x14 => "Invalid ELF header received",
+        0x15 => "Unknown host error received in HELLO_RESP",
+        0x16 => "Timeout while receiving data",
+        0x17 => "Timeout while transmitting data",
+        0x18 => "Invalid mode received from host",
+        0x19 => "Invalid memory read access",
+        0x1a => "Host cannot handle read data size requested",
+        0x1b => "Memory debug not supported",
+        0x1c => "Invalid mode switch",
+        0x1d => "Failed to execute command",
+        0x1e => "Invalid parameter passed to command execution",
+        0x1f => "Unsupported client command received",
+        0x20 => "Invalid client command received for data response",
+        0x21 => "Failed to authenticate hash table",
+        0x22 => "Failed to verify hash for a given segment of ELF image",
+        0x23 => "Failed to find hash table in ELF image",
+        0x24 => "Target failed to initialize",
+        0x25 => "Failed to authenticate generic image",
+        0x26 => "Invalid ELF hash table size",
+        _ => "Unknown error",
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SaharaError {
+    #[error("Malformed packet, too short: {0:02x?}")]
+    ShortPacket(Vec<u8>),
+    #[error("Got unknown command 0x{0:x}")]
+    UnknownCommand(u32),
+    #[error("Got unexpected {0:?} packet")]
+    UnexpectedCommand(SaharaCmd),
+    #[error("Malformed {cmd:?} packet: {source}")]
+    MalformedPacket {
+        cmd: SaharaCmd,
+        source: bincode::Error,
+    },
+    #[error("{cmd:?} packet is {actual} bytes long, expected {expected}")]
+    LengthMismatch {
+        cmd: SaharaCmd,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("Device rejected image {image}: {} (0x{status:02x})", sahara_status_message(*status))]
+    EndOfImage { image: u32, status: u32 },
+    #[error("Device asked for image {0}, which wasn't provided")]
+    UnknownImage(u64),
+    #[error("Attempted OOB read {end} > {len} of image {image}")]
+    OutOfBounds { image: u64, end: u64, len: usize },
+    #[error("Device asked for 0x{len:x} bytes at 0x{offset:x} of image {image}, out of range")]
+    OutOfRange { image: u64, offset: u64, len: u64 },
+    #[error("Ramdump table of {0} bytes is not a whole number of entries or too large")]
+    InvalidRamdumpTable(u64),
+    #[error("Device is in command mode, but no command was given")]
+    MissingCommand,
+    #[error("Got Firehose data while expecting a Sahara packet: {0:?}")]
+    FirehoseData(String),
+    #[error("{0}")]
+    Unsupported(String),
+    #[error("Timed out waiting for the device")]
+    Timeout,
+    #[error("I/O error: {0}")]
+    Io(std::io::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl From<std::io::Error> for SaharaError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::TimedOut => SaharaError::Timeout,
+            _ => SaharaError::Io(e),
+        }
+    }
+}
+
+pub type SaharaResult<T> = std::result::Result<T, SaharaError>;
+
 #[derive(Copy, Clone, Debug, PartialEq, Deserialize_repr, Serialize_repr)]
 #[repr(u32)]
 pub enum SaharaMode {
@@ -65,7 +168,7 @@ pub enum SaharaCmd {
     /* Proto >= 2.8 */
     SaharaReadData64 = 0x12,
     /* Proto >= 2.9 */
-    SaharaResetState = 0x13,
+    SaharaResetState = 0x13, /* Host asks Device to start over from Hello */
     /* Proto >= 3.0 */
     SaharaWriteData = 0x14,
 
@@ -217,26 +320,91 @@ pub struct RamdumpTable64 {
     filename: [u8; 20],
 }
 
+impl RamdumpTable64 {
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the device suggests saving this region by default
+    pub fn save_pref(&self) -> bool {
+        self.save_pref != 0
+    }
+
+    pub fn description(&self) -> String {
+        c_str_field(&self.description)
+    }
+
+    pub fn filename(&self) -> String {
+        c_str_field(&self.filename)
+    }
+
+    /// Region name as picked by the user, i.e. the lowercase filename
+    /// without the extension proposed by the device
+    pub fn name(&self) -> String {
+        self.filename()
+            .to_ascii_lowercase()
+            .split('.')
+            .next()
+            .unwrap_or("")
+            .to_owned()
+    }
+}
+
+fn c_str_field(field: &[u8]) -> String {
+    match CStr::from_bytes_until_nul(field) {
+        Ok(s) => s.to_string_lossy().into_owned(),
+        Err(_) => String::from_utf8_lossy(field).into_owned(),
+    }
+}
+
+/// Images served during the Sahara phase, by the ID the device asks for
+/// (e.g. 13 for the programmer, as in QSaharaServer's `-s 13:file`)
+pub type SaharaImages = HashMap<u64, Vec<u8>>;
+
+/// Image ID the PBL asks for when it wants a programmer
+pub const SAHARA_PROGRAMMER_IMAGE_ID: u64 = 13;
+/// Key of a lone image given without an ID, served whatever ID is asked for
+pub const SAHARA_ANY_IMAGE_ID: u64 = u64::MAX;
+
 pub fn sahara_send_img_to_device<T: Read + Write>(
     channel: &mut T,
-    img_arr: &mut [Vec<u8>],
+    images: &SaharaImages,
     image_idx: u64,
     image_offset: u64,
     image_len: u64,
-) -> Result<usize, anyhow::Error> {
-    let image = if img_arr.len() == 1 { 0 } else { image_idx };
-    let buf = &mut img_arr[image as usize];
-    if (image_offset + image_len) as usize > buf.len() {
-        bail!(
-            "Attempted OOB read {} > {}",
-            image_offset + image_len,
-            buf.len()
-        );
+) -> SaharaResult<usize> {
+    let Some(buf) = images
+        .get(&image_idx)
+        .or_else(|| images.get(&SAHARA_ANY_IMAGE_ID))
+    else {
+        return Err(SaharaError::UnknownImage(image_idx));
+    };
+    // Offset and length come straight from the device, don't trust them
+    let range = image_offset
+        .checked_add(image_len)
+        .and_then(|end| Some(usize::try_from(image_offset).ok()?..usize::try_from(end).ok()?))
+        .ok_or(SaharaError::OutOfRange {
+            image: image_idx,
+            offset: image_offset,
+            len: image_len,
+        })?;
+    if range.end > buf.len() {
+        return Err(SaharaError::OutOfBounds {
+            image: image_idx,
+            end: range.end as u64,
+            len: buf.len(),
+        });
     }
 
-    channel
-        .write(&buf[image_offset as usize..(image_offset + image_len) as usize])
-        .map_err(|e| e.into())
+    channel.write(&buf[range]).map_err(|e| e.into())
 }
 
 fn sahara_send_generic<T: Read + Write>(
@@ -244,23 +412,43 @@ fn sahara_send_generic<T: Read + Write>(
     cmd: SaharaCmd,
     body: SaharaPacketBody,
     body_len: usize,
-) -> Result<usize> {
+) -> SaharaResult<usize> {
     let pkt = SaharaPacket {
         cmd,
         len: (size_of_val(&cmd) + size_of::<u32>() + body_len) as u32,
         body,
     };
 
-    channel
-        .write(&serialize(&pkt).expect("Error serializing packet"))
-        .map_err(|e| e.into())
+    let data = serialize(&pkt).map_err(|source| SaharaError::MalformedPacket { cmd, source })?;
+    channel.write(&data).map_err(|e| e.into())
+}
+
+/// Newest protocol version this host speaks
+const SAHARA_VERSION: u32 = 3;
+/// Oldest protocol version this host speaks
+const SAHARA_MIN_VERSION: u32 = 1;
+
+/// Pick the protocol version to answer a Hello with: the device's, capped
+/// to ours, as long as both sides still accept it
+pub fn sahara_negotiate_version(req: &HelloReq) -> SaharaResult<u32> {
+    let version = min(req.ver, SAHARA_VERSION);
+    if version < SAHARA_MIN_VERSION || version < req.compatible {
+        return Err(SaharaError::Unsupported(format!(
+            "Device speaks Sahara v{} (compatible down to v{}), this host only supports v{} to v{}",
+            req.ver, req.compatible, SAHARA_MIN_VERSION, SAHARA_VERSION
+        )));
+    }
+    Ok(version)
 }
 
-const SAHARA_VERSION: u32 = 2;
-pub fn sahara_send_hello_rsp<T: Read + Write>(channel: &mut T, mode: SaharaMode) -> Result<usize> {
+pub fn sahara_send_hello_rsp<T: Read + Write>(
+    channel: &mut T,
+    version: u32,
+    mode: SaharaMode,
+) -> SaharaResult<usize> {
     let data = HelloResp {
-        ver: SAHARA_VERSION,
-        compatible: 1,
+        ver: version,
+        compatible: SAHARA_MIN_VERSION,
         status: SAHARA_STATUS_SUCCESS,
         mode,
         unk0: 0,
@@ -279,7 +467,7 @@ pub fn sahara_send_hello_rsp<T: Read + Write>(channel: &mut T, mode: SaharaMode)
     )
 }
 
-pub fn sahara_send_done<T: Read + Write>(channel: &mut T) -> Result<usize> {
+pub fn sahara_send_done<T: Read + Write>(channel: &mut T) -> SaharaResult<usize> {
     let data = DoneReq {};
 
     sahara_send_generic(
@@ -293,7 +481,7 @@ pub fn sahara_send_done<T: Read + Write>(channel: &mut T) -> Result<usize> {
 pub fn sahara_send_cmd_exec<T: Read + Write>(
     channel: &mut T,
     command: SaharaCmdModeCmd,
-) -> Result<usize, anyhow::Error> {
+) -> SaharaResult<usize> {
     sahara_send_generic(
         channel,
         SaharaCmd::SaharaExecute,
@@ -305,7 +493,7 @@ pub fn sahara_send_cmd_exec<T: Read + Write>(
 pub fn sahara_send_cmd_data<T: Read + Write>(
     channel: &mut T,
     command: SaharaCmdModeCmd,
-) -> Result<usize, anyhow::Error> {
+) -> SaharaResult<usize> {
     sahara_send_generic(
         channel,
         SaharaCmd::SaharaExecuteData,
@@ -315,7 +503,7 @@ pub fn sahara_send_cmd_data<T: Read + Write>(
 }
 
 #[allow(dead_code)]
-pub fn sahara_reset<T: Read + Write>(channel: &mut T) -> Result<usize, anyhow::Error> {
+pub fn sahara_reset<T: Read + Write>(channel: &mut T) -> SaharaResult<usize> {
     let data = ResetReq {};
 
     sahara_send_generic(
@@ -326,10 +514,23 @@ pub fn sahara_reset<T: Read + Write>(channel: &mut T) -> Result<usize, anyhow::E
     )
 }
 
+/// Ask the device to restart its Sahara state machine (proto >= 2.9), it
+/// answers with a fresh Hello without going through a full reset
+pub fn sahara_reset_state<T: Read + Write>(channel: &mut T) -> SaharaResult<usize> {
+    let data = ResetReq {};
+
+    sahara_send_generic(
+        channel,
+        SaharaCmd::SaharaResetState,
+        SaharaPacketBody::ResetReq(data),
+        size_of_val(&data),
+    )
+}
+
 pub fn sahara_switch_mode<T: Read + Write>(
     channel: &mut T,
     mode: SaharaMode,
-) -> Result<usize, anyhow::Error> {
+) -> SaharaResult<usize> {
     let data = SwitchMode { mode };
 
     sahara_send_generic(
@@ -340,12 +541,20 @@ pub fn sahara_switch_mode<T: Read + Write>(
     )
 }
 
+/// Largest ramdump table we take from the device, real ones have a few
+/// dozen entries
+const RAMDUMP_TBL_MAX_LEN: u64 = 64 * 1024;
+
 pub fn sahara_get_ramdump_tbl<T: Read + Write>(
     channel: &mut T,
     addr: u64,
     len: u64,
     verbose: bool,
-) -> Result<Vec<RamdumpTable64>, anyhow::Error> {
+) -> SaharaResult<Vec<RamdumpTable64>> {
+    let entry_size = size_of::<RamdumpTable64>();
+    if len > RAMDUMP_TBL_MAX_LEN || !len.is_multiple_of(entry_size as u64) {
+        return Err(SaharaError::InvalidRamdumpTable(len));
+    }
     let data = ReadMem64Req { addr, len };
 
     sahara_send_generic(
@@ -355,7 +564,6 @@ pub fn sahara_get_ramdump_tbl<T: Read + Write>(
         size_of_val(&data),
     )?;
 
-    let entry_size = size_of::<RamdumpTable64>();
     let num_chunks = len as usize / entry_size;
     let mut tbl = Vec::<RamdumpTable64>::with_capacity(num_chunks);
 
@@ -366,12 +574,18 @@ pub fn sahara_get_ramdump_tbl<T: Read + Write>(
         println!("Available images:");
     }
     for i in 0..num_chunks {
-        let entry = bincode::deserialize::<RamdumpTable64>(&buf[i * entry_size..])?;
+        let entry =
+            bincode::deserialize::<RamdumpTable64>(&buf[i * entry_size..]).map_err(|source| {
+                SaharaError::MalformedPacket {
+                    cmd: SaharaCmd::SaharaMemRead64,
+                    source,
+                }
+            })?;
         tbl.push(entry);
         if verbose {
             println!(
                 "\t{} (0x{:x} @ 0x{:x}){}",
-                String::from_utf8(entry.filename.to_vec())?,
+                entry.filename(),
                 entry.len,
                 entry.base,
                 match entry.save_pref {
@@ -385,18 +599,48 @@ pub fn sahara_get_ramdump_tbl<T: Read + Write>(
     Ok(tbl)
 }
 
-fn sahara_dump_region<T: QdlChan>(
+/// Called with the region filename, the bytes dumped so far and the region size
+pub type RamdumpProgress<'a> = &'a mut dyn FnMut(&str, u64, u64);
+
+/// Greet a device that booted into crash-dump mode and fetch the table of
+/// regions it offers, leaving it ready for `sahara_dump_regions`
+pub fn sahara_get_ramdump_regions<T: QdlChan>(
+    channel: &mut T,
+    verbose: bool,
+) -> SaharaResult<Vec<RamdumpTable64>> {
+    let mut buf = vec![0; 4096];
+
+    let bytes_read = channel.read(&mut buf[..])?;
+    let pkt = sahara_parse_packet(&buf[..bytes_read], verbose)?;
+    let SaharaPacketBody::HelloReq(req) = pkt.body else {
+        return Err(SaharaError::UnexpectedCommand(pkt.cmd));
+    };
+    if req.mode != SaharaMode::MemoryDebug {
+        return Err(SaharaError::Unsupported(format!(
+            "Device is not in crash-dump mode (Hello mode {:?})",
+            req.mode
+        )));
+    }
+    let version = sahara_negotiate_version(&req)?;
+    sahara_send_hello_rsp(channel, version, SaharaMode::MemoryDebug)?;
+
+    let bytes_read = channel.read(&mut buf[..])?;
+    let pkt = sahara_parse_packet(&buf[..bytes_read], verbose)?;
+    let SaharaPacketBody::Debug64Req(req) = pkt.body else {
+        return Err(SaharaError::UnexpectedCommand(pkt.cmd));
+    };
+
+    sahara_get_ramdump_tbl(channel, req.addr, req.len, verbose)
+}
+
+pub fn sahara_dump_region<T: QdlChan>(
     channel: &mut T,
     entry: RamdumpTable64,
     output: &mut impl Write,
+    progress: RamdumpProgress,
 ) -> Result<()> {
-    let mut pb = ProgressBar::new(entry.len);
-    pb.show_time_left = true;
-    pb.message(&format!(
-        "Dumping {}: ",
-        String::from_utf8(entry.filename.to_vec())?
-    ));
-    pb.set_units(Units::Bytes);
+    let filename = entry.filename();
+    progress(&filename, 0, entry.len);
 
     let mut bytes_read = 0usize;
     while bytes_read < entry.len as usize {
@@ -422,56 +666,59 @@ fn sahara_dump_region<T: QdlChan>(
             let _ = channel.read(&mut []);
         }
 
-        pb.set(bytes_read as u64);
+        progress(&filename, bytes_read as u64, entry.len);
         let _ = output.write(&buf)?;
     }
 
     Ok(())
 }
 
-pub fn sahara_dump_regions<T: QdlChan>(
-    channel: &mut T,
-    dump_tbl: Vec<RamdumpTable64>,
-    regions_to_dump: Vec<String>,
-) -> Result<()> {
+/// Pick the regions named in `regions_to_dump` out of the table, or the
+/// ones the device suggests saving if no name is given
+pub fn sahara_select_regions(
+    dump_tbl: &[RamdumpTable64],
+    regions_to_dump: &[String],
+) -> Vec<RamdumpTable64> {
     // Make all of them lowercase for better UX
     let regions_to_dump = regions_to_dump
         .iter()
         .map(|rname| rname.to_ascii_lowercase())
         .collect::<Vec<String>>();
 
-    std::fs::create_dir_all("ramdump/")?;
-    let filtered_list: Vec<RamdumpTable64> = match regions_to_dump.len() {
+    match regions_to_dump.len() {
         // Dump everything with save_pref == true if no argument was provided
-        0 => dump_tbl
-            .iter()
-            .filter(|e| e.save_pref != 0)
-            .copied()
-            .collect(),
+        0 => dump_tbl.iter().filter(|e| e.save_pref()).copied().collect(),
         _ => dump_tbl
             .iter()
-            .filter(|dump_entry| {
-                regions_to_dump.contains(
-                    &String::from_utf8(dump_entry.filename.to_vec())
-                        .unwrap_or("".to_owned())
-                        .to_ascii_lowercase()
-                        .split('.') // Ignore file extensions proposed by ramdump
-                        .next()
-                        .unwrap_or("")
-                        .to_owned(),
-                )
-            })
+            .filter(|dump_entry| regions_to_dump.contains(&dump_entry.name()))
             .copied()
             .collect(),
-    };
-    for entry in filtered_list {
-        let fname = CStr::from_bytes_until_nul(&entry.filename)
-            .unwrap()
-            .to_str()?
-            .to_owned();
-
-        let mut f = File::create(std::path::Path::new(&format!("ramdump/{fname}")))?;
-        sahara_dump_region(channel, entry, &mut f)?;
+    }
+}
+
+/// The region filename, if it's safe to create inside the output directory
+pub fn sahara_region_filename(entry: &RamdumpTable64) -> Result<String> {
+    let fname = entry.filename();
+    if fname.is_empty() || fname.contains(['/', '\\']) || fname.contains("..") {
+        bail!("Refusing to dump to suspicious file name {:?}", fname);
+    }
+    Ok(fname)
+}
+
+/// Dump the regions named in `regions_to_dump` (or the ones the device
+/// suggests saving, if empty) into `output_dir`, one file per region
+pub fn sahara_dump_regions<T: QdlChan>(
+    channel: &mut T,
+    dump_tbl: Vec<RamdumpTable64>,
+    regions_to_dump: Vec<String>,
+    output_dir: &Path,
+    progress: RamdumpProgress,
+) -> Result<()> {
+    std::fs::create_dir_all(output_dir)?;
+    for entry in sahara_select_regions(&dump_tbl, &regions_to_dump) {
+        let fname = sahara_region_filename(&entry)?;
+        let mut f = File::create(output_dir.join(&fname))?;
+        sahara_dump_region(channel, entry, &mut f, &mut *progress)?;
     }
 
     Ok(())
@@ -481,21 +728,20 @@ pub fn sahara_run<T: QdlChan>(
     channel: &mut T,
     sahara_mode: SaharaMode,
     sahara_command: Option<SaharaCmdModeCmd>,
-    images: &mut [Vec<u8>],
+    images: &SaharaImages,
     filenames: Vec<String>,
     verbose: bool,
-) -> Result<Vec<u8>> {
+) -> SaharaResult<Vec<u8>> {
     let mut buf = vec![0; 4096];
 
     loop {
         let bytes_read = channel.read(&mut buf[..])?;
         let pkt = sahara_parse_packet(&buf[..bytes_read], verbose)?;
-        let pktsize = size_of_val(&pkt.cmd) + size_of_val(&pkt.len);
 
         match pkt.cmd {
             SaharaCmd::SaharaHello => {
                 if let SaharaPacketBody::HelloReq(req) = pkt.body {
-                    assert_eq!(pkt.len as usize, pktsize + mem::size_of::<HelloReq>());
+                    sahara_check_len(&pkt, mem::size_of::<HelloReq>())?;
 
                     // MemoryDebug mode can only be entered if the device offers it
                     let mode = if sahara_mode == SaharaMode::MemoryDebug
@@ -505,12 +751,19 @@ pub fn sahara_run<T: QdlChan>(
                     } else {
                         sahara_mode
                     };
-                    sahara_send_hello_rsp(channel, mode)?;
+                    let version = sahara_negotiate_version(&req)?;
+                    if verbose {
+                        println!(
+                            "Device speaks Sahara v{} (compatible down to v{}), using v{}",
+                            req.ver, req.compatible, version
+                        );
+                    }
+                    sahara_send_hello_rsp(channel, version, mode)?;
                 }
             }
             SaharaCmd::SaharaReadData => {
                 if let SaharaPacketBody::ReadReq(rr) = pkt.body {
-                    assert_eq!(pkt.len as usize, pktsize + mem::size_of::<ReadReq>());
+                    sahara_check_len(&pkt, mem::size_of::<ReadReq>())?;
                     sahara_send_img_to_device(
                         channel,
                         images,
@@ -522,14 +775,18 @@ pub fn sahara_run<T: QdlChan>(
             }
             SaharaCmd::SaharaEndOfImage => {
                 if let SaharaPacketBody::Eoi(req) = pkt.body {
-                    assert_eq!(pkt.len as usize, pktsize + mem::size_of::<Eoi>());
+                    sahara_check_len(&pkt, mem::size_of::<Eoi>())?;
 
-                    if req.status == 0 {
-                        sahara_send_done(channel)?;
-                    } else {
-                        eprintln!("Received unsuccessful End of Image packet");
-                        return Ok(vec![]);
+                    if req.status != SAHARA_STATUS_SUCCESS {
+                        return Err(SaharaError::EndOfImage {
+                            image: req.image,
+                            status: req.status,
+                        });
                     }
+                    if verbose {
+                        println!("Image {} sent", req.image);
+                    }
+                    sahara_send_done(channel)?;
                 }
             }
             SaharaCmd::SaharaDoneResp => {
@@ -542,10 +799,10 @@ pub fn sahara_run<T: QdlChan>(
                 }
             }
             SaharaCmd::SaharaCommandReady => {
-                assert_eq!(pkt.len as usize, pktsize);
+                sahara_check_len(&pkt, 0)?;
                 match sahara_command {
                     Some(cmd) => sahara_send_cmd_exec(channel, cmd),
-                    None => bail!("Missing sahara command"),
+                    None => return Err(SaharaError::MissingCommand),
                 }?;
             }
             SaharaCmd::SaharaExecuteResp => {
@@ -556,7 +813,13 @@ pub fn sahara_run<T: QdlChan>(
                     sahara_send_cmd_data(channel, resp.command)?;
 
                     let resp_len = channel.read(&mut resp_buf)?;
-                    assert_eq!(resp_len, resp.len as usize);
+                    if resp_len != resp.len as usize {
+                        return Err(SaharaError::LengthMismatch {
+                            cmd: SaharaCmd::SaharaExecuteData,
+                            expected: resp.len as usize,
+                            actual: resp_len,
+                        });
+                    }
 
                     // Got everything we want, exit command mode
                     sahara_switch_mode(channel, SaharaMode::WaitingForImage)?;
@@ -566,89 +829,116 @@ pub fn sahara_run<T: QdlChan>(
             }
             SaharaCmd::SaharaMemDebug64 => {
                 if let SaharaPacketBody::Debug64Req(req) = pkt.body {
-                    assert_eq!(pkt.len as usize, pktsize + mem::size_of::<Debug64Req>());
+                    sahara_check_len(&pkt, mem::size_of::<Debug64Req>())?;
 
                     // Receive the dump info table
                     let dump_tbl = sahara_get_ramdump_tbl(channel, req.addr, req.len, verbose)?;
 
                     // Grab some (possibly all) of the available regions
-                    sahara_dump_regions(channel, dump_tbl, filenames)?;
+                    sahara_dump_regions(
+                        channel,
+                        dump_tbl,
+                        filenames,
+                        Path::new("ramdump"),
+                        &mut |fname, done, total| {
+                            if verbose && done == total {
+                                println!("Dumped {fname} (0x{total:x} bytes)");
+                            }
+                        },
+                    )?;
 
                     return Ok(vec![]);
                 }
             }
             SaharaCmd::SaharaReadData64 => {
                 if let SaharaPacketBody::ReadData64Req(rr) = pkt.body {
-                    assert_eq!(pkt.len as usize, pktsize + mem::size_of::<ReadData64Req>());
+                    sahara_check_len(&pkt, mem::size_of::<ReadData64Req>())?;
                     sahara_send_img_to_device(channel, images, rr.image, rr.offset, rr.len)?;
                 }
             }
             SaharaCmd::SaharaResetResp => {
-                assert_eq!(pkt.len as usize, pktsize);
+                sahara_check_len(&pkt, 0)?;
             }
             SaharaCmd::SaharaXML => {
                 // Todo: make this optionally "fine"
                 println!("Device booted into the loader already");
                 return Ok(vec![]);
             }
-            _ => todo!("Got packet {:?}", pkt),
+            _ => return Err(SaharaError::UnexpectedCommand(pkt.cmd)),
         }
     }
 }
 
-fn sahara_parse_packet(buf: &[u8], verbose: bool) -> Result<SaharaPacket> {
+/// Packets carry their total length (header included), check it against
+/// the body we expect for the command
+fn sahara_check_len(pkt: &SaharaPacket, body_len: usize) -> SaharaResult<()> {
+    let expected = size_of_val(&pkt.cmd) + size_of_val(&pkt.len) + body_len;
+    if pkt.len as usize != expected {
+        return Err(SaharaError::LengthMismatch {
+            cmd: pkt.cmd,
+            expected,
+            actual: pkt.len as usize,
+        });
+    }
+    Ok(())
+}
+
+fn sahara_parse_body<'a, B: Deserialize<'a>>(cmd: SaharaCmd, args: &'a [u8]) -> SaharaResult<B> {
+    bincode::deserialize::<B>(args).map_err(|source| SaharaError::MalformedPacket { cmd, source })
+}
+
+fn sahara_parse_packet(buf: &[u8], verbose: bool) -> SaharaResult<SaharaPacket> {
     let (cmd, rest) = buf
         .split_first_chunk::<4>()
-        .ok_or_else(|| anyhow!("Malformed packet, too short: {buf:?}"))?;
+        .ok_or_else(|| SaharaError::ShortPacket(buf.to_vec()))?;
     let (len, args) = rest
         .split_first_chunk::<4>()
-        .ok_or_else(|| anyhow!("Malformed packet, too short: {buf:?}"))?;
+        .ok_or_else(|| SaharaError::ShortPacket(buf.to_vec()))?;
 
     let cmd = bincode::deserialize::<SaharaCmd>(cmd)
-        .unwrap_or_else(|_| panic!("Got unknown command {}", u32::from_le_bytes(*cmd)));
+        .map_err(|_| SaharaError::UnknownCommand(u32::from_le_bytes(*cmd)))?;
 
     let ret = SaharaPacket {
         cmd,
         len: u32::from_le_bytes(*len),
         body: match cmd {
-            SaharaCmd::SaharaHello => {
-                SaharaPacketBody::HelloReq(bincode::deserialize::<HelloReq>(args).unwrap())
-            }
+            SaharaCmd::SaharaHello => SaharaPacketBody::HelloReq(sahara_parse_body(cmd, args)?),
             SaharaCmd::SaharaHelloResp => {
-                SaharaPacketBody::HelloResp(bincode::deserialize::<HelloResp>(args).unwrap())
-            }
-            SaharaCmd::SaharaReadData => {
-                SaharaPacketBody::ReadReq(bincode::deserialize::<ReadReq>(args).unwrap())
-            }
-            SaharaCmd::SaharaEndOfImage => {
-                SaharaPacketBody::Eoi(bincode::deserialize::<Eoi>(args).unwrap())
+                SaharaPacketBody::HelloResp(sahara_parse_body(cmd, args)?)
             }
+            SaharaCmd::SaharaReadData => SaharaPacketBody::ReadReq(sahara_parse_body(cmd, args)?),
+            SaharaCmd::SaharaEndOfImage => SaharaPacketBody::Eoi(sahara_parse_body(cmd, args)?),
             SaharaCmd::SaharaDone => SaharaPacketBody::DoneReq(DoneReq {}),
-            SaharaCmd::SaharaDoneResp => {
-                SaharaPacketBody::DoneResp(bincode::deserialize::<DoneResp>(args).unwrap())
-            }
+            SaharaCmd::SaharaDoneResp => SaharaPacketBody::DoneResp(sahara_parse_body(cmd, args)?),
             SaharaCmd::SaharaResetResp => SaharaPacketBody::ResetResp(ResetResp {}),
+            SaharaCmd::SaharaWriteData => {
+                return Err(SaharaError::Unsupported(
+                    "Device asked to send data to the host (Sahara v3 WriteData), which isn't supported"
+                        .to_owned(),
+                ));
+            }
             SaharaCmd::SaharaCommandReady => SaharaPacketBody::CommandReady(CommandReady {}),
             SaharaCmd::SaharaExecuteResp => {
-                SaharaPacketBody::ExecResp(bincode::deserialize::<ExecResp>(args).unwrap())
+                SaharaPacketBody::ExecResp(sahara_parse_body(cmd, args)?)
             }
             SaharaCmd::SaharaExecuteData => {
-                SaharaPacketBody::Command(bincode::deserialize::<SaharaCmdModeCmd>(args).unwrap())
+                SaharaPacketBody::Command(sahara_parse_body(cmd, args)?)
             }
             SaharaCmd::SaharaMemDebug64 => {
-                SaharaPacketBody::Debug64Req(bincode::deserialize::<Debug64Req>(args).unwrap())
+                SaharaPacketBody::Debug64Req(sahara_parse_body(cmd, args)?)
             }
             SaharaCmd::SaharaMemRead64 => {
-                SaharaPacketBody::ReadMem64Req(bincode::deserialize::<ReadMem64Req>(args).unwrap())
+                SaharaPacketBody::ReadMem64Req(sahara_parse_body(cmd, args)?)
+            }
+            SaharaCmd::SaharaReadData64 => {
+                SaharaPacketBody::ReadData64Req(sahara_parse_body(cmd, args)?)
             }
-            SaharaCmd::SaharaReadData64 => SaharaPacketBody::ReadData64Req(
-                bincode::deserialize::<ReadData64Req>(args).unwrap(),
-            ),
-            SaharaCmd::SaharaXML => bail!(
-                "Got Firehose command while expecting Sahara command: {:?}",
-                String::from_utf8_lossy(buf)
-            ),
-            _ => bail!("Got unimplemented command: {:?}", buf),
+            SaharaCmd::SaharaXML => {
+                return Err(SaharaError::FirehoseData(
+                    String::from_utf8_lossy(buf).into_owned(),
+                ));
+            }
+            _ => return Err(SaharaError::UnexpectedCommand(cmd)),
         },
     };
 
@@ -658,3 +948,49 @@ fn sahara_parse_packet(buf: &[u8], verbose: bool) -> Result<SaharaPacket> {
 
     Ok(ret)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn image_request_out_of_range() {
+        let images = SaharaImages::from([(13, vec![0u8; 0x100])]);
+        let mut channel = Cursor::new(Vec::new());
+        assert!(sahara_send_img_to_device(&mut channel, &images, 13, 0x80, 0x80).is_ok());
+        assert!(matches!(
+            sahara_send_img_to_device(&mut channel, &images, 13, 0x80, 0x81),
+            Err(SaharaError::OutOfBounds { .. })
+        ));
+        assert!(matches!(
+            sahara_send_img_to_device(&mut channel, &images, 13, u64::MAX, 2),
+            Err(SaharaError::OutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn lone_image_serves_any_id() {
+        let images = SaharaImages::from([(SAHARA_ANY_IMAGE_ID, vec![0u8; 0x100])]);
+        let mut channel = Cursor::new(Vec::new());
+        assert!(sahara_send_img_to_device(&mut channel, &images, 13, 0, 0x10).is_ok());
+        assert!(sahara_send_img_to_device(&mut channel, &images, 21, 0, 0x10).is_ok());
+
+        let images = SaharaImages::from([(13, vec![0u8; 0x100])]);
+        assert!(matches!(
+            sahara_send_img_to_device(&mut channel, &images, 21, 0, 0x10),
+            Err(SaharaError::UnknownImage(21))
+        ));
+    }
+
+    #[test]
+    fn ramdump_table_length() {
+        let mut channel = Cursor::new(Vec::new());
+        for len in [63, RAMDUMP_TBL_MAX_LEN + 64, u64::MAX] {
+            assert!(matches!(
+                sahara_get_ramdump_tbl(&mut channel, 0, len, false),
+                Err(SaharaError::InvalidRamdumpTable(_))
+            ));
+        }
+    }
+}
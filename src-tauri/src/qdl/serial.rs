@@ -3,6 +3,7 @@
 use anyhow::{Result, bail};
 use serial2::{self, SerialPort};
 use std::io::{BufRead, Read, Write};
+use std::time::Duration;
 
 use crate::qdl::types::QdlReadWrite;
 
@@ -13,7 +14,6 @@ pub struct QdlSerialConfig {
     cap: usize,
 }
 
-// TODO: timeouts?
 impl Write for QdlSerialConfig {
     fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
         self.serport.write(buf)
@@ -60,7 +60,12 @@ impl BufRead for QdlSerialConfig {
     }
 }
 
-impl QdlReadWrite for QdlSerialConfig {}
+impl QdlReadWrite for QdlSerialConfig {
+    fn set_timeouts(&mut self, read: Duration, write: Duration) -> Result<(), std::io::Error> {
+        self.serport.set_read_timeout(read)?;
+        self.serport.set_write_timeout(write)
+    }
+}
 
 pub fn setup_serial_device(dev_path: Option<String>) -> Result<QdlSerialConfig> {
     if dev_path.is_none() {
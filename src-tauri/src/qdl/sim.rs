@@ -0,0 +1,1427 @@
+// SPDX-License-Identifier: BSD-3-Clause
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+
+//! In-memory EDL target, speaking Sahara and then Firehose XML on top of
+//! per-LUN disk image files. It lets the whole SaharaClient/firehose_* flow
+//! run without a phone attached.
+
+use anyhow::{Result, bail};
+use indexmap::IndexMap;
+use ring::digest::{SHA256, digest};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use xmltree::{Element, XMLNode};
+
+use crate::qdl::types::QdlReadWrite;
+
+// Sahara command IDs, as seen on the wire
+const SAHARA_HELLO: u32 = 0x1;
+const SAHARA_HELLO_RESP: u32 = 0x2;
+const SAHARA_READ_DATA: u32 = 0x3;
+const SAHARA_END_OF_IMAGE: u32 = 0x4;
+const SAHARA_DONE: u32 = 0x5;
+const SAHARA_DONE_RESP: u32 = 0x6;
+const SAHARA_RESET: u32 = 0x7;
+const SAHARA_RESET_RESP: u32 = 0x8;
+const SAHARA_COMMAND_READY: u32 = 0xb;
+const SAHARA_SWITCH_MODE: u32 = 0xc;
+const SAHARA_EXECUTE: u32 = 0xd;
+const SAHARA_EXECUTE_RESP: u32 = 0xe;
+const SAHARA_EXECUTE_DATA: u32 = 0xf;
+const SAHARA_MEM_DEBUG64: u32 = 0x10;
+const SAHARA_MEM_READ64: u32 = 0x11;
+const SAHARA_READ_DATA64: u32 = 0x12;
+const SAHARA_RESET_STATE: u32 = 0x13;
+
+const SAHARA_MODE_IMAGE: u32 = 0x0;
+const SAHARA_MODE_MEMORY_DEBUG: u32 = 0x2;
+const SAHARA_MODE_COMMAND: u32 = 0x3;
+
+const SAHARA_STATUS_SUCCESS: u32 = 0x0;
+const SAHARA_STATUS_INVALID_ELF_HDR: u32 = 0x14;
+
+/// Image ID the PBL asks for when it wants a programmer
+const SIM_PROGRAMMER_IMAGE_ID: u64 = 13;
+/// Where the ramdump table "lives" in device memory
+const SIM_RAMDUMP_TBL_ADDR: u64 = 0x1000_0000;
+const SIM_RAMDUMP_ENTRY_SIZE: usize = 64;
+/// Largest chunk the simulated PBL asks for in one ReadData request
+const SIM_MAX_READ_CHUNK: u64 = 1024 * 1024;
+
+/// A chunk of device memory, exposed through MemoryDebug and \<peek\>
+#[derive(Clone, Debug)]
+pub struct SimMemRegion {
+    pub name: String,
+    pub base: u64,
+    pub data: Vec<u8>,
+    pub save_pref: bool,
+}
+
+/// A misbehaviour of the simulated loader, fired once on the next command
+/// with the given tag, see setup_sim_device()
+#[derive(Clone, Debug, PartialEq)]
+pub enum SimFault {
+    /// Answer NAK
+    Nak(String),
+    /// Answer NAK with AttemptRetry first, then handle the command
+    Retry(String),
+    /// Answer NAK with AttemptRestart
+    Restart(String),
+    /// Swallow the command and never answer it
+    Timeout(String),
+    /// Send only half of the data of a \<read\>, then go quiet
+    ShortRead(String),
+}
+
+/// Knobs for the simulated target
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    /// One image file per LUN, in physical partition order
+    pub lun_images: Vec<PathBuf>,
+    pub sector_size: usize,
+    pub sahara_version: u32,
+    pub sahara_min_version: u32,
+    /// Mode announced in Hello, MemoryDebug makes the device "crashed"
+    pub sahara_mode: u32,
+    pub serial_num: u32,
+    pub hw_id: u64,
+    pub oem_key_hash: Vec<u8>,
+    pub memory: Vec<SimMemRegion>,
+    pub max_payload_size: usize,
+    /// Largest chained digest table taken once VIP is on
+    pub max_digest_table_size: usize,
+    pub welcome_logs: Vec<String>,
+    pub faults: Vec<SimFault>,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            lun_images: Vec::new(),
+            sector_size: 4096,
+            sahara_version: 2,
+            sahara_min_version: 1,
+            sahara_mode: SAHARA_MODE_IMAGE,
+            serial_num: 0x1234_5678,
+            hw_id: 0x0022_a0e1_0000_0000,
+            oem_key_hash: [0x5au8; 32].repeat(3),
+            memory: Vec::new(),
+            max_payload_size: 1024 * 1024,
+            max_digest_table_size: 8192,
+            welcome_logs: vec!["Simulated Firehose loader".to_owned()],
+            faults: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ElfStage {
+    Header,
+    ProgramHeaders { is_64: bool },
+    Segments,
+}
+
+#[derive(Debug, PartialEq)]
+enum SimState {
+    /// Waiting for a Sahara packet from the host
+    Sahara,
+    /// Waiting for `len` bytes of image data requested through ReadData
+    SaharaImageData { len: usize },
+    /// Waiting for an XML command
+    Firehose,
+    /// Waiting for `bytes_left` of rawmode data for a \<program\>
+    FirehoseRaw {
+        lun: usize,
+        offset: u64,
+        bytes_left: usize,
+    },
+    /// The device was powered off or rebooted into the OS
+    Gone,
+}
+
+pub struct QdlSimDevice {
+    cfg: SimConfig,
+    luns: Vec<File>,
+    state: SimState,
+    // Device -> Host frames, one per bulk transfer
+    outbound: VecDeque<Vec<u8>>,
+    // Host -> Device bytes that didn't make a complete command yet
+    inbound: Vec<u8>,
+    pos: usize,
+
+    elf_stage: ElfStage,
+    segments: VecDeque<(u64, u64)>,
+    bootable_drive: u32,
+    // Digest tables received so far, the first one is the signed table
+    digest_tables: usize,
+    vip_enabled: bool,
+    // MaxPayloadSizeToTargetInBytes as last configured
+    payload_size: usize,
+    // LUNs of the <ufs> provisioning so far, None until the device wide tag
+    ufs_luns: Option<usize>,
+    ufs_lock: bool,
+    ufs_locked: bool,
+}
+
+fn le32(v: u32) -> [u8; 4] {
+    v.to_le_bytes()
+}
+
+fn rd32(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+fn rd64(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Evaluate the sector expressions found in rawprogram/patch XMLs, e.g.
+/// "34", "NUM_DISK_SECTORS-5." or "(NUM_DISK_SECTORS-33.)"
+fn eval_sector_expr(expr: &str, num_disk_sectors: u64) -> Option<u64> {
+    let expr = expr.trim().trim_start_matches('(').trim_end_matches(')');
+    let expr = expr.trim().trim_end_matches('.');
+    if let Some(rest) = expr.strip_prefix("NUM_DISK_SECTORS") {
+        let rest = rest.trim();
+        if rest.is_empty() {
+            return Some(num_disk_sectors);
+        }
+        let n = rest.strip_prefix('-')?.trim().trim_end_matches('.');
+        return num_disk_sectors.checked_sub(n.parse().ok()?);
+    }
+    expr.parse().ok()
+}
+
+impl QdlSimDevice {
+    pub fn new(cfg: SimConfig) -> Result<Self> {
+        let mut luns = Vec::with_capacity(cfg.lun_images.len());
+        for path in &cfg.lun_images {
+            luns.push(OpenOptions::new().read(true).write(true).open(path)?);
+        }
+
+        let mut dev = Self {
+            cfg,
+            luns,
+            state: SimState::Sahara,
+            outbound: VecDeque::new(),
+            inbound: Vec::new(),
+            pos: 0,
+            elf_stage: ElfStage::Header,
+            segments: VecDeque::new(),
+            bootable_drive: 1,
+            digest_tables: 0,
+            vip_enabled: false,
+            payload_size: 0,
+            ufs_luns: None,
+            ufs_lock: false,
+            ufs_locked: false,
+        };
+        dev.sahara_hello();
+        Ok(dev)
+    }
+
+    fn push_frame(&mut self, frame: Vec<u8>) {
+        self.outbound.push_back(frame);
+    }
+
+    fn sahara_packet(&mut self, cmd: u32, body: &[u8]) {
+        let mut pkt = Vec::with_capacity(8 + body.len());
+        pkt.extend_from_slice(&le32(cmd));
+        pkt.extend_from_slice(&le32((8 + body.len()) as u32));
+        pkt.extend_from_slice(body);
+        self.push_frame(pkt);
+    }
+
+    fn sahara_hello(&mut self) {
+        self.elf_stage = ElfStage::Header;
+        self.segments.clear();
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&le32(self.cfg.sahara_version));
+        body.extend_from_slice(&le32(self.cfg.sahara_min_version));
+        body.extend_from_slice(&le32(0x400)); // max_len
+        body.extend_from_slice(&le32(self.cfg.sahara_mode));
+        body.extend_from_slice(&[0u8; 24]);
+        self.sahara_packet(SAHARA_HELLO, &body);
+    }
+
+    fn sahara_request(&mut self, offset: u64, len: u64) {
+        if self.cfg.sahara_version >= 2 {
+            let mut body = Vec::new();
+            body.extend_from_slice(&SIM_PROGRAMMER_IMAGE_ID.to_le_bytes());
+            body.extend_from_slice(&offset.to_le_bytes());
+            body.extend_from_slice(&len.to_le_bytes());
+            self.sahara_packet(SAHARA_READ_DATA64, &body);
+        } else {
+            let mut body = Vec::new();
+            body.extend_from_slice(&le32(SIM_PROGRAMMER_IMAGE_ID as u32));
+            body.extend_from_slice(&le32(offset as u32));
+            body.extend_from_slice(&le32(len as u32));
+            self.sahara_packet(SAHARA_READ_DATA, &body);
+        }
+        self.state = SimState::SaharaImageData { len: len as usize };
+    }
+
+    fn sahara_end_of_image(&mut self, status: u32) {
+        let mut body = Vec::new();
+        body.extend_from_slice(&le32(SIM_PROGRAMMER_IMAGE_ID as u32));
+        body.extend_from_slice(&le32(status));
+        self.sahara_packet(SAHARA_END_OF_IMAGE, &body);
+        self.state = SimState::Sahara;
+    }
+
+    fn sahara_next_segment(&mut self) {
+        match self.segments.pop_front() {
+            Some((offset, len)) => {
+                let chunk = len.min(SIM_MAX_READ_CHUNK);
+                if chunk < len {
+                    self.segments.push_front((offset + chunk, len - chunk));
+                }
+                self.sahara_request(offset, chunk);
+            }
+            None => self.sahara_end_of_image(SAHARA_STATUS_SUCCESS),
+        }
+    }
+
+    /// Walk the programmer ELF the same way the PBL does: header, program
+    /// headers, then every loadable segment
+    fn sahara_image_data(&mut self, data: &[u8]) {
+        match self.elf_stage {
+            ElfStage::Header => {
+                if data.len() < 0x34 || &data[..4] != b"\x7fELF" {
+                    self.sahara_end_of_image(SAHARA_STATUS_INVALID_ELF_HDR);
+                    return;
+                }
+                let is_64 = data[4] == 2;
+                let (phoff, phentsize, phnum) = if is_64 {
+                    (
+                        rd64(data, 0x20),
+                        u16::from_le_bytes([data[0x36], data[0x37]]),
+                        u16::from_le_bytes([data[0x38], data[0x39]]),
+                    )
+                } else {
+                    (
+                        rd32(data, 0x1c) as u64,
+                        u16::from_le_bytes([data[0x2a], data[0x2b]]),
+                        u16::from_le_bytes([data[0x2c], data[0x2d]]),
+                    )
+                };
+                self.elf_stage = ElfStage::ProgramHeaders { is_64 };
+                self.sahara_request(phoff, phentsize as u64 * phnum as u64);
+            }
+            ElfStage::ProgramHeaders { is_64 } => {
+                let phentsize = if is_64 { 56 } else { 32 };
+                for phdr in data.chunks_exact(phentsize) {
+                    let (offset, filesz) = if is_64 {
+                        (rd64(phdr, 8), rd64(phdr, 32))
+                    } else {
+                        (rd32(phdr, 4) as u64, rd32(phdr, 16) as u64)
+                    };
+                    if filesz > 0 {
+                        self.segments.push_back((offset, filesz));
+                    }
+                }
+                self.elf_stage = ElfStage::Segments;
+                self.sahara_next_segment();
+            }
+            ElfStage::Segments => self.sahara_next_segment(),
+        }
+    }
+
+    fn sahara_exec(&mut self, cmd: u32) -> Vec<u8> {
+        match cmd {
+            0x1 => le32(self.cfg.serial_num).to_vec(),
+            0x2 => self.cfg.hw_id.to_le_bytes().to_vec(),
+            0x3 => self.cfg.oem_key_hash.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn ramdump_table(&self) -> Vec<u8> {
+        let mut tbl = Vec::new();
+        for region in &self.cfg.memory {
+            let mut description = [0u8; 20];
+            let mut filename = [0u8; 20];
+            let desc_len = region.name.len().min(19);
+            description[..desc_len].copy_from_slice(&region.name.as_bytes()[..desc_len]);
+            let fname = format!("{}.BIN", region.name);
+            let fname_len = fname.len().min(19);
+            filename[..fname_len].copy_from_slice(&fname.as_bytes()[..fname_len]);
+
+            tbl.extend_from_slice(&(region.save_pref as u64).to_le_bytes());
+            tbl.extend_from_slice(&region.base.to_le_bytes());
+            tbl.extend_from_slice(&(region.data.len() as u64).to_le_bytes());
+            tbl.extend_from_slice(&description);
+            tbl.extend_from_slice(&filename);
+        }
+        tbl
+    }
+
+    fn read_memory(&self, addr: u64, len: usize) -> Vec<u8> {
+        if addr == SIM_RAMDUMP_TBL_ADDR {
+            let mut tbl = self.ramdump_table();
+            tbl.resize(len, 0);
+            return tbl;
+        }
+        let mut out = vec![0u8; len];
+        for region in &self.cfg.memory {
+            let end = region.base + region.data.len() as u64;
+            if addr >= region.base && addr < end {
+                let start = (addr - region.base) as usize;
+                let n = len.min(region.data.len() - start);
+                out[..n].copy_from_slice(&region.data[start..start + n]);
+                break;
+            }
+        }
+        out
+    }
+
+    fn handle_sahara(&mut self) {
+        while self.inbound.len() >= 8 {
+            let cmd = rd32(&self.inbound, 0);
+            let len = rd32(&self.inbound, 4) as usize;
+            if len < 8 || self.inbound.len() < len {
+                return;
+            }
+            let pkt: Vec<u8> = self.inbound.drain(..len).collect();
+
+            match cmd {
+                SAHARA_HELLO_RESP => {
+                    let mode = if pkt.len() >= 24 { rd32(&pkt, 20) } else { 0 };
+                    match mode {
+                        SAHARA_MODE_COMMAND => self.sahara_packet(SAHARA_COMMAND_READY, &[]),
+                        SAHARA_MODE_MEMORY_DEBUG => {
+                            let mut body = Vec::new();
+                            body.extend_from_slice(&SIM_RAMDUMP_TBL_ADDR.to_le_bytes());
+                            let tbl_len = (self.cfg.memory.len() * SIM_RAMDUMP_ENTRY_SIZE) as u64;
+                            body.extend_from_slice(&tbl_len.to_le_bytes());
+                            self.sahara_packet(SAHARA_MEM_DEBUG64, &body);
+                        }
+                        _ => self.sahara_request(0, 0x40),
+                    }
+                }
+                SAHARA_EXECUTE if pkt.len() >= 12 => {
+                    let exec_cmd = rd32(&pkt, 8);
+                    let resp_len = self.sahara_exec(exec_cmd).len() as u32;
+                    let mut body = Vec::new();
+                    body.extend_from_slice(&le32(exec_cmd));
+                    body.extend_from_slice(&le32(resp_len));
+                    self.sahara_packet(SAHARA_EXECUTE_RESP, &body);
+                }
+                SAHARA_EXECUTE_DATA if pkt.len() >= 12 => {
+                    let data = self.sahara_exec(rd32(&pkt, 8));
+                    self.push_frame(data);
+                }
+                SAHARA_SWITCH_MODE => self.sahara_hello(),
+                SAHARA_MEM_READ64 if pkt.len() >= 24 => {
+                    let data = self.read_memory(rd64(&pkt, 8), rd64(&pkt, 16) as usize);
+                    self.push_frame(data);
+                }
+                SAHARA_DONE => {
+                    self.sahara_packet(SAHARA_DONE_RESP, &le32(1));
+                    self.state = SimState::Firehose;
+                    for log in self.cfg.welcome_logs.clone() {
+                        self.firehose_log(&log);
+                    }
+                }
+                SAHARA_RESET => {
+                    self.sahara_packet(SAHARA_RESET_RESP, &[]);
+                    self.sahara_hello();
+                }
+                SAHARA_RESET_STATE => self.sahara_hello(),
+                _ => eprintln!("sim: unexpected Sahara packet {cmd:#x}"),
+            }
+
+            if self.state != SimState::Sahara {
+                return;
+            }
+        }
+    }
+
+    fn firehose_response(&mut self, attrs: &[(&str, String)]) {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" ?><data><response");
+        for (k, v) in attrs {
+            xml += &format!(" {}=\"{}\"", k, xml_escape(v));
+        }
+        xml += " /></data>";
+        self.push_frame(xml.into_bytes());
+    }
+
+    fn firehose_ack(&mut self, ack: bool) {
+        let value = if ack { "ACK" } else { "NAK" };
+        self.firehose_response(&[("value", value.to_owned())]);
+    }
+
+    fn firehose_log(&mut self, msg: &str) {
+        let xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\" ?><data><log value=\"{}\" /></data>",
+            xml_escape(msg)
+        );
+        self.push_frame(xml.into_bytes());
+    }
+
+    fn lun(&mut self, attrs: &IndexMap<String, String>) -> Option<usize> {
+        let lun = attrs
+            .get("physical_partition_number")
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(0);
+        (lun < self.luns.len()).then_some(lun)
+    }
+
+    fn num_disk_sectors(&self, lun: usize) -> u64 {
+        let len = self.luns[lun].metadata().map(|m| m.len()).unwrap_or(0);
+        len / self.cfg.sector_size as u64
+    }
+
+    /// (lun, byte offset, byte count) of the range a storage command targets
+    fn storage_range(&mut self, attrs: &IndexMap<String, String>) -> Option<(usize, u64, u64)> {
+        let lun = self.lun(attrs)?;
+        let sector_size = self.cfg.sector_size as u64;
+        let disk_sectors = self.num_disk_sectors(lun);
+        let start = eval_sector_expr(attrs.get("start_sector")?, disk_sectors)?;
+        let count = attrs
+            .get("num_partition_sectors")
+            .and_then(|v| eval_sector_expr(v, disk_sectors))
+            .unwrap_or(0);
+        if start + count > disk_sectors {
+            return None;
+        }
+        Some((lun, start * sector_size, count * sector_size))
+    }
+
+    fn disk_read(&mut self, lun: usize, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        let f = &mut self.luns[lun];
+        f.seek(SeekFrom::Start(offset))?;
+        f.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn disk_write(&mut self, lun: usize, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        let f = &mut self.luns[lun];
+        f.seek(SeekFrom::Start(offset))?;
+        f.write_all(data)
+    }
+
+    fn fh_configure(&mut self, attrs: &IndexMap<String, String>) {
+        let requested = attrs
+            .get("MaxPayloadSizeToTargetInBytes")
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(self.cfg.max_payload_size);
+        let supported = self.cfg.max_payload_size;
+        let value = if requested > supported { "NAK" } else { "ACK" };
+        self.payload_size = requested.min(supported);
+
+        let mut response = vec![
+            ("value", value.to_owned()),
+            ("MemoryName", "UFS".to_owned()),
+            ("MaxPayloadSizeFromTargetInBytes", "4096".to_owned()),
+            (
+                "MaxPayloadSizeToTargetInBytes",
+                requested.min(supported).to_string(),
+            ),
+            (
+                "MaxPayloadSizeToTargetInBytesSupported",
+                supported.to_string(),
+            ),
+            ("MaxXMLSizeInBytes", "4096".to_owned()),
+            ("Version", "1".to_owned()),
+            ("MinVersionSupported", "1".to_owned()),
+        ];
+        if self.vip_enabled {
+            response.push((
+                "MaxDigestTableSizeInBytes",
+                self.cfg.max_digest_table_size.to_string(),
+            ));
+        }
+        self.firehose_response(&response);
+    }
+
+    /// A raw VIP digest table, sent outside of any XML command
+    fn fh_digest_table(&mut self, table: &[u8]) {
+        // The signed table comes first and may be of any size
+        if self.digest_tables > 0 && table.len() > self.cfg.max_digest_table_size {
+            self.firehose_log(&format!(
+                "ERROR: digest table of {} bytes exceeds MaxDigestTableSizeInBytes",
+                table.len()
+            ));
+            self.firehose_ack(false);
+            return;
+        }
+        if self.digest_tables > 0 && !self.vip_enabled {
+            self.firehose_log("ERROR: chained digest table before <verify EnableVip=\"1\">");
+            self.firehose_ack(false);
+            return;
+        }
+        self.digest_tables += 1;
+        self.firehose_log(&format!("Digest table of {} bytes accepted", table.len()));
+        self.firehose_ack(true);
+    }
+
+    fn fh_program(&mut self, attrs: &IndexMap<String, String>) {
+        match self.storage_range(attrs) {
+            Some((lun, offset, len)) if len > 0 => {
+                self.firehose_response(&[
+                    ("value", "ACK".to_owned()),
+                    ("rawmode", "true".to_owned()),
+                ]);
+                self.state = SimState::FirehoseRaw {
+                    lun,
+                    offset,
+                    bytes_left: len as usize,
+                };
+            }
+            _ => {
+                self.firehose_log("ERROR: program range is out of bounds");
+                self.firehose_ack(false);
+            }
+        }
+    }
+
+    fn fh_read(&mut self, attrs: &IndexMap<String, String>, short: bool) {
+        let Some((lun, offset, len)) = self.storage_range(attrs) else {
+            self.firehose_log("ERROR: read range is out of bounds");
+            self.firehose_ack(false);
+            return;
+        };
+        let data = match self.disk_read(lun, offset, len as usize) {
+            Ok(data) => data,
+            Err(e) => {
+                self.firehose_log(&format!("ERROR: read failed: {e}"));
+                self.firehose_ack(false);
+                return;
+            }
+        };
+
+        self.firehose_response(&[("value", "ACK".to_owned()), ("rawmode", "true".to_owned())]);
+        let data = match short {
+            true => &data[..data.len() / 2],
+            false => &data[..],
+        };
+        for chunk in data.chunks(self.cfg.max_payload_size) {
+            self.push_frame(chunk.to_vec());
+        }
+        if !short {
+            self.firehose_response(&[("value", "ACK".to_owned()), ("rawmode", "false".to_owned())]);
+        }
+    }
+
+    fn fh_erase(&mut self, attrs: &IndexMap<String, String>) {
+        let ok = match self.storage_range(attrs) {
+            Some((lun, offset, len)) => self
+                .disk_write(lun, offset, &vec![0u8; len as usize])
+                .is_ok(),
+            None => false,
+        };
+        self.firehose_ack(ok);
+    }
+
+    fn fh_patch_value(&mut self, lun: usize, value: &str) -> Option<u64> {
+        let disk_sectors = self.num_disk_sectors(lun);
+        let value = value.trim();
+        // CRC32(start_sector, len_in_bytes)
+        if let Some(args) = value
+            .strip_prefix("CRC32(")
+            .and_then(|v| v.strip_suffix(')'))
+        {
+            let (start, len) = args.split_once(',')?;
+            let start = eval_sector_expr(start, disk_sectors)?;
+            let len = eval_sector_expr(len, disk_sectors)?;
+            let data = self
+                .disk_read(lun, start * self.cfg.sector_size as u64, len as usize)
+                .ok()?;
+            return Some(crc32(&data) as u64);
+        }
+        eval_sector_expr(value, disk_sectors)
+    }
+
+    fn fh_patch(&mut self, attrs: &IndexMap<String, String>) {
+        if attrs.get("filename").map(|f| f.as_str()) != Some("DISK") {
+            // Patching host-side files is not the device's business
+            self.firehose_ack(true);
+            return;
+        }
+        let Some(lun) = self.lun(attrs) else {
+            self.firehose_ack(false);
+            return;
+        };
+        let disk_sectors = self.num_disk_sectors(lun);
+        let start = attrs
+            .get("start_sector")
+            .and_then(|v| eval_sector_expr(v, disk_sectors));
+        let byte_off = attrs.get("byte_offset").and_then(|v| v.parse::<u64>().ok());
+        let size = attrs
+            .get("size_in_bytes")
+            .and_then(|v| v.parse::<usize>().ok());
+        let value = attrs
+            .get("value")
+            .cloned()
+            .and_then(|v| self.fh_patch_value(lun, &v));
+
+        let ok = match (start, byte_off, size, value) {
+            (Some(start), Some(byte_off), Some(size), Some(value)) if size <= 8 => {
+                let offset = start * self.cfg.sector_size as u64 + byte_off;
+                self.disk_write(lun, offset, &value.to_le_bytes()[..size])
+                    .is_ok()
+            }
+            _ => false,
+        };
+        self.firehose_ack(ok);
+    }
+
+    fn fh_sha256(&mut self, attrs: &IndexMap<String, String>) {
+        let Some((lun, offset, len)) = self.storage_range(attrs) else {
+            self.firehose_ack(false);
+            return;
+        };
+        match self.disk_read(lun, offset, len as usize) {
+            Ok(data) => {
+                let hash = digest(&SHA256, &data);
+                let hex: String = hash.as_ref().iter().map(|b| format!("{b:02X}")).collect();
+                self.firehose_log(&format!("Digest 0x{hex}"));
+                self.firehose_ack(true);
+            }
+            Err(_) => self.firehose_ack(false),
+        }
+    }
+
+    fn fh_storage_info(&mut self, attrs: &IndexMap<String, String>) {
+        let Some(lun) = self.lun(attrs) else {
+            self.firehose_ack(false);
+            return;
+        };
+        let total_blocks = self.num_disk_sectors(lun);
+        let num_physical = self.luns.len();
+        self.firehose_log(&format!(
+            "Device Total Physical Partitions: {num_physical:#x}"
+        ));
+        self.firehose_log(&format!("Device Serial Number: {:#x}", self.cfg.serial_num));
+        self.firehose_log("UFS Inquiry Command Output: SIM     QDL-SIM-UFS     0001");
+        self.firehose_log(&format!(
+            "Boot Partition Enabled: {:#x}",
+            self.bootable_drive
+        ));
+        self.firehose_log(&format!(
+            "INFO: {{\"storage_info\": {{\"total_blocks\":{}, \"block_size\":{}, \"page_size\":{}, \
+             \"num_physical\":{}, \"manufacturer_id\":0, \"serial_num\":{}, \
+             \"fw_version\":\"0001\", \"mem_type\":\"UFS\", \"prod_name\":\"QDL-SIM-UFS\"}}}}",
+            total_blocks,
+            self.cfg.sector_size,
+            self.cfg.sector_size,
+            num_physical,
+            self.cfg.serial_num
+        ));
+        self.firehose_ack(true);
+    }
+
+    fn fh_peek(&mut self, attrs: &IndexMap<String, String>) {
+        let addr = attrs
+            .get("address64")
+            .and_then(|v| parse_u64(v))
+            .unwrap_or(0);
+        let size = attrs
+            .get("size_in_bytes")
+            .and_then(|v| parse_u64(v))
+            .unwrap_or(0) as usize;
+
+        let data = self.read_memory(addr, size);
+        self.firehose_log(&format!("Using address {addr:#x}"));
+        for line in data.chunks(16) {
+            let hex: Vec<String> = line.iter().map(|b| format!("0x{b:02X}")).collect();
+            self.firehose_log(&hex.join(" "));
+        }
+        self.firehose_ack(true);
+    }
+
+    /// Pretend to move the configured payload size `trials` times, at a
+    /// fixed per-transfer overhead plus 400 MB/s (writes) or 800 MB/s (reads)
+    fn fh_benchmark(&mut self, attrs: &IndexMap<String, String>) {
+        let trials = attrs
+            .get("trials")
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(1);
+        let write = attrs.get("TestWritePerformance").is_some_and(|v| v == "1");
+        let (op, mb_per_s) = if write { ("Write", 400) } else { ("Read", 800) };
+        let time_us = trials * (100 + self.payload_size as u64 / mb_per_s);
+        self.firehose_log(&format!(
+            "{op}: {} bytes x {trials} trials took {time_us} us",
+            self.payload_size
+        ));
+        self.firehose_ack(true);
+    }
+
+    fn fh_ufs(&mut self, attrs: &IndexMap<String, String>) {
+        if self.ufs_locked {
+            self.firehose_log("ERROR: UFS configuration descriptors are locked");
+            self.firehose_ack(false);
+            return;
+        }
+        if attrs.contains_key("bNumberLU") {
+            self.ufs_luns = Some(0);
+            self.ufs_lock = attrs.get("bConfigDescrLock").is_some_and(|v| v == "1");
+            self.firehose_ack(true);
+        } else if let Some(luns) = self.ufs_luns
+            && attrs.contains_key("LUNum")
+        {
+            self.ufs_luns = Some(luns + 1);
+            self.firehose_ack(true);
+        } else if let Some(luns) = self.ufs_luns
+            && luns > 0
+            && let Some(commit) = attrs.get("commit")
+        {
+            if commit == "1" {
+                self.firehose_log(&format!("UFS provisioning of {luns} LUNs committed"));
+                self.ufs_locked = self.ufs_lock;
+            }
+            self.firehose_ack(true);
+        } else {
+            self.firehose_log("ERROR: unexpected <ufs> tag");
+            self.firehose_ack(false);
+        }
+    }
+
+    fn fh_power(&mut self, attrs: &IndexMap<String, String>) {
+        self.firehose_ack(true);
+        match attrs.get("value").map(|v| v.as_str()) {
+            Some("reset_to_edl") => {
+                self.state = SimState::Sahara;
+                self.sahara_hello();
+            }
+            _ => self.state = SimState::Gone,
+        }
+    }
+
+    fn handle_firehose(&mut self) {
+        loop {
+            let marker = b"</data>";
+            let Some(pos) = self.inbound.windows(marker.len()).position(|w| w == marker) else {
+                return;
+            };
+            let doc: Vec<u8> = self.inbound.drain(..pos + marker.len()).collect();
+            // Drop the '\n' padding the host adds to 512-byte-multiple XMLs
+            while self
+                .inbound
+                .first()
+                .is_some_and(|b| b.is_ascii_whitespace())
+            {
+                self.inbound.remove(0);
+            }
+
+            let xml = match Element::parse(&doc[..]) {
+                Ok(xml) => xml,
+                Err(e) => {
+                    self.firehose_log(&format!("ERROR: XML parse failed: {e}"));
+                    self.firehose_ack(false);
+                    continue;
+                }
+            };
+            let Some(XMLNode::Element(cmd)) =
+                xml.children.into_iter().find(|c| c.as_element().is_some())
+            else {
+                self.firehose_ack(false);
+                continue;
+            };
+            let attrs = cmd.attributes;
+
+            let fault = self.cfg.faults.iter().position(|f| match f {
+                SimFault::Nak(tag)
+                | SimFault::Retry(tag)
+                | SimFault::Restart(tag)
+                | SimFault::Timeout(tag)
+                | SimFault::ShortRead(tag) => *tag == cmd.name,
+            });
+            let mut short_read = false;
+            match fault.map(|idx| self.cfg.faults.remove(idx)) {
+                Some(SimFault::Nak(_)) => {
+                    self.firehose_log(&format!("ERROR: injected NAK of <{}>", cmd.name));
+                    self.firehose_ack(false);
+                    continue;
+                }
+                Some(SimFault::Retry(_)) => {
+                    self.firehose_response(&[
+                        ("value", "NAK".to_owned()),
+                        ("AttemptRetry", "1".to_owned()),
+                    ]);
+                }
+                Some(SimFault::Restart(_)) => {
+                    self.firehose_response(&[
+                        ("value", "NAK".to_owned()),
+                        ("AttemptRestart", "1".to_owned()),
+                    ]);
+                    continue;
+                }
+                Some(SimFault::Timeout(_)) => continue,
+                Some(SimFault::ShortRead(_)) => short_read = true,
+                None => {}
+            }
+
+            match cmd.name.as_str() {
+                "configure" => self.fh_configure(&attrs),
+                "program" => self.fh_program(&attrs),
+                "read" => self.fh_read(&attrs, short_read),
+                "erase" | "zeroout" => self.fh_erase(&attrs),
+                "patch" => self.fh_patch(&attrs),
+                "getsha256digest" => self.fh_sha256(&attrs),
+                "getstorageinfo" => self.fh_storage_info(&attrs),
+                "setbootablestoragedrive" => {
+                    match attrs.get("value").and_then(|v| v.parse::<u32>().ok()) {
+                        Some(drive) => {
+                            self.bootable_drive = drive;
+                            self.firehose_ack(true);
+                        }
+                        None => self.firehose_ack(false),
+                    }
+                }
+                "peek" => self.fh_peek(&attrs),
+                "poke" | "nop" => self.firehose_ack(true),
+                "benchmark" => self.fh_benchmark(&attrs),
+                "ufs" => self.fh_ufs(&attrs),
+                "power" => self.fh_power(&attrs),
+                "transfercfg" | "sha256init" => self.firehose_ack(true),
+                "verify" => {
+                    self.vip_enabled = attrs.get("EnableVip").is_some_and(|v| v == "1");
+                    self.firehose_ack(true);
+                }
+                other => {
+                    self.firehose_log(&format!("ERROR: unsupported command <{other}>"));
+                    self.firehose_ack(false);
+                }
+            }
+
+            if !matches!(self.state, SimState::Firehose) {
+                // Any leftovers belong to the new state (e.g. rawmode data)
+                let rest = std::mem::take(&mut self.inbound);
+                self.process(&rest);
+                return;
+            }
+        }
+    }
+
+    fn process(&mut self, buf: &[u8]) {
+        match self.state {
+            SimState::Sahara => {
+                self.inbound.extend_from_slice(buf);
+                self.handle_sahara();
+            }
+            SimState::SaharaImageData { len } => {
+                self.inbound.extend_from_slice(buf);
+                if self.inbound.len() >= len {
+                    let data: Vec<u8> = self.inbound.drain(..len).collect();
+                    self.state = SimState::Sahara;
+                    self.sahara_image_data(&data);
+                    let rest = std::mem::take(&mut self.inbound);
+                    if !rest.is_empty() {
+                        self.process(&rest);
+                    }
+                }
+            }
+            // Anything but XML between commands is a digest table
+            SimState::Firehose if self.inbound.is_empty() && !buf.starts_with(b"<") => {
+                self.fh_digest_table(buf);
+            }
+            SimState::Firehose => {
+                self.inbound.extend_from_slice(buf);
+                self.handle_firehose();
+            }
+            SimState::FirehoseRaw {
+                lun,
+                offset,
+                bytes_left,
+            } => {
+                let n = bytes_left.min(buf.len());
+                if self.disk_write(lun, offset, &buf[..n]).is_err() {
+                    self.firehose_log("ERROR: failed to write to the disk image");
+                }
+                let bytes_left = bytes_left - n;
+                if bytes_left > 0 {
+                    self.state = SimState::FirehoseRaw {
+                        lun,
+                        offset: offset + n as u64,
+                        bytes_left,
+                    };
+                    return;
+                }
+                self.firehose_response(&[
+                    ("value", "ACK".to_owned()),
+                    ("rawmode", "false".to_owned()),
+                ]);
+                self.state = SimState::Firehose;
+                if n < buf.len() {
+                    self.process(&buf[n..]);
+                }
+            }
+            SimState::Gone => {}
+        }
+    }
+}
+
+fn parse_u64(v: &str) -> Option<u64> {
+    match v.strip_prefix("0x").or_else(|| v.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => v.parse().ok(),
+    }
+}
+
+/// Plain CRC32 (IEEE), as used for GPT headers and entry arrays
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+impl Write for QdlSimDevice {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        if self.state == SimState::Gone {
+            return Err(ErrorKind::NotConnected.into());
+        }
+        // A ZLP carries no data and only terminates a transfer
+        if !buf.is_empty() {
+            self.process(buf);
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl Read for QdlSimDevice {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, std::io::Error> {
+        let n = {
+            let available = self.fill_buf()?;
+            let n = std::cmp::min(out.len(), available.len());
+            out[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for QdlSimDevice {
+    fn fill_buf(&mut self) -> Result<&[u8], std::io::Error> {
+        // Nothing queued is what a real, idle device looks like
+        if self.outbound.is_empty() {
+            return Err(match self.state {
+                SimState::Gone => ErrorKind::NotConnected.into(),
+                _ => ErrorKind::TimedOut.into(),
+            });
+        }
+        Ok(&self.outbound[0][self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let Some(front) = self.outbound.front() else {
+            return;
+        };
+        self.pos = std::cmp::min(self.pos + amt, front.len());
+        if self.pos == front.len() {
+            self.outbound.pop_front();
+            self.pos = 0;
+        }
+    }
+}
+
+impl QdlReadWrite for QdlSimDevice {}
+
+/// Base address of the first region loaded from a `ramdump/` directory
+const SIM_RAMDUMP_BASE: u64 = 0x8000_0000;
+
+/// Load every file of `dir` as a memory region, laid out back to back
+fn load_sim_memory(dir: &Path) -> Result<Vec<SimMemRegion>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_file())
+        .collect();
+    paths.sort();
+
+    let mut memory = Vec::new();
+    let mut base = SIM_RAMDUMP_BASE;
+    for path in paths {
+        let data = std::fs::read(&path)?;
+        let name = path
+            .file_stem()
+            .and_then(|n| n.to_str())
+            .unwrap_or("region")
+            .to_ascii_uppercase();
+        let len = data.len() as u64;
+        memory.push(SimMemRegion {
+            name,
+            base,
+            data,
+            save_pref: true,
+        });
+        base += len.next_multiple_of(0x1000);
+    }
+    Ok(memory)
+}
+
+impl std::str::FromStr for SimFault {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((kind, tag)) = s.split_once(':') else {
+            bail!("Malformed fault \"{s}\", expected kind:tag");
+        };
+        let tag = tag.trim().to_owned();
+        match kind.trim() {
+            "nak" => Ok(SimFault::Nak(tag)),
+            "retry" => Ok(SimFault::Retry(tag)),
+            "restart" => Ok(SimFault::Restart(tag)),
+            "timeout" => Ok(SimFault::Timeout(tag)),
+            "shortread" => Ok(SimFault::ShortRead(tag)),
+            other => bail!("Unknown fault kind \"{other}\""),
+        }
+    }
+}
+
+/// Faults listed in `path`, one "kind:tag" per line (e.g. "nak:program")
+fn load_sim_faults(path: &Path) -> Result<Vec<SimFault>> {
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::parse)
+        .collect()
+}
+
+/// Create a simulated device from `lunN.img` files found in `image_dir`.
+/// If `image_dir` has a `ramdump/` directory, the device comes up in
+/// crash-dump mode instead, exposing each file in it as a memory region.
+/// A `faults` file there lists the SimFaults to inject.
+pub fn setup_sim_device(image_dir: Option<String>) -> Result<QdlSimDevice> {
+    let Some(image_dir) = image_dir else {
+        bail!("Simulator image directory unspecified");
+    };
+
+    let ramdump_dir = Path::new(&image_dir).join("ramdump");
+    if ramdump_dir.is_dir() {
+        return QdlSimDevice::new(SimConfig {
+            sahara_mode: SAHARA_MODE_MEMORY_DEBUG,
+            memory: load_sim_memory(&ramdump_dir)?,
+            ..Default::default()
+        });
+    }
+
+    let mut lun_images = Vec::new();
+    for idx in 0.. {
+        let path = Path::new(&image_dir).join(format!("lun{idx}.img"));
+        if !path.is_file() {
+            break;
+        }
+        lun_images.push(path);
+    }
+    if lun_images.is_empty() {
+        bail!("No lun0.img found in {image_dir}");
+    }
+
+    QdlSimDevice::new(SimConfig {
+        lun_images,
+        faults: load_sim_faults(&Path::new(&image_dir).join("faults"))?,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qdl::firehose::{
+        FIREHOSE_DEFAULT_DIGEST_TABLE_SIZE, firehose_configure, firehose_nop,
+        firehose_program_storage, firehose_read, firehose_read_storage, firehose_vip_handshake,
+    };
+    use crate::qdl::parsers::{firehose_parser_ack_nak, firehose_parser_configure_response};
+    use crate::qdl::sahara::{
+        SaharaCmdModeCmd, SaharaError, SaharaImages, SaharaMode, sahara_reset_state, sahara_run,
+    };
+    use crate::qdl::types::{
+        FirehoseConfiguration, FirehoseError, FirehoseRetryPolicy, QdlBackend, QdlDevice,
+    };
+
+    const SECTOR: usize = 4096;
+
+    /// Minimal ELF32 programmer, one 8KiB PT_LOAD segment at offset 0x1000
+    fn test_elf() -> Vec<u8> {
+        let mut elf = vec![0u8; 0x1000 + 8192];
+        elf[..4].copy_from_slice(b"\x7fELF");
+        elf[4] = 1;
+        elf[0x1c..0x20].copy_from_slice(&0x34u32.to_le_bytes());
+        elf[0x2a..0x2c].copy_from_slice(&32u16.to_le_bytes());
+        elf[0x2c..0x2e].copy_from_slice(&1u16.to_le_bytes());
+        elf[0x34..0x38].copy_from_slice(&1u32.to_le_bytes());
+        elf[0x38..0x3c].copy_from_slice(&0x1000u32.to_le_bytes());
+        elf[0x44..0x48].copy_from_slice(&8192u32.to_le_bytes());
+        elf
+    }
+
+    /// A simulated device with a 64-sector LUN 0, still in Sahara
+    fn sim_channel(name: &str, faults: Vec<SimFault>) -> QdlDevice<dyn QdlReadWrite> {
+        sim_channel_with(
+            name,
+            SimConfig {
+                faults,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Like `sim_channel`, with the rest of the device set up by `cfg`
+    fn sim_channel_with(name: &str, cfg: SimConfig) -> QdlDevice<dyn QdlReadWrite> {
+        let dir = std::env::temp_dir().join(format!("qdl-sim-test-{name}"));
+        std::fs::create_dir_all(&dir).unwrap();
+        let lun0 = dir.join("lun0.img");
+        File::create(&lun0)
+            .unwrap()
+            .set_len(64 * SECTOR as u64)
+            .unwrap();
+        let dev = QdlSimDevice::new(SimConfig {
+            lun_images: vec![lun0],
+            ..cfg
+        })
+        .unwrap();
+        QdlDevice {
+            rw: Box::new(dev),
+            fh_cfg: FirehoseConfiguration {
+                backend: QdlBackend::Sim,
+                bypass_storage: false,
+                // Faults are fired once, a retry would hide them
+                query_retry: FirehoseRetryPolicy::NEVER,
+                storage_retry: FirehoseRetryPolicy::NEVER,
+                ..Default::default()
+            },
+            reset_on_drop: false,
+            log_sink: None,
+            fh_op: String::new(),
+        }
+    }
+
+    /// Load the programmer and configure the loader
+    fn sim_firehose(name: &str, faults: Vec<SimFault>) -> QdlDevice<dyn QdlReadWrite> {
+        let mut channel = sim_channel(name, faults);
+        let images = SaharaImages::from([(SIM_PROGRAMMER_IMAGE_ID, test_elf())]);
+        sahara_run(
+            &mut channel,
+            SaharaMode::WaitingForImage,
+            None,
+            &images,
+            vec![],
+            false,
+        )
+        .unwrap();
+        // Welcome logs
+        let _ = firehose_read(&mut channel, firehose_parser_ack_nak);
+        firehose_configure(&mut channel, true).unwrap();
+        firehose_read(&mut channel, firehose_parser_configure_response).unwrap();
+        channel
+    }
+
+    #[test]
+    fn sahara_handshake() {
+        let mut channel = sim_channel("handshake", vec![]);
+        let sn = sahara_run(
+            &mut channel,
+            SaharaMode::Command,
+            Some(SaharaCmdModeCmd::ReadSerialNum),
+            &SaharaImages::new(),
+            vec![],
+            false,
+        )
+        .unwrap();
+        assert_eq!(sn, 0x1234_5678u32.to_le_bytes());
+
+        let images = SaharaImages::from([(SIM_PROGRAMMER_IMAGE_ID, test_elf())]);
+        sahara_run(
+            &mut channel,
+            SaharaMode::WaitingForImage,
+            None,
+            &images,
+            vec![],
+            false,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn sahara_reset_state_restarts() {
+        let mut channel = sim_channel("reset-state", vec![]);
+        // Eat the Hello, as an aborted earlier session would have
+        let mut buf = [0u8; 64];
+        assert!(channel.read(&mut buf).unwrap() > 0);
+        let read_sn = |channel: &mut QdlDevice<dyn QdlReadWrite>| {
+            sahara_run(
+                channel,
+                SaharaMode::Command,
+                Some(SaharaCmdModeCmd::ReadSerialNum),
+                &SaharaImages::new(),
+                vec![],
+                false,
+            )
+        };
+        assert!(matches!(read_sn(&mut channel), Err(SaharaError::Timeout)));
+
+        sahara_reset_state(&mut channel).unwrap();
+        assert_eq!(read_sn(&mut channel).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn chained_digests_sized_by_config() {
+        // A loader taking 4KiB tables, sent 3 full ones and a short one
+        let chained = vec![0x11u8; 3 * 4096 + 100];
+        let vip_channel = |name: &str, table_size: usize| {
+            let mut channel = sim_channel_with(
+                name,
+                SimConfig {
+                    max_digest_table_size: 4096,
+                    ..Default::default()
+                },
+            );
+            channel.fh_cfg.max_digest_table_size = table_size;
+            let images = SaharaImages::from([(SIM_PROGRAMMER_IMAGE_ID, test_elf())]);
+            sahara_run(
+                &mut channel,
+                SaharaMode::WaitingForImage,
+                None,
+                &images,
+                vec![],
+                false,
+            )
+            .unwrap();
+            let _ = firehose_read(&mut channel, firehose_parser_ack_nak);
+            channel
+        };
+
+        let mut channel = vip_channel("vip-4k", 4096);
+        firehose_vip_handshake(&mut channel, &[0x7f; 10000], &chained).unwrap();
+
+        // The default is too much for it
+        let mut channel = vip_channel("vip-default", FIREHOSE_DEFAULT_DIGEST_TABLE_SIZE);
+        assert!(firehose_vip_handshake(&mut channel, &[0x7f; 10000], &chained).is_err());
+    }
+
+    #[test]
+    fn ufs_dry_run_never_commits() {
+        let provision = crate::xml_file_util::parser_provision_xml(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<data>
+  <ufs bNumberLU="3" bBootEnable="1" bDescrAccessEn="0" bInitPowerMode="1" bHighPriorityLUN="0x7F" bSecureRemovalType="0" bInitActiveICCLevel="0" wPeriodicRTCUpdate="0" bConfigDescrLock="0" />
+  <ufs LUNum="0" bLUEnable="1" bBootLunID="0" size_in_kb="0" bDataReliability="0" bLUWriteProtect="0" bMemoryType="0" bLogicalBlockSize="0x0c" bProvisioningType="2" wContextCapabilities="0" desc="userdata" />
+  <ufs LUNum="1" bLUEnable="1" bBootLunID="1" size_in_kb="8192" bDataReliability="1" bLUWriteProtect="0" bMemoryType="3" bLogicalBlockSize="0x0c" bProvisioningType="2" wContextCapabilities="0" desc="xbl_a" />
+  <ufs LUNtoGrow="0" commit="1" />
+</data>"#,
+        )
+        .unwrap();
+        // The device logs each commit="1" it takes
+        let commits_sent = |commit: bool| {
+            let mut channel = sim_firehose("ufs", vec![]);
+            let commits = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let counter = commits.clone();
+            channel.log_sink = Some(Box::new(move |log| {
+                if log.message.contains("committed") {
+                    counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+            }));
+            crate::firehose_backend::send_ufs_provision(&mut channel, &provision, commit).unwrap();
+            commits.load(std::sync::atomic::Ordering::Relaxed)
+        };
+
+        // Even though the XML itself says commit="1"
+        assert!(provision.commit);
+        assert_eq!(commits_sent(false), 0);
+        assert_eq!(commits_sent(true), 1);
+    }
+
+    #[test]
+    fn program_then_read() {
+        let mut channel = sim_firehose("program-read", vec![]);
+        let data: Vec<u8> = (0..2 * SECTOR).map(|i| (i % 251) as u8).collect();
+        firehose_program_storage(&mut channel, &mut &data[..], "test", 2, 0, 0, "3").unwrap();
+
+        let mut out = Vec::new();
+        firehose_read_storage(&mut channel, &mut out, 2, 0, 0, 3).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn faults_file() {
+        let dir = std::env::temp_dir().join("qdl-sim-test-faults-file");
+        std::fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("lun0.img"))
+            .unwrap()
+            .set_len(64 * SECTOR as u64)
+            .unwrap();
+        std::fs::write(
+            dir.join("faults"),
+            "# comment\nnak:program\ntimeout: read\n",
+        )
+        .unwrap();
+        let dev = setup_sim_device(Some(dir.to_str().unwrap().to_owned())).unwrap();
+        assert_eq!(
+            dev.cfg.faults,
+            [
+                SimFault::Nak("program".to_owned()),
+                SimFault::Timeout("read".to_owned())
+            ]
+        );
+        assert!("bogus:read".parse::<SimFault>().is_err());
+        assert!("nak".parse::<SimFault>().is_err());
+    }
+
+    #[test]
+    fn fault_nak() {
+        let mut channel = sim_firehose("nak", vec![SimFault::Nak("nop".to_owned())]);
+        let e = firehose_nop(&mut channel).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(FirehoseError::Nak(_))));
+        // Fired once only
+        firehose_nop(&mut channel).unwrap();
+    }
+
+    #[test]
+    fn fault_retry() {
+        let mut channel = sim_firehose("retry", vec![SimFault::Retry("nop".to_owned())]);
+        firehose_nop(&mut channel).unwrap();
+    }
+
+    #[test]
+    fn fault_restart() {
+        let mut channel = sim_firehose("restart", vec![SimFault::Restart("nop".to_owned())]);
+        let e = firehose_nop(&mut channel).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(FirehoseError::RestartRequested)
+        ));
+    }
+
+    #[test]
+    fn fault_timeout() {
+        let mut channel = sim_firehose("timeout", vec![SimFault::Timeout("nop".to_owned())]);
+        let e = firehose_nop(&mut channel).unwrap_err();
+        assert!(
+            format!("{e:#}").to_lowercase().contains("timed out"),
+            "{e:#}"
+        );
+    }
+
+    #[test]
+    fn fault_short_read() {
+        let mut channel = sim_firehose("short-read", vec![SimFault::ShortRead("read".to_owned())]);
+        let mut out = Vec::new();
+        assert!(firehose_read_storage(&mut channel, &mut out, 2, 0, 0, 0).is_err());
+        assert_eq!(out.len(), SECTOR);
+    }
+}
@@ -0,0 +1,279 @@
+// SPDX-License-Identifier: BSD-3-Clause
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+
+//! Android sparse images, as produced by img2simg and the AOSP build.
+//!
+//! A 28 byte file header is followed by `total_chunks` chunks, each with a
+//! 12 byte header telling how many output blocks it covers. Raw chunks
+//! carry the blocks, fill chunks a 4 byte pattern repeated over them,
+//! don't-care chunks nothing (the blocks are left alone, simg2img leaves
+//! holes that read back as zeros) and CRC32 chunks a checksum of the data
+//! so far, covering no blocks.
+
+use anyhow::{Result, bail};
+use std::cmp::min;
+use std::io::{self, Read, Seek, SeekFrom};
+
+pub const SPARSE_HEADER_MAGIC: u32 = 0xed26ff3a;
+const SPARSE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SparseChunkData {
+    /// The blocks are stored in the sparse file, starting at this offset
+    Raw {
+        file_offset: u64,
+    },
+    /// Every 4 bytes of the blocks hold this pattern
+    Fill([u8; 4]),
+    DontCare,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SparseChunk {
+    /// First block of the expanded image the chunk covers
+    pub start_block: u64,
+    pub num_blocks: u64,
+    pub data: SparseChunkData,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SparseImage {
+    pub block_size: u32,
+    pub total_blocks: u64,
+    /// In block order, without the CRC32 ones
+    pub chunks: Vec<SparseChunk>,
+}
+
+impl SparseImage {
+    /// Size of the image once expanded, as simg2img would write it
+    pub fn expanded_len(&self) -> u64 {
+        self.total_blocks * self.block_size as u64
+    }
+}
+
+/// A stretch of the expanded image that is written in one go
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SparseRun {
+    pub start_block: u64,
+    pub num_blocks: u64,
+    /// Nothing to send, the blocks just have to end up zeroed
+    pub zero: bool,
+}
+
+/// Merge the chunks of `image` into runs of data (raw and fill chunks) and
+/// runs of zeros (zero fills and don't-care chunks). Don't-care blocks are
+/// zeroed rather than left alone, so that the device ends up holding the
+/// expanded image, like when flashing the output of simg2img.
+pub fn sparse_runs(image: &SparseImage) -> Vec<SparseRun> {
+    let mut runs: Vec<SparseRun> = Vec::new();
+    for chunk in &image.chunks {
+        let zero = matches!(
+            chunk.data,
+            SparseChunkData::Fill([0, 0, 0, 0]) | SparseChunkData::DontCare
+        );
+        match runs.last_mut() {
+            Some(run) if run.zero == zero => run.num_blocks += chunk.num_blocks,
+            _ => runs.push(SparseRun {
+                start_block: chunk.start_block,
+                num_blocks: chunk.num_blocks,
+                zero,
+            }),
+        }
+    }
+    runs
+}
+
+fn le_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Walk the chunk headers of the sparse image `file`, from its start
+pub fn sparse_parse<R: Read + Seek>(file: &mut R) -> Result<SparseImage> {
+    let mut hdr = [0u8; SPARSE_HEADER_SIZE];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut hdr)?;
+
+    if le_u32(&hdr, 0) != SPARSE_HEADER_MAGIC {
+        bail!("Not a sparse image");
+    }
+    let major_version = le_u16(&hdr, 4);
+    if major_version != 1 {
+        bail!("Unsupported sparse image version {major_version}");
+    }
+    let file_hdr_sz = le_u16(&hdr, 8) as u64;
+    let chunk_hdr_sz = le_u16(&hdr, 10) as u64;
+    let block_size = le_u32(&hdr, 12);
+    let total_blocks = le_u32(&hdr, 16) as u64;
+    let total_chunks = le_u32(&hdr, 20);
+    if file_hdr_sz < SPARSE_HEADER_SIZE as u64 || chunk_hdr_sz < CHUNK_HEADER_SIZE as u64 {
+        bail!("Sparse image has truncated headers");
+    }
+    if block_size == 0 || !block_size.is_multiple_of(4) {
+        bail!("Invalid sparse block size {block_size}");
+    }
+
+    // Every chunk takes at least a header, a count the file can't hold is
+    // garbage and must not size the allocation
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let max_chunks = file_len.saturating_sub(file_hdr_sz) / chunk_hdr_sz;
+    if total_chunks as u64 > max_chunks {
+        bail!(
+            "Sparse image claims {total_chunks} chunks, a {file_len} byte file holds at most {max_chunks}"
+        );
+    }
+
+    let mut chunks = Vec::with_capacity(total_chunks as usize);
+    let mut offset = file_hdr_sz;
+    let mut block = 0;
+    for idx in 0..total_chunks {
+        let mut chdr = [0u8; CHUNK_HEADER_SIZE];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut chdr)?;
+        let chunk_type = le_u16(&chdr, 0);
+        let num_blocks = le_u32(&chdr, 4) as u64;
+        let total_sz = le_u32(&chdr, 8) as u64;
+        let data_offset = offset + chunk_hdr_sz;
+        let data_sz = total_sz
+            .checked_sub(chunk_hdr_sz)
+            .ok_or_else(|| anyhow::anyhow!("Chunk {idx} is smaller than its header"))?;
+
+        let data = match chunk_type {
+            CHUNK_TYPE_RAW => {
+                if data_sz != num_blocks * block_size as u64 {
+                    bail!("Raw chunk {idx} holds {data_sz} bytes for {num_blocks} blocks");
+                }
+                SparseChunkData::Raw {
+                    file_offset: data_offset,
+                }
+            }
+            CHUNK_TYPE_FILL => {
+                if data_sz != 4 {
+                    bail!("Fill chunk {idx} holds {data_sz} bytes instead of 4");
+                }
+                let mut pattern = [0u8; 4];
+                file.read_exact(&mut pattern)?;
+                SparseChunkData::Fill(pattern)
+            }
+            CHUNK_TYPE_DONT_CARE => SparseChunkData::DontCare,
+            CHUNK_TYPE_CRC32 => {
+                offset += total_sz;
+                continue;
+            }
+            _ => bail!("Unknown sparse chunk type {chunk_type:#x} in chunk {idx}"),
+        };
+        if num_blocks > 0 {
+            chunks.push(SparseChunk {
+                start_block: block,
+                num_blocks,
+                data,
+            });
+        }
+        block += num_blocks;
+        offset += total_sz;
+    }
+
+    if block != total_blocks {
+        bail!("Sparse chunks cover {block} blocks, the header says {total_blocks}");
+    }
+
+    Ok(SparseImage {
+        block_size,
+        total_blocks,
+        chunks,
+    })
+}
+
+/// Reads the expanded contents of a sparse image, as simg2img would write
+/// them. Don't-care blocks read as zeros.
+pub struct SparseReader<R> {
+    file: R,
+    image: SparseImage,
+    /// Position in the expanded image
+    pos: u64,
+    chunk_idx: usize,
+}
+
+impl<R: Read + Seek> SparseReader<R> {
+    pub fn new(mut file: R) -> Result<Self> {
+        let image = sparse_parse(&mut file)?;
+        Ok(Self {
+            file,
+            image,
+            pos: 0,
+            chunk_idx: 0,
+        })
+    }
+    pub fn image(&self) -> &SparseImage {
+        &self.image
+    }
+
+    /// Continue reading from the start of `block` of the expanded image
+    pub fn seek_block(&mut self, block: u64) {
+        self.pos = block * self.image.block_size as u64;
+        self.chunk_idx = self
+            .image
+            .chunks
+            .partition_point(|chunk| chunk.start_block + chunk.num_blocks <= block);
+    }
+}
+
+impl<R: Read + Seek> Read for SparseReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let block_size = self.image.block_size as u64;
+        let Some(chunk) = self.image.chunks.get(self.chunk_idx).copied() else {
+            return Ok(0);
+        };
+        let chunk_start = chunk.start_block * block_size;
+        let chunk_end = chunk_start + chunk.num_blocks * block_size;
+        let offset = self.pos - chunk_start;
+        let len = min(buf.len() as u64, chunk_end - self.pos) as usize;
+        let buf = &mut buf[..len];
+
+        match chunk.data {
+            SparseChunkData::Raw { file_offset } => {
+                self.file.seek(SeekFrom::Start(file_offset + offset))?;
+                self.file.read_exact(buf)?;
+            }
+            SparseChunkData::Fill(pattern) => {
+                for (i, b) in buf.iter_mut().enumerate() {
+                    *b = pattern[(offset as usize + i) % 4];
+                }
+            }
+            SparseChunkData::DontCare => buf.fill(0),
+        }
+
+        self.pos += len as u64;
+        if self.pos == chunk_end {
+            self.chunk_idx += 1;
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn chunk_count_bounded_by_file() {
+        let mut hdr = [0u8; SPARSE_HEADER_SIZE];
+        hdr[0..4].copy_from_slice(&SPARSE_HEADER_MAGIC.to_le_bytes());
+        hdr[4..6].copy_from_slice(&1u16.to_le_bytes());
+        hdr[8..10].copy_from_slice(&(SPARSE_HEADER_SIZE as u16).to_le_bytes());
+        hdr[10..12].copy_from_slice(&(CHUNK_HEADER_SIZE as u16).to_le_bytes());
+        hdr[12..16].copy_from_slice(&4096u32.to_le_bytes());
+        hdr[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = sparse_parse(&mut Cursor::new(hdr.to_vec())).unwrap_err();
+        assert!(err.to_string().contains("at most 0"), "{err}");
+    }
+}
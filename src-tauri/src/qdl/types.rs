@@ -5,11 +5,13 @@ use std::{
     fmt::Display,
     io::{BufRead, ErrorKind, Read, Write},
     str::FromStr,
+    time::Duration,
 };
 
-use crate::qdl::firehose::firehose_reset;
+use crate::qdl::firehose::{FIREHOSE_DEFAULT_DIGEST_TABLE_SIZE, firehose_reset};
 use anyhow::{Error, bail};
 use owo_colors::OwoColorize;
+use serde::Serialize;
 
 /// Common respones indicating success/failure respectively
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -19,10 +21,59 @@ pub enum FirehoseStatus {
     Nak = 1,
 }
 
+/// Failures the recovery layer acts upon, carried through anyhow::Error
+#[derive(Debug)]
+pub enum FirehoseError {
+    /// The loader answered NAK, with what couldn't be done
+    Nak(String),
+    /// The loader sent AttemptRestart, it has to be loaded again
+    RestartRequested,
+    /// The loader still expects (or sends) rawmode data, XML would be
+    /// taken for part of it
+    InRawMode,
+}
+
+impl Display for FirehoseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FirehoseError::Nak(what) => write!(f, "{what}"),
+            FirehoseError::RestartRequested => write!(f, "Firehose requested a restart"),
+            FirehoseError::InRawMode => {
+                write!(f, "Firehose is in the middle of a rawmode transfer")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FirehoseError {}
+
+/// How an operation the loader NAKed is retried
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FirehoseRetryPolicy {
+    /// Attempts after the first one
+    pub retries: u32,
+    pub delay: Duration,
+    /// Reset the board to EDL once out of retries, for loaders that are
+    /// known to hang after a NAK
+    pub reset_after: bool,
+}
+
+impl FirehoseRetryPolicy {
+    pub const NEVER: Self = Self {
+        retries: 0,
+        delay: Duration::ZERO,
+        reset_after: false,
+    };
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum QdlBackend {
     Serial,
     Usb,
+    /// In-process simulated device, see qdl::sim
+    Sim,
+    /// Device side of a recorded session, see qdl::capture
+    Replay,
 }
 
 impl FromStr for QdlBackend {
@@ -32,6 +83,8 @@ impl FromStr for QdlBackend {
         match s {
             "serial" => Ok(QdlBackend::Serial),
             "usb" => Ok(QdlBackend::Usb),
+            "sim" => Ok(QdlBackend::Sim),
+            "replay" => Ok(QdlBackend::Replay),
             _ => bail!("Unknown backend"),
         }
     }
@@ -52,6 +105,9 @@ pub struct FirehoseConfiguration {
     pub send_buffer_size: usize,
     pub recv_buffer_size: usize,
     pub xml_buf_size: usize,
+    // Largest chained digest table the loader takes in one go (VIP). Has to
+    // be known ahead, <configure> only reports it once the tables are in.
+    pub max_digest_table_size: usize,
 
     pub storage_sector_size: usize,
     pub storage_type: FirehoseStorageType,
@@ -64,6 +120,21 @@ pub struct FirehoseConfiguration {
     pub skip_usb_zlp: bool,
     pub skip_firehose_log: bool,
     pub verbose_firehose: bool,
+
+    // Channel-wide defaults, applied through QdlChan::set_timeouts()
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    // Extra time granted per MiB moved by program/read/digest operations
+    pub xfer_timeout_per_mib: Duration,
+
+    // Per-operation retry policies, see retry_policy()
+    pub query_retry: FirehoseRetryPolicy,
+    pub storage_retry: FirehoseRetryPolicy,
+    pub control_retry: FirehoseRetryPolicy,
+
+    // Not a setting: set while the loader is in rawmode, as told by the
+    // rawmode="true"/"false" responses
+    pub rawmode: bool,
 }
 
 impl Default for FirehoseConfiguration {
@@ -72,6 +143,7 @@ impl Default for FirehoseConfiguration {
             send_buffer_size: 1024 * 1024,
             recv_buffer_size: 4096,
             xml_buf_size: 4096,
+            max_digest_table_size: FIREHOSE_DEFAULT_DIGEST_TABLE_SIZE,
             storage_sector_size: 4096,
             storage_type: FirehoseStorageType::Ufs,
             bypass_storage: true,
@@ -82,16 +154,128 @@ impl Default for FirehoseConfiguration {
             skip_usb_zlp: cfg!(target_os = "macos"),
             skip_firehose_log: true,
             verbose_firehose: false,
+            read_timeout: Duration::from_secs(2),
+            write_timeout: Duration::from_secs(5),
+            xfer_timeout_per_mib: Duration::from_secs(1),
+            query_retry: FirehoseRetryPolicy {
+                retries: 2,
+                delay: Duration::from_millis(200),
+                reset_after: false,
+            },
+            storage_retry: FirehoseRetryPolicy {
+                retries: 1,
+                delay: Duration::from_millis(500),
+                reset_after: false,
+            },
+            control_retry: FirehoseRetryPolicy::NEVER,
+            rawmode: false,
         }
     }
 }
+
+impl FirehoseConfiguration {
+    /// Timeout budget for an operation moving `bytes` of data
+    pub fn xfer_timeout(&self, bytes: usize) -> Duration {
+        let mibs = bytes.div_ceil(1024 * 1024) as u32;
+        self.read_timeout.max(self.write_timeout) + self.xfer_timeout_per_mib * mibs
+    }
+
+    /// Retry policy of the `op` Firehose command: reading things back is
+    /// retried the most, storage writes a little, and commands changing the
+    /// loader's state (configure, power, VIP...) not at all
+    pub fn retry_policy(&self, op: &str) -> FirehoseRetryPolicy {
+        match op {
+            "nop" | "getstorageinfo" | "getsha256digest" | "read" | "peek" | "benchmark" => {
+                self.query_retry
+            }
+            "program" | "erase" | "zeroout" | "patch" | "poke" | "setbootablestoragedrive" => {
+                self.storage_retry
+            }
+            _ => self.control_retry,
+        }
+    }
+}
+
+/// How bad a \<log\> message of the loader is
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FirehoseLogSeverity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl FirehoseLogSeverity {
+    /// Loaders flag their messages with a prefix, e.g. "ERROR: ..."
+    pub fn from_message(msg: &str) -> Self {
+        let msg = msg.trim_start().to_ascii_uppercase();
+        if msg.starts_with("ERROR") || msg.starts_with("FAIL") {
+            FirehoseLogSeverity::Error
+        } else if msg.starts_with("WARN") {
+            FirehoseLogSeverity::Warning
+        } else {
+            FirehoseLogSeverity::Info
+        }
+    }
+}
+
+/// A \<log\> message, with the command it came in reply to
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FirehoseLog {
+    pub op: String,
+    pub severity: FirehoseLogSeverity,
+    pub message: String,
+}
+
+impl FirehoseLog {
+    pub fn new(op: &str, message: String) -> Self {
+        Self {
+            op: op.to_owned(),
+            severity: FirehoseLogSeverity::from_message(&message),
+            message,
+        }
+    }
+}
+
+/// Where the \<log\> messages go instead of stdout, see QdlChan::firehose_log()
+pub type FirehoseLogSink = Box<dyn FnMut(&FirehoseLog) + Send + Sync>;
+
 pub trait QdlChan: BufRead + Write {
     fn fh_config(&self) -> &FirehoseConfiguration;
     fn mut_fh_config(&mut self) -> &mut FirehoseConfiguration;
+
+    /// The Firehose command last sent, logs are tagged with it
+    fn firehose_op(&self) -> &str {
+        ""
+    }
+    fn set_firehose_op(&mut self, _op: &str) {}
+
+    /// Hand `log` over to the log sink. False if there's none, it's then up
+    /// to skip_firehose_log.
+    fn firehose_log(&mut self, _log: &FirehoseLog) -> bool {
+        false
+    }
+
+    /// Override the read/write timeouts of the underlying transport
+    fn set_timeouts(&mut self, _read: Duration, _write: Duration) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
-pub trait QdlReadWrite: BufRead + Write + Send + Sync {}
-impl<T> QdlReadWrite for &mut T where T: QdlReadWrite + ?Sized {}
+pub trait QdlReadWrite: BufRead + Write + Send + Sync {
+    /// Transports that can't time out (e.g. in-memory ones) keep the no-op
+    fn set_timeouts(&mut self, _read: Duration, _write: Duration) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+impl<T> QdlReadWrite for &mut T
+where
+    T: QdlReadWrite + ?Sized,
+{
+    fn set_timeouts(&mut self, read: Duration, write: Duration) -> std::io::Result<()> {
+        (**self).set_timeouts(read, write)
+    }
+}
 
 pub struct QdlDevice<T>
 where
@@ -100,6 +284,8 @@ where
     pub rw: Box<T>,
     pub fh_cfg: FirehoseConfiguration,
     pub reset_on_drop: bool,
+    pub log_sink: Option<FirehoseLogSink>,
+    pub fh_op: String,
 }
 
 impl<T> Read for QdlDevice<T>
@@ -148,6 +334,28 @@ where
     fn mut_fh_config(&mut self) -> &mut FirehoseConfiguration {
         &mut self.fh_cfg
     }
+
+    fn firehose_op(&self) -> &str {
+        &self.fh_op
+    }
+
+    fn set_firehose_op(&mut self, op: &str) {
+        op.clone_into(&mut self.fh_op);
+    }
+
+    fn firehose_log(&mut self, log: &FirehoseLog) -> bool {
+        match &mut self.log_sink {
+            Some(sink) => {
+                sink(log);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn set_timeouts(&mut self, read: Duration, write: Duration) -> std::io::Result<()> {
+        self.rw.set_timeouts(read, write)
+    }
 }
 
 impl<T> Drop for QdlDevice<T>
@@ -156,8 +364,13 @@ where
 {
     fn drop(&mut self) {
         // Avoid having the board be stuck in EDL limbo in case of errors
-        // TODO: watch 'rawmode' and adjust accordingly
-        if self.reset_on_drop {
+        if self.reset_on_drop && self.fh_cfg.rawmode {
+            // A <power> now would be taken for rawmode data
+            println!(
+                "Firehose {} in the middle of a transfer. Power cycle the board.",
+                "failed".bright_red()
+            );
+        } else if self.reset_on_drop {
             println!(
                 "Firehose {}. Resetting the board to {}, try again.",
                 "failed".bright_red(),
@@ -205,6 +418,45 @@ impl Display for FirehoseStorageType {
     }
 }
 
+/// Outcome of \<benchmark\> trials with one payload size
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct BenchmarkResult {
+    /// "read" or "write"
+    pub operation: String,
+    pub payload_size: usize,
+    pub trials: u32,
+    /// Time the loader took for all trials, if it said
+    pub time_us: Option<u64>,
+    /// MB/s, as reported by the loader or worked out from `time_us`
+    pub throughput_mbps: Option<f64>,
+}
+
+/// What \<getstorageinfo\> reports about one physical partition (LUN)
+/// and the medium it lives on
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct StorageInfo {
+    /// The LUN the block counts are about
+    pub physical_partition: u8,
+    pub total_blocks: u64,
+    pub block_size: usize,
+    pub page_size: usize,
+    /// Number of LUNs of the medium
+    pub num_physical: u8,
+    pub serial_num: u64,
+    pub manufacturer: String,
+    pub product: String,
+    pub firmware_revision: String,
+    pub mem_type: String,
+    /// The boot LUN selected through \<setbootablestoragedrive\>
+    pub boot_lun_enabled: Option<u8>,
+}
+
+impl StorageInfo {
+    pub fn size_in_bytes(&self) -> u64 {
+        self.total_blocks * self.block_size as u64
+    }
+}
+
 /// List of supported reboot modes, supplied to the \<reset\> command
 pub enum FirehoseResetMode {
     ResetToEdl,
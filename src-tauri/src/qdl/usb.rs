@@ -0,0 +1,258 @@
+// SPDX-License-Identifier: BSD-3-Clause
+// Copyright (c) Qualcomm Technologies, Inc. and/or its subsidiaries.
+use anyhow::{Result, bail};
+use itertools::Itertools;
+use rusb::{Device, DeviceHandle, Direction, GlobalContext, TransferType};
+use std::io::{BufRead, ErrorKind, Read, Write};
+use std::time::Duration;
+
+use crate::qdl::types::QdlReadWrite;
+
+/// Qualcomm vendor ID
+pub const QCOM_USB_VID: u16 = 0x05c6;
+/// Product ID of a device in Emergency Download (9008) mode
+pub const QCOM_EDL_USB_PID: u16 = 0x9008;
+/// Product ID of a device that crashed into Sahara memory debug mode
+pub const QCOM_RAMDUMP_USB_PID: u16 = 0x900e;
+
+/// Whether the VID/PID pair belongs to a device speaking Sahara
+pub fn is_edl_usb_id(vid: u16, pid: u16) -> bool {
+    vid == QCOM_USB_VID && (pid == QCOM_EDL_USB_PID || pid == QCOM_RAMDUMP_USB_PID)
+}
+
+/// Physical location of the device, in the same "bus-port.port" form the
+/// Linux sysfs uses (e.g. "1-2.3")
+pub fn usb_bus_location(device: &Device<GlobalContext>) -> String {
+    match device.port_numbers() {
+        Ok(ports) if !ports.is_empty() => format!(
+            "{}-{}",
+            device.bus_number(),
+            ports.iter().map(|p| p.to_string()).join(".")
+        ),
+        _ => format!("{}-0", device.bus_number()),
+    }
+}
+
+// Sahara/Firehose have no notion of a keepalive, so an idle bulk pipe only
+// means "nothing to say" and the callers rely on TimedOut to notice that.
+const USB_DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct QdlUsbConfig {
+    dev_handle: DeviceHandle<GlobalContext>,
+    interface: u8,
+    in_ep: u8,
+    out_ep: u8,
+    max_packet_size: usize,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    buf: Vec<u8>,
+    pos: usize,
+    cap: usize,
+}
+
+fn usb_err_to_io(e: rusb::Error) -> std::io::Error {
+    let kind = match e {
+        rusb::Error::Timeout => ErrorKind::TimedOut,
+        rusb::Error::NoDevice => ErrorKind::NotConnected,
+        rusb::Error::Pipe => ErrorKind::BrokenPipe,
+        rusb::Error::Interrupted => ErrorKind::Interrupted,
+        rusb::Error::Access => ErrorKind::PermissionDenied,
+        rusb::Error::NotFound => ErrorKind::NotFound,
+        _ => ErrorKind::Other,
+    };
+    std::io::Error::new(kind, e)
+}
+
+impl QdlUsbConfig {
+    fn read_bulk(&mut self, out: &mut [u8]) -> Result<usize, std::io::Error> {
+        self.dev_handle
+            .read_bulk(self.in_ep, out, self.read_timeout)
+            .map_err(usb_err_to_io)
+    }
+}
+
+impl Write for QdlUsbConfig {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        // An empty buffer is sent as-is, which is how callers issue a ZLP
+        self.dev_handle
+            .write_bulk(self.out_ep, buf, self.write_timeout)
+            .map_err(usb_err_to_io)
+    }
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl Read for QdlUsbConfig {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, std::io::Error> {
+        // Drain internal buffer first
+        if self.pos < self.cap {
+            let n = std::cmp::min(out.len(), self.cap - self.pos);
+            out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+
+        // A zero-length read is used to consume a ZLP, and reads that are a
+        // multiple of wMaxPacketSize can't overflow, so pass those straight
+        // through. Anything else is bounced through the internal buffer.
+        if out.is_empty() || out.len().is_multiple_of(self.max_packet_size) {
+            return self.read_bulk(out);
+        }
+
+        let n = {
+            let available = self.fill_buf()?;
+            let n = std::cmp::min(out.len(), available.len());
+            out[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for QdlUsbConfig {
+    fn fill_buf(&mut self) -> Result<&[u8], std::io::Error> {
+        if self.pos >= self.cap {
+            self.pos = 0;
+            self.cap = 0;
+            if self.buf.is_empty() {
+                self.buf.resize(4096, 0);
+            }
+            let mut buf = std::mem::take(&mut self.buf);
+            let ret = self.read_bulk(&mut buf);
+            self.buf = buf;
+            self.cap = ret?;
+        }
+        Ok(&self.buf[self.pos..self.cap])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = std::cmp::min(self.pos + amt, self.cap);
+    }
+}
+
+impl Drop for QdlUsbConfig {
+    fn drop(&mut self) {
+        let _ = self.dev_handle.release_interface(self.interface);
+    }
+}
+
+impl QdlReadWrite for QdlUsbConfig {
+    fn set_timeouts(&mut self, read: Duration, write: Duration) -> Result<(), std::io::Error> {
+        self.read_timeout = read;
+        self.write_timeout = write;
+        Ok(())
+    }
+}
+
+/// Find the (interface, IN endpoint, OUT endpoint, wMaxPacketSize) tuple of the
+/// vendor-specific interface the EDL device exposes
+fn find_bulk_endpoints(device: &Device<GlobalContext>) -> Result<(u8, u8, u8, usize)> {
+    let config = device.active_config_descriptor()?;
+
+    for interface in config.interfaces() {
+        for desc in interface.descriptors() {
+            let mut in_ep = None;
+            let mut out_ep = None;
+            let mut max_packet_size = 0;
+
+            for ep in desc.endpoint_descriptors() {
+                if ep.transfer_type() != TransferType::Bulk {
+                    continue;
+                }
+                match ep.direction() {
+                    Direction::In => in_ep = Some(ep.address()),
+                    Direction::Out => out_ep = Some(ep.address()),
+                }
+                max_packet_size = ep.max_packet_size() as usize;
+            }
+
+            if let (Some(in_ep), Some(out_ep)) = (in_ep, out_ep) {
+                return Ok((desc.interface_number(), in_ep, out_ep, max_packet_size));
+            }
+        }
+    }
+
+    bail!("No bulk IN/OUT endpoint pair found")
+}
+
+/// Open an EDL device, optionally picking the one with the given USB serial
+/// number or bus location
+pub fn setup_usb_device(dev_id: Option<String>) -> Result<QdlUsbConfig> {
+    for device in rusb::devices()?.iter() {
+        let desc = match device.device_descriptor() {
+            Ok(desc) => desc,
+            Err(_) => continue,
+        };
+        if !is_edl_usb_id(desc.vendor_id(), desc.product_id()) {
+            continue;
+        }
+
+        let dev_handle = match device.open() {
+            Ok(handle) => handle,
+            Err(e) => {
+                eprintln!(
+                    "Couldn't open EDL device on bus {} addr {}: {}",
+                    device.bus_number(),
+                    device.address(),
+                    e
+                );
+                continue;
+            }
+        };
+
+        if let Some(wanted) = &dev_id {
+            let sn = dev_handle
+                .read_serial_number_string_ascii(&desc)
+                .unwrap_or_default();
+            if !sn.eq_ignore_ascii_case(wanted) && usb_bus_location(&device) != *wanted {
+                continue;
+            }
+        }
+
+        // Another EDL device on the bus may still do
+        let (interface, in_ep, out_ep, max_packet_size) = match find_bulk_endpoints(&device) {
+            Ok(endpoints) => endpoints,
+            Err(e) => {
+                eprintln!(
+                    "Skipping EDL device on bus {} addr {}: {}",
+                    device.bus_number(),
+                    device.address(),
+                    e
+                );
+                continue;
+            }
+        };
+
+        // Kick qcserial & co. off the interface (Linux only, fails elsewhere)
+        let _ = dev_handle.set_auto_detach_kernel_driver(true);
+        if let Err(e) = dev_handle.claim_interface(interface) {
+            eprintln!(
+                "Couldn't claim EDL device on bus {} addr {}: {}",
+                device.bus_number(),
+                device.address(),
+                e
+            );
+            continue;
+        }
+
+        return Ok(QdlUsbConfig {
+            dev_handle,
+            interface,
+            in_ep,
+            out_ep,
+            max_packet_size: max_packet_size.max(1),
+            read_timeout: USB_DEFAULT_TIMEOUT,
+            write_timeout: USB_DEFAULT_TIMEOUT,
+            buf: Vec::new(),
+            pos: 0,
+            cap: 0,
+        });
+    }
+
+    match dev_id {
+        Some(id) => bail!("EDL device {id} not found"),
+        None => bail!("No EDL device found"),
+    }
+}
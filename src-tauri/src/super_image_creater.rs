@@ -134,8 +134,6 @@ fn exec_cmd(cmd: &str, args: Vec<String>, current_dir: &Path) -> bool {
 struct EnvConfig {
     lpmake_path: String,
     lpmake_path_linux: String,
-    simg2img_path: String,
-    simg2img_path_linux: String,
     work_dir: PathBuf,
 }
 
@@ -143,8 +141,6 @@ fn get_runtime_env(path: &str) -> EnvConfig {
     let mut config = EnvConfig {
         lpmake_path: String::new(),
         lpmake_path_linux: String::new(),
-        simg2img_path: String::new(),
-        simg2img_path_linux: String::new(),
         work_dir: PathBuf::new(),
     };
     let current_exe = match env::current_exe() {
@@ -155,8 +151,6 @@ fn get_runtime_env(path: &str) -> EnvConfig {
     let tools_dir = parent_dir.join("tools");
     let lpmake_path = tools_dir.join("lpmake.exe");
     let lpmake_path_linux = tools_dir.join("lpmake");
-    let simg2img_path = tools_dir.join("simg2img.exe");
-    let simg2img_path_linux = tools_dir.join("simg2img");
 
     let (_file_name, dir_path) = file_util::parse_file_path("", &path);
     let work_dir = PathBuf::from(dir_path)
@@ -166,11 +160,6 @@ fn get_runtime_env(path: &str) -> EnvConfig {
 
     config.lpmake_path = lpmake_path.to_str().unwrap_or("lpmake.exe").to_string();
     config.lpmake_path_linux = lpmake_path_linux.to_str().unwrap_or("lpmake").to_string();
-    config.simg2img_path = simg2img_path.to_str().unwrap_or("simg2img.exe").to_string();
-    config.simg2img_path_linux = simg2img_path_linux
-        .to_str()
-        .unwrap_or("simg2img")
-        .to_string();
     config.work_dir = work_dir;
     return config;
 }
@@ -209,23 +198,15 @@ pub fn creat_super_image(path: &str) -> bool {
                             partition.name, partition.group_name
                         ));
                     } else {
-                        let mut simg_args = Vec::<String>::new();
-                        simg_args.push(format!("/c"));
-                        simg_args.push(format!("{}", env_config.simg2img_path));
-                        simg_args.push(partition.path.clone());
-                        simg_args.push(format!("{}.raw", &partition.path));
-
                         args.push(format!("--partition"));
                         args.push(format!(
                             "{}:readonly:{}:{}",
                             partition.name, partition.size, partition.group_name
                         ));
+                        // lpmake reads sparse images as they are, no need
+                        // for simg2img'd copies
                         args.push(format!("--image"));
-                        if exec_cmd("cmd", simg_args, env_config.work_dir.as_path()) {
-                            args.push(format!("{}={}.raw", partition.name, &partition.path));
-                        } else {
-                            args.push(format!("{}={}", partition.name, &partition.path));
-                        }
+                        args.push(format!("{}={}", partition.name, &partition.path));
                     }
                 }
                 args.push(format!("-F"));
@@ -1,7 +1,9 @@
 use crate::file_util;
+use anyhow::bail;
 use quick_xml::de::from_str;
 use quick_xml::se::to_string;
-use serde::{Deserialize, Serialize, Serializer};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt::Write;
 
 // Define struct for the root <data> node in XML
@@ -19,6 +21,10 @@ pub struct DataRoot {
     // Match multiple <erase> child nodes under <data>
     #[serde(rename = "erase", default)]
     pub erase_tags: Vec<EraseTag>,
+
+    // Match multiple <patch> child nodes under <data>
+    #[serde(rename = "patch", default)]
+    pub patches: Vec<PatchTag>,
 }
 
 // Define struct for the <program> node (matches all attributes)
@@ -87,6 +93,29 @@ pub struct ReadTag {
     pub sparse: bool,
 }
 
+// Define struct for the <patch> node (matches all attributes)
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+#[serde(rename = "patch")]
+pub struct PatchTag {
+    #[serde(rename = "@SECTOR_SIZE_IN_BYTES")]
+    pub sector_size_in_bytes: u64,
+    #[serde(rename = "@byte_offset")]
+    pub byte_offset: u64,
+    // "DISK" for the device's storage, a host-side file name otherwise
+    #[serde(rename = "@filename")]
+    pub filename: String,
+    #[serde(rename = "@physical_partition_number")]
+    pub physical_partition_number: u8,
+    #[serde(rename = "@size_in_bytes")]
+    pub size_in_bytes: u64,
+    #[serde(rename = "@start_sector")]
+    pub start_sector: String,
+    #[serde(rename = "@value")]
+    pub value: String,
+    #[serde(rename = "@what", default)]
+    pub what: String,
+}
+
 fn serialize_size_in_kb<S>(value: &f64, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -100,11 +129,11 @@ pub fn create_program_dynamic(
     lun: u8,
     start_sector: u64,
     num_partition_sectors: u64,
+    sector_size: u64,
     label: &str,
 ) -> Program {
-    let size_in_kb = num_partition_sectors as f64 * 4.0;
-    let sector_size = 4096;
-    let start_byte = start_sector as u64 * sector_size as u64;
+    let size_in_kb = (num_partition_sectors * sector_size) as f64 / 1024.0;
+    let start_byte = start_sector * sector_size;
     let start_byte_hex = format!("{:X}", start_byte);
 
     Program {
@@ -118,7 +147,7 @@ pub fn create_program_dynamic(
         filename: format!("{}.img", label).to_string(),
         sparse: false,
         start_byte_hex,
-        sector_size_in_bytes: 4096,
+        sector_size_in_bytes: sector_size,
         label: label.to_string(),
     }
 }
@@ -128,6 +157,7 @@ pub fn create_read_tag_dynamic(
     lun: u8,
     start_sector: u64,
     num_partition_sectors: u64,
+    sector_size: u64,
     label: &str,
 ) -> ReadTag {
     ReadTag {
@@ -136,7 +166,7 @@ pub fn create_read_tag_dynamic(
         label: label.to_string(),
         start_sector,
         num_partition_sectors,
-        sector_size_in_bytes: 4096,
+        sector_size_in_bytes: sector_size,
         sparse: false,
     }
 }
@@ -284,3 +314,268 @@ pub fn to_xml<T: serde::Serialize>(tag: &T) -> String {
     };
     return read_xml;
 }
+
+// Define struct for the root <data> node of a provision_*.xml
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename = "data")]
+struct ProvisionRoot {
+    // All the nodes are <ufs>, told apart by their attributes
+    #[serde(rename = "ufs", default)]
+    ufs_tags: Vec<UfsTag>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum UfsTag {
+    Common(UfsCommon),
+    Lun(UfsLun),
+    Commit(UfsCommit),
+}
+
+// Define struct for the device wide <ufs bNumberLU=...> node
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct UfsCommon {
+    #[serde(rename = "@bNumberLU", deserialize_with = "deserialize_num")]
+    pub number_lu: u8,
+    #[serde(rename = "@bBootEnable", deserialize_with = "deserialize_num")]
+    pub boot_enable: u8,
+    #[serde(rename = "@bDescrAccessEn", deserialize_with = "deserialize_num")]
+    pub descr_access_en: u8,
+    #[serde(rename = "@bInitPowerMode", deserialize_with = "deserialize_num")]
+    pub init_power_mode: u8,
+    #[serde(rename = "@bHighPriorityLUN", deserialize_with = "deserialize_num")]
+    pub high_priority_lun: u8,
+    #[serde(rename = "@bSecureRemovalType", deserialize_with = "deserialize_num")]
+    pub secure_removal_type: u8,
+    #[serde(rename = "@bInitActiveICCLevel", deserialize_with = "deserialize_num")]
+    pub init_active_icc_level: u8,
+    #[serde(rename = "@wPeriodicRTCUpdate", deserialize_with = "deserialize_num")]
+    pub periodic_rtc_update: u16,
+    // Once committed with 1, the configuration descriptors can't be written again
+    #[serde(rename = "@bConfigDescrLock", deserialize_with = "deserialize_num")]
+    pub config_descr_lock: u8,
+    // Write booster attributes, only in the provision XMLs of UFS 3.1 and later
+    #[serde(
+        rename = "@bWriteBoosterBufferPreserveUserSpaceEn",
+        deserialize_with = "deserialize_opt_num",
+        default
+    )]
+    pub wb_preserve_user_space_en: Option<u8>,
+    #[serde(
+        rename = "@bWriteBoosterBufferType",
+        deserialize_with = "deserialize_opt_num",
+        default
+    )]
+    pub wb_buffer_type: Option<u8>,
+    #[serde(
+        rename = "@shared_wb_buffer_size_in_kb",
+        deserialize_with = "deserialize_opt_num",
+        default
+    )]
+    pub shared_wb_buffer_size_in_kb: Option<u64>,
+}
+
+// Define struct for the <ufs LUNum=...> node of each LUN
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct UfsLun {
+    #[serde(rename = "@LUNum", deserialize_with = "deserialize_num")]
+    pub lun: u8,
+    #[serde(rename = "@bLUEnable", deserialize_with = "deserialize_num")]
+    pub enable: u8,
+    // 1 for boot LUN A, 2 for boot LUN B, 0 if not a boot LUN
+    #[serde(rename = "@bBootLunID", deserialize_with = "deserialize_num")]
+    pub boot_lun_id: u8,
+    // 0 for the LUN taking whatever space the others leave
+    #[serde(rename = "@size_in_kb", deserialize_with = "deserialize_num")]
+    pub size_in_kb: u64,
+    #[serde(rename = "@bDataReliability", deserialize_with = "deserialize_num")]
+    pub data_reliability: u8,
+    #[serde(rename = "@bLUWriteProtect", deserialize_with = "deserialize_num")]
+    pub write_protect: u8,
+    // 0 for normal memory, 3 for the enhanced (SLC like) one boot LUNs use
+    #[serde(rename = "@bMemoryType", deserialize_with = "deserialize_num")]
+    pub memory_type: u8,
+    // log2 of the block size, 0x0c for 4096 bytes
+    #[serde(rename = "@bLogicalBlockSize", deserialize_with = "deserialize_num")]
+    pub logical_block_size: u8,
+    #[serde(rename = "@bProvisioningType", deserialize_with = "deserialize_num")]
+    pub provisioning_type: u8,
+    #[serde(rename = "@wContextCapabilities", deserialize_with = "deserialize_num")]
+    pub context_capabilities: u16,
+    #[serde(rename = "@desc", default)]
+    pub desc: String,
+}
+
+// Define struct for the closing <ufs commit=...> node
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+struct UfsCommit {
+    #[serde(
+        rename = "@LUNtoGrow",
+        deserialize_with = "deserialize_opt_num",
+        default
+    )]
+    lun_to_grow: Option<u8>,
+    #[serde(rename = "@commit", deserialize_with = "deserialize_num")]
+    commit: u8,
+}
+
+/// The `<ufs>` tags of a provision XML, in the order they are sent
+#[derive(Debug, PartialEq, Clone)]
+pub struct UfsProvision {
+    pub common: UfsCommon,
+    pub luns: Vec<UfsLun>,
+    pub lun_to_grow: Option<u8>,
+    /// The commit value written in the file. Whether the device commits is
+    /// up to the caller.
+    pub commit: bool,
+}
+
+impl UfsCommon {
+    /// Attributes of the `<ufs>` tag sent to the loader
+    pub fn attributes(&self) -> Vec<(&'static str, String)> {
+        let mut attrs = vec![
+            ("bNumberLU", self.number_lu.to_string()),
+            ("bBootEnable", self.boot_enable.to_string()),
+            ("bDescrAccessEn", self.descr_access_en.to_string()),
+            ("bInitPowerMode", self.init_power_mode.to_string()),
+            ("bHighPriorityLUN", self.high_priority_lun.to_string()),
+            ("bSecureRemovalType", self.secure_removal_type.to_string()),
+            (
+                "bInitActiveICCLevel",
+                self.init_active_icc_level.to_string(),
+            ),
+            ("wPeriodicRTCUpdate", self.periodic_rtc_update.to_string()),
+            ("bConfigDescrLock", self.config_descr_lock.to_string()),
+        ];
+        if let Some(preserve) = self.wb_preserve_user_space_en {
+            attrs.push((
+                "bWriteBoosterBufferPreserveUserSpaceEn",
+                preserve.to_string(),
+            ));
+        }
+        if let Some(buffer_type) = self.wb_buffer_type {
+            attrs.push(("bWriteBoosterBufferType", buffer_type.to_string()));
+        }
+        if let Some(size) = self.shared_wb_buffer_size_in_kb {
+            attrs.push(("shared_wb_buffer_size_in_kb", size.to_string()));
+        }
+        attrs
+    }
+}
+
+impl UfsLun {
+    /// Attributes of the `<ufs>` tag sent to the loader
+    pub fn attributes(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("LUNum", self.lun.to_string()),
+            ("bLUEnable", self.enable.to_string()),
+            ("bBootLunID", self.boot_lun_id.to_string()),
+            ("size_in_kb", self.size_in_kb.to_string()),
+            ("bDataReliability", self.data_reliability.to_string()),
+            ("bLUWriteProtect", self.write_protect.to_string()),
+            ("bMemoryType", self.memory_type.to_string()),
+            ("bLogicalBlockSize", self.logical_block_size.to_string()),
+            ("bProvisioningType", self.provisioning_type.to_string()),
+            (
+                "wContextCapabilities",
+                self.context_capabilities.to_string(),
+            ),
+            ("desc", self.desc.clone()),
+        ]
+    }
+}
+
+impl UfsProvision {
+    /// Attributes of the closing `<ufs>` tag. With `commit` false the loader
+    /// only checks the layout it was sent.
+    pub fn commit_attributes(&self, commit: bool) -> Vec<(&'static str, String)> {
+        let mut attrs = Vec::new();
+        if let Some(lun) = self.lun_to_grow {
+            attrs.push(("LUNtoGrow", lun.to_string()));
+        }
+        attrs.push(("commit", (commit as u8).to_string()));
+        attrs
+    }
+}
+
+// Provision XMLs mix decimal and 0x prefixed hex values
+fn parse_num(value: &str) -> Option<u64> {
+    let value = value.trim();
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn deserialize_num<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_num(&value)
+        .and_then(|num| T::try_from(num).ok())
+        .ok_or_else(|| D::Error::custom(format!("invalid value {}", value)))
+}
+
+fn deserialize_opt_num<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    deserialize_num(deserializer).map(Some)
+}
+
+/// Parse the `<ufs>` tags of a Qualcomm provision_*.xml
+pub fn parser_provision_xml(content: &str) -> anyhow::Result<UfsProvision> {
+    // A <ufs> tag missing one of the attributes of its kind matches none
+    let root = match from_str::<ProvisionRoot>(content) {
+        Ok(root) => root,
+        Err(e) => bail!("XML parsing failed, check the <ufs> tags: {}", e),
+    };
+
+    let mut common = None;
+    let mut luns: Vec<UfsLun> = Vec::new();
+    let mut closing = None;
+    for tag in root.ufs_tags {
+        match tag {
+            UfsTag::Common(tag) => {
+                if common.replace(tag).is_some() {
+                    bail!("More than one <ufs bNumberLU=...> tag");
+                }
+            }
+            UfsTag::Lun(tag) => {
+                if luns.iter().any(|lun| lun.lun == tag.lun) {
+                    bail!("LUN {} is provisioned twice", tag.lun);
+                }
+                luns.push(tag);
+            }
+            UfsTag::Commit(tag) => closing = Some(tag),
+        }
+    }
+
+    let Some(common) = common else {
+        bail!("No <ufs bNumberLU=...> tag, not a UFS provision XML");
+    };
+    let Some(closing) = closing else {
+        bail!("No closing <ufs commit=...> tag");
+    };
+    if luns.is_empty() {
+        bail!("No <ufs LUNum=...> tag");
+    }
+    if let Some(lun) = closing.lun_to_grow
+        && !luns.iter().any(|tag| tag.lun == lun)
+    {
+        bail!("LUNtoGrow is {} but that LUN isn't provisioned", lun);
+    }
+
+    Ok(UfsProvision {
+        common,
+        luns,
+        lun_to_grow: closing.lun_to_grow,
+        commit: closing.commit != 0,
+    })
+}
@@ -1,8 +1,9 @@
 import { ref } from "vue";
 import { listen } from '@tauri-apps/api/event';
 import { invoke } from "@tauri-apps/api/core";
+import { open } from "@tauri-apps/plugin-dialog";
 
-export function useAdvancedPanelEventHandler(imgSavingPath, isDebug) {
+export function useAdvancedPanelEventHandler(imgSavingPath, isDebug, selectedDevice, t) {
 
     let xmlContent = ref('<?xml version="1.0" ?>\n<data>\n\t<power DelayInSeconds="0" value="reset" />\n</data >');
 
@@ -10,6 +11,12 @@ export function useAdvancedPanelEventHandler(imgSavingPath, isDebug) {
 
     let selectedCmd = ref("power");
 
+    let benchTrials = ref(10);
+    let benchPayloadSizes = ref("");
+
+    let provisionXmlPath = ref("");
+    let provisionCommit = ref(false);
+
     const cmdList = ref([
         { id: 1, label: '1. Send Loader', value: 'loader' },
         { id: 2, label: '2. Send Digest', value: 'digest' },
@@ -39,10 +46,80 @@ export function useAdvancedPanelEventHandler(imgSavingPath, isDebug) {
             digest: digest,
             sig: sig,
             content: xmlContent.value,
+            deviceId: selectedDevice.value,
             isDebug: isDebug.value,
         });
     }
 
+    // Empty sizes let the device sweep powers of two up to the negotiated payload
+    async function runBenchmark() {
+        let payloadSizes = benchPayloadSizes.value.split(",")
+            .map(size => parseInt(size.trim()))
+            .filter(size => !isNaN(size) && size > 0);
+        try {
+            let results = await invoke("benchmark", {
+                deviceId: selectedDevice.value,
+                trials: parseInt(benchTrials.value) || 10,
+                payloadSizes: payloadSizes,
+            });
+            cmdOutput.value = results.map(result => {
+                let time = result.time_us == null ? "-" : result.time_us + " us";
+                let throughput = result.throughput_mbps == null ? "-" : result.throughput_mbps.toFixed(2) + " MB/s";
+                return result.operation.padEnd(6) + String(result.payload_size).padStart(9) + " bytes x "
+                    + result.trials + ": " + time + ", " + throughput;
+            }).join("\n");
+        } catch (error) {
+            cmdOutput.value = error;
+        }
+    }
+
+    async function selectProvisionXml() {
+        try {
+            const file = await open({
+                multiple: false,
+                directory: false,
+                filters: [{ name: 'XML file', extensions: ['xml'] }],
+            });
+            if (file) {
+                provisionXmlPath.value = file;
+            }
+        } catch (error) {
+            console.error('Error occurred while selecting a file:', error);
+        }
+    }
+
+    // Shows the LUN layout of the provision XML, null if it can't be parsed
+    async function dryRunProvision() {
+        try {
+            cmdOutput.value = await invoke("read_ufs_provision", { path: provisionXmlPath.value });
+            return cmdOutput.value;
+        } catch (error) {
+            cmdOutput.value = error;
+            return null;
+        }
+    }
+
+    // Committing can't be undone, so the layout is shown and confirmed twice first
+    async function provisionUfs() {
+        if (await dryRunProvision() == null) {
+            return;
+        }
+        if (provisionCommit.value) {
+            if (!confirm(t('advanced.provisionConfirm')) || !confirm(t('advanced.provisionConfirmAgain'))) {
+                return;
+            }
+        }
+        try {
+            await invoke("provision_ufs", {
+                deviceId: selectedDevice.value,
+                path: provisionXmlPath.value,
+                commit: provisionCommit.value,
+            });
+        } catch (error) {
+            cmdOutput.value = error;
+        }
+    }
+
     async function handleSelectCmdChange() {
         if (selectedCmd.value == "read") {
             xmlContent.value = '<?xml version="1.0" ?>\n<data>\n\t<read filename="misc.img" physical_partition_number="0" label="misc" start_sector="8200" num_partition_sectors="256" SECTOR_SIZE_IN_BYTES="4096" sparse="false"/>\n</data>';
@@ -92,5 +169,13 @@ export function useAdvancedPanelEventHandler(imgSavingPath, isDebug) {
         cmdList,
         runCommand,
         handleSelectCmdChange,
+        benchTrials,
+        benchPayloadSizes,
+        runBenchmark,
+        provisionXmlPath,
+        provisionCommit,
+        selectProvisionXml,
+        dryRunProvision,
+        provisionUfs,
     }
 }
\ No newline at end of file
@@ -83,6 +83,7 @@ export function useConfigPanelEventHandler(tableData, activeTab, activeStep) {
                             imgPath: item['@_filename'],
                             sel: '',
                             sparse: item['@_sparse'],
+                            sectorSize: item['@_SECTOR_SIZE_IN_BYTES'],
                         });
                         i++;
                     }
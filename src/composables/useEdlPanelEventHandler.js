@@ -1,16 +1,16 @@
 import { invoke } from "@tauri-apps/api/core";
 
-export function useEdlPanelEventHandler(isRunning, isProtectLun5, isDebug) {
+export function useEdlPanelEventHandler(isRunning, isProtectLun5, isDebug, selectedDevice) {
 
     async function startFlashing() {
         isRunning.value = true;
         const edlFolder = document.getElementById('edlFolderPathDisplay').value;
-        await invoke("start_flashing", { path: edlFolder, isProtectLun5: isProtectLun5.value, isDebug: isDebug.value });
+        await invoke("start_flashing", { path: edlFolder, isProtectLun5: isProtectLun5.value, deviceId: selectedDevice.value, isDebug: isDebug.value });
     }
 
     async function stopFlashing() {
         isRunning.value = false;
-        await invoke("stop_flashing");
+        await invoke("stop_flashing", { deviceId: selectedDevice.value });
     }
 
     return {
@@ -2,7 +2,7 @@ import { ref, watch } from "vue";
 import { listen } from '@tauri-apps/api/event';
 import { XMLParser } from 'fast-xml-parser';
 
-export function useEventListener(tableData) {
+export function useEventListener(tableData, selectedDevice) {
 
     let activeStep = ref(1);
     let slotDialogRef = ref(null);
@@ -12,6 +12,7 @@ export function useEventListener(tableData) {
     let isSentLoader = ref(false);
     let percentage = ref(0);
     let working_percentage = ref(0);
+    let jobs = ref({});
 
     watch(isDialogOpen, (newVal) => {
         const dialog = slotDialogRef.value;
@@ -20,19 +21,25 @@ export function useEventListener(tableData) {
         newVal ? dialog.showModal() : dialog.close();
     });
 
+    function escapeHtml(text) {
+        return text.replace(/&/g, "&amp;").replace(/</g, "&lt;").replace(/>/g, "&gt;");
+    }
+
     listen("log_event", (payload) => {
         console.log(payload);
         const logContainer = document.getElementById('logContainer');
         const time = new Date().toLocaleTimeString('zh-CN', { hour12: false });
-        const logText = `[${time}] ${payload.payload.toString()}<br>`;
+        let message = payload.payload.toString();
+        // <log> messages of the loader come with the command they belong to
+        if (typeof payload.payload === 'object') {
+            const log = payload.payload;
+            message = `<span class="log-${log.severity}">${escapeHtml(`<${log.op}> ${log.message}`)}</span>`;
+        }
+        const logText = `[${time}] ${message}<br>`;
         logContainer.innerHTML += logText;
         logContainer.scrollTop = logContainer.scrollHeight;
     });
 
-    listen("stop_edl_flashing", (payload) => {
-        isRunning.value = false;
-    });
-
     listen("update_command_running_status", (payload) => {
         isCommandRunning = payload.payload;
     });
@@ -41,8 +48,10 @@ export function useEventListener(tableData) {
         isSentLoader.value = payload.payload;
     });
 
-    listen("update_percentage", (payload) => {
-        percentage.value = payload.payload;
+    function showSelectedJob() {
+        const job = jobs.value[selectedDevice.value];
+        isRunning.value = job !== undefined && job.state == "running";
+        percentage.value = job !== undefined ? job.percentage : 0;
         if (percentage.value >= 100) {
             activeStep.value = 7;
         } else if (percentage.value >= 95) {
@@ -58,6 +67,13 @@ export function useEventListener(tableData) {
         } else {
             activeStep.value = 1;
         }
+    }
+
+    watch(selectedDevice, showSelectedJob);
+
+    listen("update_job_status", (payload) => {
+        jobs.value[payload.payload.device_id] = payload.payload;
+        showSelectedJob();
     });
 
     listen("update_working_percentage", (payload) => {
@@ -88,6 +104,7 @@ export function useEventListener(tableData) {
                     imgPath: '',
                     sel: '',
                     sparse: item['@_sparse'],
+                    sectorSize: item['@_SECTOR_SIZE_IN_BYTES'],
                 });
                 i++;
             }
@@ -103,5 +120,6 @@ export function useEventListener(tableData) {
         isSentLoader,
         percentage,
         working_percentage,
+        jobs,
     }
 }
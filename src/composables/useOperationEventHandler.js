@@ -1,7 +1,7 @@
 import { XMLBuilder } from 'fast-xml-parser';
 import { invoke } from "@tauri-apps/api/core";
 import { open, save } from "@tauri-apps/plugin-dialog";
-export function useOperationEventHandler(imgSavingPath, isBuildIn, isDialogOpen, tableData, isDebug, t) {
+export function useOperationEventHandler(imgSavingPath, isBuildIn, isDialogOpen, isSentLoader, tableData, isDebug, selectedDevice, t) {
 
     async function erasePart() {
         const builder = new XMLBuilder({
@@ -24,9 +24,10 @@ export function useOperationEventHandler(imgSavingPath, isBuildIn, isDialogOpen,
                 const partname = item.partName;
                 let part_start_sector = item.partStart;
                 const part_num = item.partNum;
+                const sector_size = item.sectorSize || "4096";
 
                 parts.push({
-                    "@_SECTOR_SIZE_IN_BYTES": "4096",
+                    "@_SECTOR_SIZE_IN_BYTES": sector_size,
                     "@_label": partname,
                     "@_physical_partition_number": num,
                     "@_start_sector": part_start_sector,
@@ -35,16 +36,27 @@ export function useOperationEventHandler(imgSavingPath, isBuildIn, isDialogOpen,
             }
         });
         const xmlContent = builder.build(jsObj);
-        await invoke("erase_part", { xml: xmlContent, isDebug: isDebug.value });
+        await invoke("erase_part", { xml: xmlContent, deviceId: selectedDevice.value, isDebug: isDebug.value });
     }
 
     async function readDeviceInfo() {
-        let result = await invoke("read_device_info", { isDebug: isDebug.value });
+        // Without a loader only what the PBL tells over Sahara is available
+        if (isSentLoader.value == false) {
+            try {
+                let info = await invoke("read_sahara_info", { deviceId: selectedDevice.value, isDebug: isDebug.value });
+                alert(" Chipset:" + info.chipset + "\n HW ID:" + info.hw_id + "\n MSM ID:" + info.msm_id + "\n OEM ID:" + info.oem_id
+                    + "\n Model ID:" + info.model_id + "\n Serial Number:" + info.serial_num);
+            } catch (error) {
+                alert(error);
+            }
+            return;
+        }
+        let result = await invoke("read_device_info", { deviceId: selectedDevice.value, isDebug: isDebug.value });
         alert(result);
     }
 
     async function readGPT() {
-        await invoke("read_gpt", { isDebug: isDebug.value });
+        await invoke("read_gpt", { deviceId: selectedDevice.value, isDebug: isDebug.value });
     }
 
     async function readPart() {
@@ -69,6 +81,7 @@ export function useOperationEventHandler(imgSavingPath, isBuildIn, isDialogOpen,
                 let part_size = item.partSize;
                 let part_start_sector = item.partStart;
                 const part_num = item.partNum;
+                const sector_size = item.sectorSize || "4096";
 
                 if (part_size.length >= 2) {
                     part_size = part_size.slice(0, -2);
@@ -80,13 +93,13 @@ export function useOperationEventHandler(imgSavingPath, isBuildIn, isDialogOpen,
                     "@_label": partname,
                     "@_start_sector": part_start_sector,
                     "@_num_partition_sectors": part_num,
-                    "@_SECTOR_SIZE_IN_BYTES": "4096",
+                    "@_SECTOR_SIZE_IN_BYTES": sector_size,
                     "@_sparse": "false"
                 });
             }
         });
         const xmlContent = builder.build(jsObj);
-        await invoke("read_part", { xml: xmlContent, folder: imgSavingPath.value, isDebug: isDebug.value });
+        await invoke("read_part", { xml: xmlContent, folder: imgSavingPath.value, deviceId: selectedDevice.value, isDebug: isDebug.value });
     }
 
     async function saveToXML() {
@@ -114,14 +127,15 @@ export function useOperationEventHandler(imgSavingPath, isBuildIn, isDialogOpen,
                 let part_size = item.partSize;
                 let part_start_sector = item.partStart;
                 const part_num = item.partNum;
+                const sector_size = item.sectorSize || "4096";
 
                 if (part_size.length >= 2) {
                     part_size = part_size.slice(0, -2);
                 }
                 let start_byte_hex = "";
                 if (isNaN(num) == false) {
-                    part_start_sector = parseInt(part_start_sector) * 4096;
-                    start_byte_hex = '0x' + part_start_sector.toString(16);
+                    start_byte_hex = parseInt(part_start_sector) * parseInt(sector_size);
+                    start_byte_hex = '0x' + start_byte_hex.toString(16);
                 }
 
                 programs.push({
@@ -135,7 +149,7 @@ export function useOperationEventHandler(imgSavingPath, isBuildIn, isDialogOpen,
                     "@_filename": item.imgPath,
                     "@_sparse": item.sparse,
                     "@_start_byte_hex": start_byte_hex,
-                    "@_SECTOR_SIZE_IN_BYTES": "4096",
+                    "@_SECTOR_SIZE_IN_BYTES": sector_size,
                     "@_label": partname
                 });
             }
@@ -156,12 +170,12 @@ export function useOperationEventHandler(imgSavingPath, isBuildIn, isDialogOpen,
         let digest = document.getElementById('digestPathDisplay').value;
         let sig = document.getElementById('signPathDisplay').value;
 
-        await invoke("send_loader", { loader: loader, digest: digest, sig: sig, native: isBuildIn.value, isDebug: isDebug.value });
+        await invoke("send_loader", { loader: loader, digest: digest, sig: sig, native: isBuildIn.value, deviceId: selectedDevice.value, isDebug: isDebug.value });
     }
 
     async function switchSlot(slot) {
         isDialogOpen.value = false;
-        await invoke("switch_slot", { slot: slot, isDebug: isDebug.value });
+        await invoke("switch_slot", { slot: slot, deviceId: selectedDevice.value, isDebug: isDebug.value });
     }
 
     async function writeFromXML() {
@@ -172,13 +186,34 @@ export function useOperationEventHandler(imgSavingPath, isBuildIn, isDialogOpen,
                 filters: [{ name: 'XML file', extensions: ['xml'] }],
             });
             if (file) {
-                await invoke("write_from_xml", { file_path: file, isDebug: isDebug.value });
+                await invoke("write_from_xml", { file_path: file, deviceId: selectedDevice.value, isDebug: isDebug.value });
             }
         } catch (error) {
             console.error('Error occurred while selecting a file:', error);
         }
     }
 
+    async function verifyFromXML() {
+        try {
+            const file = await open({
+                multiple: false,
+                directory: false,
+                filters: [{ name: 'XML file', extensions: ['xml'] }],
+            });
+            if (file) {
+                let results = await invoke("verify_from_xml", { filePath: file, deviceId: selectedDevice.value, isDebug: isDebug.value });
+                let failed = results.filter(result => !result.passed).map(result => result.label);
+                if (failed.length == 0) {
+                    alert(t('operation.verifyPassed', { count: results.length }));
+                } else {
+                    alert(t('operation.verifyFailed', { count: failed.length }) + "\n" + failed.join("\n"));
+                }
+            }
+        } catch (error) {
+            alert(error);
+        }
+    }
+
     async function writePart() {
         const builder = new XMLBuilder({
             ignoreAttributes: false,
@@ -203,13 +238,14 @@ export function useOperationEventHandler(imgSavingPath, isBuildIn, isDialogOpen,
                 let part_size = item.partSize;
                 let part_start_sector = item.partStart;
                 const part_num = item.partNum;
+                const sector_size = item.sectorSize || "4096";
 
                 if (part_size.length >= 2) {
                     part_size = part_size.slice(0, -2);
                 }
                 let start_byte_hex = "";
                 if (isNaN(num) == false) {
-                    start_byte_hex = parseInt(part_start_sector) * 4096;
+                    start_byte_hex = parseInt(part_start_sector) * parseInt(sector_size);
                     start_byte_hex = '0x' + start_byte_hex.toString(16);
                 }
 
@@ -224,13 +260,13 @@ export function useOperationEventHandler(imgSavingPath, isBuildIn, isDialogOpen,
                     "@_filename": item.imgPath,
                     "@_sparse": item.sparse,
                     "@_start_byte_hex": start_byte_hex,
-                    "@_SECTOR_SIZE_IN_BYTES": "4096",
+                    "@_SECTOR_SIZE_IN_BYTES": sector_size,
                     "@_label": partname
                 });
             }
         });
         const xmlContent = builder.build(jsObj);
-        await invoke("write_part", { xml: xmlContent, isDebug: isDebug.value });
+        await invoke("write_part", { xml: xmlContent, deviceId: selectedDevice.value, isDebug: isDebug.value });
     }
 
     return {
@@ -241,6 +277,7 @@ export function useOperationEventHandler(imgSavingPath, isBuildIn, isDialogOpen,
         saveToXML,
         sendLoader,
         switchSlot,
+        verifyFromXML,
         writeFromXML,
         writePart,
     }
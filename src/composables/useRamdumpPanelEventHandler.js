@@ -0,0 +1,130 @@
+import { ref, onMounted } from "vue";
+import { listen } from '@tauri-apps/api/event';
+import { invoke } from "@tauri-apps/api/core";
+import { open } from "@tauri-apps/plugin-dialog";
+
+export function useRamdumpPanelEventHandler(isDebug, selectedDevice) {
+
+    let ramdumpRegions = ref([]);
+    let ramdumpSavingPath = ref("ramdump/");
+    let ramdumpProgress = ref("");
+    let isDumping = ref(false);
+    let ramdumpFormat = ref("raw");
+    let peekAddress = ref("");
+    let peekSize = ref("");
+    let peekResume = ref(false);
+
+    const ramdumpFormatList = [
+        { label: 'Raw files', value: 'raw' },
+        { label: 'ELF core (ramdump.elf)', value: 'elf' },
+        { label: 'Raw files + load.cmm', value: 'cmm' },
+    ];
+
+    function formatSize(len) {
+        if (len >= 1024 * 1024) {
+            return (len / 1024 / 1024).toFixed(2) + " MB";
+        }
+        return (len / 1024).toFixed(2) + " KB";
+    }
+
+    async function changeRamdumpPath() {
+        try {
+            const dir = await open({
+                multiple: false,
+                directory: true,
+            });
+            if (dir) {
+                ramdumpSavingPath.value = dir;
+            }
+        } catch (error) {
+            console.error('Error occurred while selecting a folder:', error);
+        }
+    }
+
+    async function listRamdumpRegions() {
+        ramdumpProgress.value = "";
+        try {
+            let regions = await invoke("list_ramdump_regions", {
+                deviceId: selectedDevice.value,
+                isDebug: isDebug.value,
+            });
+            ramdumpRegions.value = regions.map(region => ({
+                ...region,
+                chk: region.save_pref,
+            }));
+        } catch (error) {
+            ramdumpRegions.value = [];
+            ramdumpProgress.value = error;
+        }
+    }
+
+    async function dumpRamdumpRegions() {
+        let regions = ramdumpRegions.value.filter(region => region.chk).map(region => region.name);
+        if (regions.length == 0) {
+            return;
+        }
+        isDumping.value = true;
+        try {
+            await invoke("dump_ramdump_regions", {
+                deviceId: selectedDevice.value,
+                regions: regions,
+                folder: ramdumpSavingPath.value,
+                format: ramdumpFormat.value,
+            });
+        } catch (error) {
+            ramdumpProgress.value = error;
+        }
+        isDumping.value = false;
+    }
+
+    // Memory of a device running the Firehose loader, read with <peek>
+    async function dumpMemory() {
+        // parseInt takes both "0x..." and decimal
+        let address = parseInt(peekAddress.value);
+        let size = parseInt(peekSize.value);
+        if (isNaN(address) || isNaN(size) || size <= 0) {
+            ramdumpProgress.value = "Invalid address or size";
+            return;
+        }
+        isDumping.value = true;
+        try {
+            await invoke("dump_memory", {
+                deviceId: selectedDevice.value,
+                address: address,
+                size: size,
+                folder: ramdumpSavingPath.value,
+                resume: peekResume.value,
+            });
+        } catch (error) {
+            ramdumpProgress.value = error;
+        }
+        isDumping.value = false;
+    }
+
+    onMounted(async () => {
+        await listen('update_ramdump_progress', (event) => {
+            if (event.payload.device_id != selectedDevice.value) {
+                return;
+            }
+            let percentage = event.payload.total == 0 ? 100 : Math.floor(event.payload.bytes * 100 / event.payload.total);
+            ramdumpProgress.value = event.payload.region + ": " + percentage + " %";
+        });
+    });
+
+    return {
+        ramdumpRegions,
+        ramdumpSavingPath,
+        ramdumpProgress,
+        isDumping,
+        ramdumpFormat,
+        ramdumpFormatList,
+        formatSize,
+        changeRamdumpPath,
+        listRamdumpRegions,
+        dumpRamdumpRegions,
+        peekAddress,
+        peekSize,
+        peekResume,
+        dumpMemory,
+    };
+}
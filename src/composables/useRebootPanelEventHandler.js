@@ -2,10 +2,10 @@ import { XMLBuilder } from 'fast-xml-parser';
 import { invoke } from "@tauri-apps/api/core";
 
 
-export function useRebootPanelEventHandler(tableData, isDebug, t) {
+export function useRebootPanelEventHandler(tableData, isDebug, selectedDevice, t) {
 
     async function rebootToEdl() {
-        await invoke("reboot_to_edl", { isDebug: isDebug.value });
+        await invoke("reboot_to_edl", { deviceId: selectedDevice.value, isDebug: isDebug.value });
     }
 
     async function rebootToFastboot() {
@@ -34,13 +34,14 @@ export function useRebootPanelEventHandler(tableData, isDebug, t) {
                 let part_size = item.partSize;
                 let part_start_sector = item.partStart;
                 const part_num = item.partNum;
+                const sector_size = item.sectorSize || "4096";
 
                 if (part_size.length >= 2) {
                     part_size = part_size.slice(0, -2);
                 }
                 let start_byte_hex = "";
                 if (isNaN(num) == false) {
-                    start_byte_hex = parseInt(part_start_sector) * 4096;
+                    start_byte_hex = parseInt(part_start_sector) * parseInt(sector_size);
                     start_byte_hex = '0x' + start_byte_hex.toString(16);
                 }
 
@@ -55,14 +56,14 @@ export function useRebootPanelEventHandler(tableData, isDebug, t) {
                     "@_filename": "misc_tofastbootd.img",
                     "@_sparse": item.sparse,
                     "@_start_byte_hex": start_byte_hex,
-                    "@_SECTOR_SIZE_IN_BYTES": "4096",
+                    "@_SECTOR_SIZE_IN_BYTES": sector_size,
                     "@_label": partname
                 });
             }
         });
         if (isFound) {
             const xmlContent = builder.build(jsObj);
-            await invoke("reboot_to_fastboot", { xml: xmlContent, isDebug: isDebug.value });
+            await invoke("reboot_to_fastboot", { xml: xmlContent, deviceId: selectedDevice.value, isDebug: isDebug.value });
         } else {
             alert(t('reboot.miscNotFound'));
         }
@@ -94,13 +95,14 @@ export function useRebootPanelEventHandler(tableData, isDebug, t) {
                 let part_size = item.partSize;
                 let part_start_sector = item.partStart;
                 const part_num = item.partNum;
+                const sector_size = item.sectorSize || "4096";
 
                 if (part_size.length >= 2) {
                     part_size = part_size.slice(0, -2);
                 }
                 let start_byte_hex = "";
                 if (isNaN(num) == false) {
-                    start_byte_hex = parseInt(part_start_sector) * 4096;
+                    start_byte_hex = parseInt(part_start_sector) * parseInt(sector_size);
                     start_byte_hex = '0x' + start_byte_hex.toString(16);
                 }
 
@@ -115,21 +117,21 @@ export function useRebootPanelEventHandler(tableData, isDebug, t) {
                     "@_filename": "misc_torecovery.img",
                     "@_sparse": item.sparse,
                     "@_start_byte_hex": start_byte_hex,
-                    "@_SECTOR_SIZE_IN_BYTES": "4096",
+                    "@_SECTOR_SIZE_IN_BYTES": sector_size,
                     "@_label": partname
                 });
             }
         });
         if (isFound) {
             const xmlContent = builder.build(jsObj);
-            await invoke("reboot_to_recovery", { xml: xmlContent, isDebug: isDebug.value });
+            await invoke("reboot_to_recovery", { xml: xmlContent, deviceId: selectedDevice.value, isDebug: isDebug.value });
         } else {
             alert(t('reboot.miscNotFound'));
         }
     }
 
     async function rebootToSystem() {
-        await invoke("reboot_to_system", { isDebug: isDebug.value });
+        await invoke("reboot_to_system", { deviceId: selectedDevice.value, isDebug: isDebug.value });
     }
 
     return {
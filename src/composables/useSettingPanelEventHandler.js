@@ -1,15 +1,63 @@
-import { ref } from "vue";
+import { ref, watch } from "vue";
 import { invoke } from "@tauri-apps/api/core";
 import { open } from "@tauri-apps/plugin-dialog";
 
-export function useSettingPanelEventHandler(portName, isSentLoader, isCommandRunning) {
+export function useSettingPanelEventHandler(portName, selectedDevice, isSentLoader, isCommandRunning) {
 
     let imgSavingPath = ref("img/");
     let isBuildIn = ref(false);
     let isProtectLun5 = ref(true);
     let isEnablePing = ref(true);
     let isDebug = ref(false);
-    
+    let isRecordSession = ref(false);
+
+    // Firehose commands whose <log> messages are shown, grouped by what they do
+    const firehoseLogGroups = [
+        { label: 'configure', ops: ['', 'configure', 'digests', 'transfercfg', 'verify', 'sha256init'] },
+        { label: 'program', ops: ['program'] },
+        { label: 'read', ops: ['read'] },
+        { label: 'erase', ops: ['erase', 'zeroout'] },
+        { label: 'patch', ops: ['patch', 'setbootablestoragedrive'] },
+        { label: 'storageinfo', ops: ['getstorageinfo'] },
+        { label: 'sha256', ops: ['getsha256digest'] },
+        { label: 'peek', ops: ['peek', 'poke'] },
+        { label: 'ufs', ops: ['ufs'] },
+        { label: 'benchmark', ops: ['benchmark'] },
+        { label: 'power', ops: ['power'] },
+        { label: 'ping', ops: ['nop'] },
+    ];
+    let firehoseLogOps = ref(firehoseLogGroups.map(group => group.label).filter(label => label != 'ping'));
+
+    watch(firehoseLogOps, async (labels) => {
+        const ops = firehoseLogGroups.filter(group => labels.includes(group.label)).flatMap(group => group.ops);
+        await invoke("set_firehose_log_ops", { ops: ops });
+    }, { immediate: true });
+
+    // Native sessions are only recorded for replay when asked to
+    watch(isRecordSession, async (enabled) => {
+        await invoke("set_record_sessions", { enabled: enabled });
+    }, { immediate: true });
+
+    // Firehose timeouts of the native sessions, in milliseconds
+    let firehoseReadTimeout = ref(2000);
+    let firehoseWriteTimeout = ref(5000);
+    let firehosePerMibTimeout = ref(1000);
+
+    watch([firehoseReadTimeout, firehoseWriteTimeout, firehosePerMibTimeout], async ([readMs, writeMs, perMibMs]) => {
+        await invoke("set_firehose_timeouts", {
+            readMs: Math.max(parseInt(readMs) || 0, 100),
+            writeMs: Math.max(parseInt(writeMs) || 0, 100),
+            perMibMs: Math.max(parseInt(perMibMs) || 0, 0),
+        });
+    }, { immediate: true });
+
+    // Chained VIP digest tables are sent this many bytes at a time
+    let digestTableSize = ref(8192);
+
+    watch(digestTableSize, async (bytes) => {
+        await invoke("set_digest_table_size", { bytes: Math.max(parseInt(bytes) || 0, 512) });
+    }, { immediate: true });
+
     async function changeSavingPath() {
         try {
             const dir = await open({
@@ -30,7 +78,7 @@ export function useSettingPanelEventHandler(portName, isSentLoader, isCommandRun
         }
 
         if (isEnablePing.value && isSentLoader.value && isCommandRunning == false) {
-            await invoke("send_ping", { isDebug: isDebug.value });
+            await invoke("send_ping", { deviceId: selectedDevice.value, isDebug: isDebug.value });
         }
     }
 
@@ -40,6 +88,13 @@ export function useSettingPanelEventHandler(portName, isSentLoader, isCommandRun
         isProtectLun5,
         isEnablePing,
         isDebug,
+        isRecordSession,
+        firehoseLogGroups,
+        firehoseLogOps,
+        firehoseReadTimeout,
+        firehoseWriteTimeout,
+        firehosePerMibTimeout,
+        digestTableSize,
         changeSavingPath,
         sendPing,
     }
@@ -1,12 +1,15 @@
 ﻿import { ref, onMounted } from "vue";
 import { invoke } from "@tauri-apps/api/core";
+import { listen } from '@tauri-apps/api/event';
 import { locale as systemLocale } from "@tauri-apps/plugin-os";
 
 export function useStatusPanelEventHandler(locale, tableColumns, tabList, t) {
 
     let portStatus = ref("EDL device not found");
     let portName = ref("N/A");
-    let portNum = ref("");
+    let devices = ref([]);
+    let selectedDevice = ref("");
+    let deviceInfo = ref({});
     let selectedLang = ref('en');
 
     const displayLang = {
@@ -33,23 +36,49 @@ export function useStatusPanelEventHandler(locale, tableColumns, tabList, t) {
             { key: 'tab_part', label: t('part.title') },
             { key: 'tab_edl', label: t('edl.title') },
             { key: 'tab_adv', label: t('advanced.title') },
+            { key: 'tab_ramdump', label: t('ramdump.title') },
             { key: 'tab_setting', label: t('setting.title') },
         ];
     };
 
+    function deviceLabel(device) {
+        let label = (device.port || device.bus_location) + " " + device.product;
+        if (device.serial_number) {
+            label += " (" + device.serial_number + ")";
+        }
+        return label;
+    }
+
     async function updatePort() {
-        const [num, name] = await invoke("update_port");
-        portNum.value = num;
-        portName.value = name;
-        if (portNum.value == "Not found") {
+        devices.value = await invoke("list_edl_devices");
+        if (!devices.value.some((device) => device.id == selectedDevice.value)) {
+            selectedDevice.value = devices.value.length > 0 ? devices.value[0].id : "";
+        }
+        const device = devices.value.find((device) => device.id == selectedDevice.value);
+        if (device) {
+            portStatus.value = t('config.portStatus');
+            portName.value = deviceLabel(device);
+        } else {
             portStatus.value = t('config.portStatusError');
             portName.value = "N/A";
-        } else {
-            portStatus.value = t('config.portStatus');
         }
     }
 
+    listen("edl_device_arrived", (payload) => {
+        updatePort();
+    });
+
+    listen("edl_device_removed", (payload) => {
+        delete deviceInfo.value[payload.payload.id];
+        updatePort();
+    });
+
+    listen("update_device_info", (payload) => {
+        deviceInfo.value[payload.payload.device_id] = payload.payload;
+    });
+
     onMounted(async () => {
+        await updatePort();
         let systemlocale = await systemLocale();
         const match = systemlocale.match(/^[a-zA-Z]+/);
         const langCode = match ? match[0] : "";
@@ -66,9 +95,13 @@ export function useStatusPanelEventHandler(locale, tableColumns, tabList, t) {
     return {
         portStatus,
         portName,
+        devices,
+        selectedDevice,
+        deviceInfo,
         selectedLang,
         displayLang,
         handleSelectLangChange,
+        deviceLabel,
         updatePort,
     }
 }
\ No newline at end of file
@@ -35,6 +35,9 @@ export default {
 		writePart: 'Write Partition',
 		erasePart: 'Erase Partition',
 		runCmdFromXML: 'Run XML command',
+		verifyFromXML: 'Verify from XML',
+		verifyPassed: "All {count} partitions match their images.",
+		verifyFailed: "{count} partitions don't match their images:",
 		createXML: 'Save to XML',
 		readDeviceInfo: 'Read device Info',
 		switchSlot: 'Switch Slot',
@@ -67,6 +70,28 @@ export default {
 		xmlContent: 'XML Command:',
 		output: 'Result:',
 		run: 'Run',
+		benchTrials: 'Benchmark trials:',
+		benchPayloadSizes: 'Payload sizes:',
+		benchmark: 'Benchmark',
+		provisionXml: 'UFS Provision XML:',
+		provisionCommit: 'Commit (irreversible)',
+		provisionDryRun: 'Dry Run',
+		provision: 'Provision UFS',
+		provisionConfirm: "Committing rewrites the LUN layout of the UFS chip, erasing every LUN, and can't be undone. Check the layout shown in the result panel. Continue?",
+		provisionConfirmAgain: 'Last chance: commit this UFS provisioning to the device now?',
+	},
+	ramdump: {
+		title: 'Ramdump',
+		savingPath: 'Dump Save Path:',
+		format: 'Output Format:',
+		description: 'Description',
+		base: 'Base Address',
+		detect: 'Detect',
+		dump: 'Dump',
+		peekAddress: 'Peek address:',
+		peekSize: 'Size:',
+		peekResume: 'Resume',
+		peekDump: 'Dump memory',
 	},
 	setting: {
 		title: 'Setting',
@@ -75,7 +100,14 @@ export default {
 		useBuildIn: 'Use the build-in fh_loader',
 		protectLun5: 'Protect LUN 5',
 		enablePing: 'Prevent timeout-induced restart',
+		recordSession: 'Record native sessions for replay',
 		storageType: 'Storage Type:',
 		logLevel: 'Log Level:',
+		firehoseLog: 'Loader logs:',
+		firehoseTimeouts: 'Loader timeouts (ms):',
+		readTimeout: 'Read ',
+		writeTimeout: 'Write ',
+		perMibTimeout: 'Per MiB ',
+		digestTableSize: 'VIP digest table size (bytes): ',
 	}
 }
\ No newline at end of file
@@ -35,6 +35,9 @@ export default {
 		writePart: 'Записать раздел',
 		erasePart: 'удалить раздел',
 		runCmdFromXML: 'Запустить XML команду',
+		verifyFromXML: 'Проверить по XML',
+		verifyPassed: "Все разделы ({count}) совпадают с образами.",
+		verifyFailed: "Разделы, не совпадающие с образами ({count}):",
 		createXML: 'Сохранить в XML',
 		readDeviceInfo: 'Читать Device Info',
 		switchSlot: 'Сменить слот',
@@ -63,6 +66,28 @@ export default {
 		xmlContent: 'XML Command:',
 		output: 'Result:',
 		run: 'Выполнить',
+		benchTrials: 'Число прогонов:',
+		benchPayloadSizes: 'Размеры пакета:',
+		benchmark: 'Тест скорости',
+		provisionXml: 'XML разметки UFS:',
+		provisionCommit: 'Зафиксировать (необратимо)',
+		provisionDryRun: 'Пробный запуск',
+		provision: 'Разметить UFS',
+		provisionConfirm: 'Фиксация перезапишет разметку LUN микросхемы UFS, сотрёт все LUN, и её нельзя отменить. Проверьте разметку в панели результата. Продолжить?',
+		provisionConfirmAgain: 'Последний шанс: зафиксировать эту разметку UFS на устройстве?',
+	},
+	ramdump: {
+		title: 'Дамп памяти',
+		savingPath: 'Путь сохранения дампа:',
+		format: 'Формат вывода:',
+		description: 'Описание',
+		base: 'Базовый адрес',
+		detect: 'Обнаружить',
+		dump: 'Сохранить дамп',
+		peekAddress: 'Адрес peek:',
+		peekSize: 'Размер:',
+		peekResume: 'Продолжить',
+		peekDump: 'Дамп памяти',
 	},
 	log: {
 		title: 'Лог',
@@ -75,7 +100,14 @@ export default {
 		useBuildIn: 'Использовать встроенный fh_loader',
 		protectLun5: 'Защита LUN 5',
 		enablePing: 'Предотвратить перезапуск из-за тайм-аута',
+		recordSession: 'Записывать сеансы для воспроизведения',
 		storageType: 'Тип хранилища:',
 		logLevel: 'Уровень отображения журнала:',
+		firehoseLog: 'Журнал загрузчика:',
+		firehoseTimeouts: 'Тайм-ауты загрузчика (мс):',
+		readTimeout: 'Чтение ',
+		writeTimeout: 'Запись ',
+		perMibTimeout: 'На МиБ ',
+		digestTableSize: 'Размер таблицы хешей VIP (байт): ',
 	}
 }
\ No newline at end of file
@@ -35,6 +35,9 @@
 		writePart: '写入选中分区',
 		erasePart: '擦除选中分区',
 		runCmdFromXML: '运行XML中的指令',
+		verifyFromXML: '按XML校验分区',
+		verifyPassed: "全部 {count} 个分区与镜像一致。",
+		verifyFailed: "{count} 个分区与镜像不一致：",
 		createXML: '保存至XML',
 		readDeviceInfo: '读设备信息',
 		switchSlot: '切换槽位',
@@ -63,6 +66,28 @@
 		xmlContent: 'XML Command:',
 		output: 'Result:',
 		run: '运行',
+		benchTrials: '测试次数:',
+		benchPayloadSizes: '负载大小:',
+		benchmark: '性能测试',
+		provisionXml: 'UFS 配置 XML:',
+		provisionCommit: '提交 (不可撤销)',
+		provisionDryRun: '试运行',
+		provision: '配置 UFS',
+		provisionConfirm: '提交将重写 UFS 芯片的 LUN 布局并擦除所有 LUN, 且无法撤销。请检查结果面板中显示的布局。是否继续?',
+		provisionConfirmAgain: '最后确认: 现在将此 UFS 配置提交到设备?',
+	},
+	ramdump: {
+		title: '内存转储',
+		savingPath: '转储保存路径:',
+		format: '输出格式:',
+		description: '描述',
+		base: '基地址',
+		detect: '检测',
+		dump: '转储',
+		peekAddress: 'Peek 地址:',
+		peekSize: '大小:',
+		peekResume: '断点续传',
+		peekDump: '转储内存',
 	},
 	log: {
 		title: '操作日志',
@@ -75,7 +100,14 @@
 		useBuildIn: '使用內建fh_loader',
 		protectLun5: '保护 LUN 5',
 		enablePing: '防止超时重启',
+		recordSession: '录制会话以便回放',
 		storageType: '闪存类型：',
 		logLevel: '日志显示等级：',
+		firehoseLog: '加载器日志：',
+		firehoseTimeouts: '加载器超时 (毫秒)：',
+		readTimeout: '读取 ',
+		writeTimeout: '写入 ',
+		perMibTimeout: '每 MiB ',
+		digestTableSize: 'VIP 摘要表大小 (字节)：',
 	}
 }
\ No newline at end of file
@@ -35,6 +35,9 @@
 		writePart: '寫入選中分區',
 		erasePart: '擦除選中分區',
 		runCmdFromXML: '運行XML指令',
+		verifyFromXML: '按XML校驗分區',
+		verifyPassed: "全部 {count} 個分區與鏡像一致。",
+		verifyFailed: "{count} 個分區與鏡像不一致：",
 		createXML: '保存至XML',
 		readDeviceInfo: '讀裝置資訊',
 		switchSlot: '切換槽位',
@@ -63,6 +66,28 @@
 		xmlContent: 'XML Command:',
 		output: 'Result:',
 		run: '執行',
+		benchTrials: '測試次數:',
+		benchPayloadSizes: '負載大小:',
+		benchmark: '效能測試',
+		provisionXml: 'UFS 配置 XML:',
+		provisionCommit: '提交 (不可復原)',
+		provisionDryRun: '試執行',
+		provision: '配置 UFS',
+		provisionConfirm: '提交將重寫 UFS 晶片的 LUN 配置並清除所有 LUN, 且無法復原。請檢查結果面板中顯示的配置。是否繼續?',
+		provisionConfirmAgain: '最後確認: 現在將此 UFS 配置提交到裝置?',
+	},
+	ramdump: {
+		title: '記憶體傾印',
+		savingPath: '傾印儲存路徑:',
+		format: '輸出格式:',
+		description: '描述',
+		base: '基底位址',
+		detect: '偵測',
+		dump: '傾印',
+		peekAddress: 'Peek 位址:',
+		peekSize: '大小:',
+		peekResume: '斷點續傳',
+		peekDump: '傾印記憶體',
 	},
 	log: {
 		title: '操作日誌',
@@ -75,7 +100,14 @@
 		useBuildIn: '使用內建fh_loader',
 		protectLun5: '保護 LUN 5',
 		enablePing: '防止超時重啟',
+		recordSession: '錄製會話以便回放',
 		storageType: '儲存空間類型：',
 		logLevel: '日誌顯示等級：',
+		firehoseLog: '載入器日誌：',
+		firehoseTimeouts: '載入器逾時 (毫秒)：',
+		readTimeout: '讀取 ',
+		writeTimeout: '寫入 ',
+		perMibTimeout: '每 MiB ',
+		digestTableSize: 'VIP 摘要表大小 (位元組)：',
 	}
 }
\ No newline at end of file